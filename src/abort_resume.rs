@@ -0,0 +1,23 @@
+use anyhow::Context;
+use indicatif::HumanBytes;
+
+use crate::dataset::Dataset;
+use crate::machine::Machine;
+
+/// Discards the partial state left behind in `ds` by an interrupted `zfs recv -s`, if any. With
+/// `dry_run`, only reports whether there is any.
+pub fn abort_resume_cli(machine: &Machine, ds: &Dataset, dry_run: bool) -> Result<String, anyhow::Error> {
+    let token = machine.get_resume_token(ds).context(format!(r#"Unable to get the resume token for "{machine}:{ds}"."#))?;
+    let Some(token) = token else {
+        return Ok(format!(r#"No partial receive state in "{machine}:{ds}"; nothing to do."#));
+    };
+    eprintln!(r#"Found the partial state of an interrupted receive in "{machine}:{ds}", with resume token {token}"#);
+    if dry_run {
+        return Ok(format!(r#"Dry run: would have discarded the partial receive state of "{machine}:{ds}"."#));
+    }
+    let used_before = machine.get_used(ds).context(format!(r#"Unable to get the space used by "{machine}:{ds}"."#))?;
+    machine.abort_partial_recv(ds).context(format!(r#"Failed to discard the partial state of "{machine}:{ds}"."#))?;
+    let used_after = machine.get_used(ds).context(format!(r#"Unable to get the space used by "{machine}:{ds}"."#))?;
+    // ZFS frees space in the background, so what's been reclaimed may not all show up yet.
+    Ok(format!(r#"Discarded the partial receive state of "{machine}:{ds}", reclaiming at least {}."#, HumanBytes(used_before.saturating_sub(used_after))))
+}
@@ -1,13 +1,93 @@
-use anyhow::Context;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use crate::dataset::{Dataset, Comm::{*}};
-use crate::machine::Machine;
+use owo_colors::OwoColorize;
+use regex::Regex;
+use serde_json::json;
+use crate::dataset::{Dataset, Comm, Comm::{*}, MRCUD, MRCUD::{*}, Snap, find_mrcud};
+use crate::machine::{Machine, parse_zfs_recursive};
+use crate::replicate::check_max_snapshots;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct CommOpts {
     pub collapse: bool,
     pub collapse_keep_both_ends: bool,
-    pub order_asc: bool
+    pub order_asc: bool,
+    pub format: OutputFormat,
+    /// Snapshots whose name matches are left out, on both sides.
+    pub exclude: Option<Regex>,
+    /// Show only the snapshots on this side; e.g. LEFT for those which a replicate would send.
+    pub only: Option<Comm>,
+    /// Follow each snapshot name with its creation date; in the collapsed outputs, that of the
+    /// first and last snapshot of each run.
+    pub show_dates: bool,
+    /// chrono format string for the dates above. RFC 3339 if None.
+    pub date_format: Option<String>,
+    /// Follow each snapshot name with its guid.
+    pub show_guids: bool,
+    /// Applied to the output only; [Dataset::comm] walks both sides in creation order regardless.
+    pub sort_by: SortBy,
+    /// Take the right-hand side's snapshots from this file, as saved from `zfs list -Hp -o
+    /// name,creation,guid,userrefs,used,referenced,written,createtxg -t snapshot`, rather than
+    /// asking its machine; see [snaps_from_file].
+    pub right_from_file: Option<String>,
+    /// Fail if either side has more snapshots than this; see [check_max_snapshots].
+    pub max_snapshots: Option<usize>,
+    /// Color the text output after the side each snapshot is on; see [paint_side]. Never the JSON.
+    pub color: bool,
+    /// Only show the snapshots taken within this window, on both sides.
+    pub window: DateWindow,
+    /// Only show the most recent snapshot in common and those after it, on either side; i.e. where
+    /// they diverge. All of them if there's none in common.
+    pub diverged_only: bool,
+    /// Compare the descendants of both datasets too, up to this many levels below them, one
+    /// section per dataset; see [comm_hierarchy_cli].
+    pub depth: Option<u32>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// The order in which comm and list show snapshots.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum SortBy {
+    #[default]
+    Creation,
+    Name,
+    Guid,
+}
+
+impl SortBy {
+    /// Stable-sorts `items` by the snapshot each refers to. Creation leaves them untouched, since
+    /// both [Dataset::comm] and [Machine::get_snaps] already yield them oldest first.
+    pub fn sort<T>(self, items: &mut [T], snap_of: impl Fn(&T) -> &Snap) {
+        match self {
+            SortBy::Creation => (),
+            SortBy::Name => items.sort_by(|a, b| snap_of(a).name.cmp(&snap_of(b).name)),
+            SortBy::Guid => items.sort_by_key(|item| snap_of(item).guid),
+        }
+    }
+}
+
+/// A span of time to which comm and list restrict the snapshots shown, by creation date; both ends
+/// included. For display only: which snapshots the two sides have in common, and so what
+/// replicate would do, may well depend on those left out.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DateWindow {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl DateWindow {
+    pub fn contains(&self, snap: &Snap) -> bool {
+        self.since.is_none_or(|since| snap.creation >= since) && self.until.is_none_or(|until| snap.creation <= until)
+    }
 }
 
 const INDENT_WIDTH : usize = 12;
@@ -20,63 +100,348 @@ pub fn comm_cli(
     opts: CommOpts
 ) -> Result<String, anyhow::Error> {
     dst_ds.append_relative(&src_ds);
+    if let Some(depth) = opts.depth {
+        return comm_hierarchy_cli(src_machine, src_ds, dst_machine, dst_ds, depth, opts);
+    }
     src_machine.get_snaps(&mut src_ds).context(format!(r#"Unable to get snapshots for "{}""#, src_ds))?;
-    dst_machine.get_snaps(&mut dst_ds).context(format!(r#"Unable to get snapshots for "{}""#, dst_ds))?;
+    match &opts.right_from_file {
+        Some(path) => dst_ds.snaps = snaps_from_file(path, &dst_ds)?,
+        None => dst_machine.get_snaps(&mut dst_ds).context(format!(r#"Unable to get snapshots for "{}""#, dst_ds))?,
+    }
+    log::info!(r#"There are {} snapshot(s) in "{src_machine}:{src_ds}" and {} in "{dst_machine}:{dst_ds}"."#, src_ds.snaps.len(), dst_ds.snaps.len());
+    check_max_snapshots(&src_machine, &src_ds, opts.max_snapshots)?;
+    check_max_snapshots(&dst_machine, &dst_ds, opts.max_snapshots)?;
+    if src_ds.snaps.is_empty() {
+        bail!(r#"Source dataset "{src_machine}:{src_ds}" has no snapshots to compare."#);
+    }
     return do_comm(src_ds, dst_ds, opts);
 }
 
+/// Like [comm_cli], for `src_ds` and `dst_ds` along with their descendants up to `depth` levels
+/// below, fetched with a single zfs-list on either side. Descendants are paired up by their name
+/// relative to either, and compared one pair after the other, each in a section of its own; in
+/// JSON, an array of {left, right, snapshots} objects. A descendant missing on either side is
+/// compared against no snapshots at all.
+fn comm_hierarchy_cli(
+    src_machine: Machine,
+    src_ds: Dataset,
+    dst_machine: Machine,
+    dst_ds: Dataset,
+    depth: u32,
+    opts: CommOpts,
+) -> Result<String, anyhow::Error> {
+    let src = src_machine.get_snaps_recursive(&src_ds, Some(depth)).context(format!(r#"Unable to get snapshots for "{src_ds}" and its descendants"#))?;
+    let dst = match &opts.right_from_file {
+        Some(path) => read_listing(path)?,
+        None => dst_machine.get_snaps_recursive(&dst_ds, Some(depth)).context(format!(r#"Unable to get snapshots for "{dst_ds}" and its descendants"#))?,
+    };
+    let pairs = pair_descendants(&src_ds, src, &dst_ds, dst, depth);
+    if pairs.is_empty() {
+        bail!(r#"Neither "{src_machine}:{src_ds}" nor "{dst_machine}:{dst_ds}" has any snapshots, nor do their descendants."#);
+    }
+    let mut sections = Vec::new();
+    for (idx, (src_child, dst_child)) in pairs.into_iter().enumerate() {
+        check_max_snapshots(&src_machine, &src_child, opts.max_snapshots)?;
+        check_max_snapshots(&dst_machine, &dst_child, opts.max_snapshots)?;
+        if opts.format == OutputFormat::Json {
+            let comparison = compare(&src_child, &dst_child, &opts);
+            warn_conflicts(&comparison.conflicts);
+            sections.push(json!({
+                "left": src_child.fullname(),
+                "right": dst_child.fullname(),
+                "snapshots": json_entries(&comparison.tagged, &comparison.conflicts),
+            }));
+            continue;
+        }
+        if idx > 0 {
+            println!();
+        }
+        println!("==> {src_machine}:{src_child} vs. {dst_machine}:{dst_child} <==");
+        do_comm(src_child, dst_child, opts.clone())?;
+    }
+    if opts.format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&sections).unwrap());
+    }
+    return Ok("".to_string());
+}
+
+/// Pairs up the datasets listed in `src` and `dst` (as [Machine::get_snaps_recursive] returns
+/// them) by their name relative to `src_root` and `dst_root` respectively, up to `depth` levels
+/// below those; datasets found on one side only are paired with an empty one. Roots first, then
+/// by name.
+fn pair_descendants(
+    src_root: &Dataset,
+    mut src: HashMap<String, Vec<Snap>>,
+    dst_root: &Dataset,
+    mut dst: HashMap<String, Vec<Snap>>,
+    depth: u32,
+) -> Vec<(Dataset, Dataset)> {
+    let relative_names = |root: &Dataset, listed: &HashMap<String, Vec<Snap>>| listed.keys()
+        .filter_map(|name| match name.strip_prefix(root.fullname())? {
+            "" => Some(String::new()),
+            rest => rest.strip_prefix('/').map(str::to_string),
+        })
+        .filter(|relative| relative.split('/').filter(|c| !c.is_empty()).count() <= depth as usize)
+        .collect::<Vec<_>>();
+    let relatives = relative_names(src_root, &src).into_iter()
+        .chain(relative_names(dst_root, &dst))
+        .collect::<BTreeSet<_>>();
+    relatives.into_iter()
+        .map(|relative| {
+            let descendant = |root: &Dataset, listed: &mut HashMap<String, Vec<Snap>>| {
+                let mut ds = Dataset::from_str(root.fullname()).expect("Application bug: a parsed dataset name no longer parses.");
+                ds.append_path(&relative);
+                ds.snaps = listed.remove(ds.fullname()).unwrap_or_default();
+                ds
+            };
+            (descendant(src_root, &mut src), descendant(dst_root, &mut dst))
+        })
+        .collect()
+}
+
+/// Reads `zfs list` output saved in `path`, which may be that of a whole hierarchy (`zfs list -r`),
+/// into the snapshots of each dataset.
+fn read_listing(path: &str) -> Result<HashMap<String, Vec<Snap>>, anyhow::Error> {
+    let listing = std::fs::read_to_string(path).context(format!("Unable to read {path}."))?;
+    parse_zfs_recursive(&listing).context(format!("Unable to parse {path}"))
+}
+
+/// Reads the snapshots of `ds` from the `zfs list` output saved in `path`; which may be that of a
+/// whole hierarchy (`zfs list -r`), with the snapshots of other datasets.
+fn snaps_from_file(path: &str, ds: &Dataset) -> Result<Vec<Snap>, anyhow::Error> {
+    let listing = std::fs::read_to_string(path).context(format!("Unable to read {path}."))?;
+    let listed = Dataset::with_snaps_from_listing(ds.fullname(), &listing).context(format!("Unable to parse {path}"))?;
+    Ok(listed.snaps)
+}
+
 // This function doesn't interact with its environment, so it can be called from a test harness.
 // It assumes the input datasets have been populated with snapshots already.
 fn do_comm(src_ds: Dataset, dst_ds: Dataset, opts: CommOpts) -> Result<String, anyhow::Error> {
-    let (mut tagged, _) = src_ds.comm(&dst_ds);
-    if !opts.order_asc {
-        tagged.reverse();
+    let comparison = compare(&src_ds, &dst_ds, &opts);
+    warn_conflicts(&comparison.conflicts);
+    if opts.format == OutputFormat::Json {
+        println!("{}", render_json(&comparison.tagged, &comparison.conflicts));
+        return Ok("".to_string());
+    }
+    for line in render_text(&comparison, &opts) {
+        println!("{line}");
     }
-    match (opts.collapse, opts.collapse_keep_both_ends) {
+    Ok("".to_string())
+}
+
+/// Renders `comparison` as the text output of comm, as per `opts`, one line per item.
+fn render_text(comparison: &Comparison, opts: &CommOpts) -> Vec<String> {
+    let Comparison { tagged, mrcud, conflicts } = comparison;
+    // Runs are only meaningful between chronologically adjacent snapshots.
+    let (collapse, collapse_keep_both_ends) = match opts.sort_by != SortBy::Creation && (opts.collapse || opts.collapse_keep_both_ends) {
+        true => {
+            log::warn!("Not collapsing runs of snapshots, since they aren't sorted by creation.");
+            (false, false)
+        }
+        false => (opts.collapse, opts.collapse_keep_both_ends),
+    };
+    // With a single side left to show, there's no point in indenting it.
+    let indent_of = |side| match (opts.only, side) {
+        (Some(_), _) => 0,
+        (None, LEFT) => 0,
+        (None, BOTH) => 1,
+        (None, RIGHT) => 2,
+    };
+    let mut lines = Vec::new();
+    if opts.diverged_only && matches!(mrcud, NoneInCommon) {
+        lines.push("(No snapshot in common, so they diverge from the start; showing them all.)".to_string());
+    }
+    // Names are padded to a common width, so that guids and dates line up within each column.
+    let name_width = tagged.iter().map(|(_, snap)| snap.name.len()).max().unwrap_or(0);
+    let label = |snap: &Snap| {
+        let mut label = snap.name.clone();
+        if opts.show_guids || opts.show_dates {
+            label = format!("{label:<name_width$}");
+        }
+        if opts.show_guids {
+            label = format!("{label}  {:<20}", snap.guid);
+        }
+        if opts.show_dates {
+            label = format!("{label}  {}", render_date(snap, &opts.date_format));
+        }
+        if conflicts.contains(snap.name.as_str()) {
+            label = format!("{}  [conflict]", label.trim_end());
+        }
+        label.trim_end().to_string()
+    };
+    let paint = |side, text| paint_side(side, text, opts.color);
+    // Conflicts are set apart from the runs around them, lest collapsing hide them.
+    let group_key = |(side, snap): &&(Comm, &Snap)| (*side, conflicts.contains(snap.name.as_str()));
+    match (collapse, collapse_keep_both_ends) {
         (false, false) => {
-            for t in tagged {
-                let (side, snap) = t;
-                let indent = match side {
-                    LEFT => 0,
-                    BOTH => 1,
-                    RIGHT => 2,
-                };
-                let line = format!("{space:n$}{snapname}\n", space = "", n = INDENT_WIDTH * indent, snapname = snap.name);
-                print!("{}", line);
+            for &(side, snap) in tagged {
+                let indent = indent_of(side);
+                lines.push(format!("{space:n$}{snapname}", space = "", n = INDENT_WIDTH * indent, snapname = paint(side, label(snap))));
             }
         }
         (true, false) => {
-            for (side, mut group) in &tagged.into_iter().group_by(|(side, _)| *side) {
+            for ((side, _), mut group) in &tagged.iter().group_by(group_key) {
                 let (_, group_leader) = group.next().unwrap();
-                let rest_of_group_len = group.count();
-                let indent = match side {
-                    LEFT => 0,
-                    BOTH => 1,
-                    RIGHT => 2,
+                let rest_of_group = group.collect::<Vec<_>>();
+                let rest_of_group_len = rest_of_group.len();
+                let indent = indent_of(side);
+                lines.push(format!("{space:n$}{group_leader_name}", space = "", n = indent * INDENT_WIDTH, group_leader_name = paint(side, label(group_leader))));
+                let rest = match rest_of_group.last() {
+                    Some((_, last_snap)) if opts.show_dates => format!("(+{rest_of_group_len}, until {})", render_date(last_snap, &opts.date_format)),
+                    _ => format!("(+{rest_of_group_len})"),
                 };
-                println!("{space:n$}{group_leader_name}", space = "", n = indent * INDENT_WIDTH, group_leader_name = group_leader.name);
-                println!("{space:n$}  (+{rest_of_group_len})", space = "", n = indent * INDENT_WIDTH);
+                lines.push(format!("{space:n$}  {rest}", space = "", n = indent * INDENT_WIDTH, rest = paint(side, rest)));
             }
         }
-        (false, true) => {
-            for (side, mut group) in &tagged.into_iter().group_by(|(side, _)| *side) {
+        // Both together: both ends, along with how many there are in the run, all told.
+        (with_total, true) => {
+            for ((side, _), mut group) in &tagged.iter().group_by(group_key) {
                 let (_, group_leader) = group.next().unwrap();
                 let last = group.enumerate().last();
-                let indent = match side {
-                    LEFT => 0,
-                    BOTH => 1,
-                    RIGHT => 2,
-                };
-                println!("{space:n$}{group_leader_name}", space = "", n = indent * INDENT_WIDTH, group_leader_name = group_leader.name);
+                let indent = indent_of(side);
+                lines.push(format!("{space:n$}{group_leader_name}", space = "", n = indent * INDENT_WIDTH, group_leader_name = paint(side, label(group_leader))));
                 if let Some((middle_elt_cnt, (_, last_snap))) = last {
-                    println!("{space:n$}  (+{group_len})", space = "", n = indent * INDENT_WIDTH, group_len = middle_elt_cnt);
-                    println!("{space:n$}{group_trailer_name}", space = "", n = indent * INDENT_WIDTH, group_trailer_name = last_snap.name)
+                    lines.push(format!("{space:n$}  {group_len}", space = "", n = indent * INDENT_WIDTH, group_len = paint(side, render_middle_count(middle_elt_cnt, with_total))));
+                    lines.push(format!("{space:n$}{group_trailer_name}", space = "", n = indent * INDENT_WIDTH, group_trailer_name = paint(side, label(last_snap))));
                 }
             }
         }
-        (true, true) => panic!()
     }
-    Ok("".to_string())
+    lines
+}
+
+/// What --collapse-keep-both-ends shows between the ends of a run: how many snapshots there are
+/// in between; and, along with --collapse, in the whole run.
+fn render_middle_count(middle_elt_cnt: usize, with_total: bool) -> String {
+    match with_total {
+        true => format!("(+{middle_elt_cnt}, {} in all)", middle_elt_cnt + 2),
+        false => format!("(+{middle_elt_cnt})"),
+    }
+}
+
+fn warn_conflicts(conflicts: &HashSet<&str>) {
+    if !conflicts.is_empty() {
+        log::warn!("{} snapshot name(s) found on both sides, but with different guids; i.e. not the same snapshot: {}", conflicts.len(), conflicts.iter().sorted().join(", "));
+    }
+}
+
+/// What comm makes of two datasets, before any of it is rendered; see [compare].
+#[derive(Debug)]
+pub struct Comparison<'a> {
+    /// The snapshots of either side, each tagged with the side it's on, in the order and with the
+    /// sides that the [CommOpts] asked for.
+    pub tagged: Vec<(Comm, &'a Snap)>,
+    /// How the two relate, as replicate sees it; regardless of the sides and snapshots shown.
+    pub mrcud: MRCUD<'a>,
+    /// The [name_conflicts] among `tagged`.
+    pub conflicts: HashSet<&'a str>,
+}
+
+/// Compares `src_ds` and `dst_ds`, whose snapshots have been populated already, as comm does; as
+/// per `opts.diverged_only`, `exclude`, `window`, `only`, `sort_by` and `order_asc`, but regardless
+/// of how it's to be rendered. Does no I/O whatsoever, so it can be used by anything that'd rather
+/// have the result than its rendering. The snapshots left out by `exclude` and `window` are only
+/// left out of `tagged`: the MRCUD, and where they diverge, are those of all of them.
+pub fn compare<'a>(src_ds: &'a Dataset, dst_ds: &'a Dataset, opts: &CommOpts) -> Comparison<'a> {
+    let (mut tagged, most_recent_common_idx) = src_ds.comm(dst_ds);
+    if opts.diverged_only {
+        tagged.drain(..most_recent_common_idx.unwrap_or(0));
+    }
+    if let Some(exclude) = &opts.exclude {
+        tagged.retain(|(_, snap)| !exclude.is_match(&snap.name));
+    }
+    tagged.retain(|(_, snap)| opts.window.contains(snap));
+    opts.sort_by.sort(&mut tagged, |(_, snap)| snap);
+    if !opts.order_asc {
+        tagged.reverse();
+    }
+    let conflicts = name_conflicts(&tagged);
+    if let Some(only) = opts.only {
+        tagged.retain(|(side, _)| *side == only);
+    }
+    Comparison { tagged, mrcud: find_mrcud(src_ds, dst_ds), conflicts }
+}
+
+/// The names of the snapshots found on both sides, but not as the same snapshot (i.e. guid); e.g.
+/// because it was destroyed and taken anew on one side. [Dataset::comm] tells them apart, as one
+/// LEFT and one RIGHT snapshot.
+fn name_conflicts<'a>(tagged: &[(Comm, &'a Snap)]) -> HashSet<&'a str> {
+    let names_on = |side| tagged.iter()
+        .filter(|(s, _)| *s == side)
+        .map(|(_, snap)| snap.name.as_str())
+        .collect::<HashSet<_>>();
+    names_on(LEFT).intersection(&names_on(RIGHT)).copied().collect()
+}
+
+/// Colors `text` after the side it's on, if `color`: red for the source only, green for both, blue
+/// for the destination only.
+fn paint_side(side: Comm, text: String, color: bool) -> String {
+    if !color {
+        return text;
+    }
+    match side {
+        LEFT => text.red().to_string(),
+        BOTH => text.green().to_string(),
+        RIGHT => text.blue().to_string(),
+    }
+}
+
+fn render_date(snap: &Snap, date_format: &Option<String>) -> String {
+    match date_format {
+        Some(date_format) => snap.creation.format(date_format).to_string(),
+        None => snap.creation.to_rfc3339(),
+    }
+}
+
+/// Renders the output of [Dataset::comm] as a JSON array, for consumption by other tools.
+fn render_json(tagged: &[(Comm, &Snap)], conflicts: &HashSet<&str>) -> String {
+    serde_json::to_string_pretty(&json_entries(tagged, conflicts)).unwrap()
+}
+
+fn json_entries(tagged: &[(Comm, &Snap)], conflicts: &HashSet<&str>) -> Vec<serde_json::Value> {
+    tagged.iter()
+        .map(|(side, snap)| json!({
+            "side": match side {
+                LEFT => "left",
+                BOTH => "both",
+                RIGHT => "right",
+            },
+            "name": snap.name,
+            "guid": snap.guid,
+            "creation": snap.creation.to_rfc3339(),
+            "used": snap.used,
+            "referenced": snap.referenced,
+            "written": snap.written,
+            "conflict": conflicts.contains(snap.name.as_str()),
+        }))
+        .collect()
+}
+
+#[test]
+fn test_render_json() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let (tagged, _) = tank_webdata.comm(&zelda_webdata);
+    let res: serde_json::Value = serde_json::from_str(&render_json(&tagged, &HashSet::new())).unwrap();
+    assert_eq!(res.as_array().unwrap().len(), tagged.len());
+    assert_eq!(res[0], json!({
+        "side": "right",
+        "name": "2020-12-06",
+        "guid": 8919435986364836257u64,
+        "creation": "2020-12-06T02:04:12+00:00",
+        "used": 1121001472u64,
+        "referenced": 54068316143u64,
+        "written": 2606941276u64,
+        "conflict": false,
+    }));
+    assert_eq!(res.as_array().unwrap().last().unwrap()["side"], "left");
 }
 
 #[test]
@@ -90,9 +455,342 @@ fn test_do_comm() {
         "zelda/webdata",
         include_str!("dataset/tests/zelda_webdata.list")
     );
-    let mut opts = CommOpts::default();
-    opts.collapse = true;
-    opts.collapse_keep_both_ends = false;
-    opts.order_asc = false;
+    let opts = CommOpts {
+        collapse: true,
+        collapse_keep_both_ends: false,
+        order_asc: false,
+        format: OutputFormat::Text,
+        exclude: None,
+        only: None,
+        show_dates: false,
+        date_format: None,
+        show_guids: false,
+        sort_by: SortBy::Creation,
+        right_from_file: None,
+        max_snapshots: None,
+        depth: None,
+        color: false,
+        window: DateWindow::default(),
+        diverged_only: false,
+    };
+    assert_eq!(render_comm(&tank_webdata, &zelda_webdata, &opts), [
+        "2021-11-21",
+        "  (+6)",
+        "            2021-11-14",
+        "              (+74)",
+        "                        2021-08-29",
+        "                          (+102)",
+    ]);
     do_comm(tank_webdata, zelda_webdata, opts).unwrap();
-}
\ No newline at end of file
+}
+
+/// The text output of comm on `src_ds` and `dst_ds`, as per `opts`.
+#[cfg(test)]
+fn render_comm(src_ds: &Dataset, dst_ds: &Dataset, opts: &CommOpts) -> Vec<String> {
+    render_text(&compare(src_ds, dst_ds, opts), opts)
+}
+
+#[test]
+fn test_do_comm_collapse_both() {
+    use crate::dataset::build_fake_dataset;
+    assert_eq!(render_middle_count(3, false), "(+3)");
+    assert_eq!(render_middle_count(3, true), "(+3, 5 in all)");
+    assert_eq!(render_middle_count(0, true), "(+0, 2 in all)");
+    let tank_webdata = build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let zelda_webdata = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata.list"));
+    let opts = CommOpts { collapse: true, collapse_keep_both_ends: true, show_dates: true, ..CommOpts::default() };
+    assert_eq!(render_comm(&tank_webdata, &zelda_webdata, &opts), [
+        "2021-11-21  2021-11-21T02:00:06+00:00",
+        "  (+5, 7 in all)",
+        "2021-11-15  2021-11-15T02:00:06+00:00",
+        "            2021-11-14  2021-11-14T02:00:25+00:00",
+        "              (+73, 75 in all)",
+        "            2021-08-30  2021-08-30T02:00:07+00:00",
+        "                        2021-08-29  2021-08-29T02:00:07+00:00",
+        "                          (+101, 103 in all)",
+        "                        2020-12-06  2020-12-06T02:04:12+00:00",
+    ]);
+    let opts = CommOpts { collapse: true, collapse_keep_both_ends: true, only: Some(LEFT), ..CommOpts::default() };
+    assert_eq!(render_comm(&tank_webdata, &zelda_webdata, &opts), ["2021-11-21", "  (+5, 7 in all)", "2021-11-15"]);
+}
+
+#[test]
+fn test_compare() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let zelda_webdata = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata.list"));
+    let comparison = compare(&tank_webdata, &zelda_webdata, &CommOpts { order_asc: true, ..CommOpts::default() });
+    assert_eq!(comparison.tagged, tank_webdata.comm(&zelda_webdata).0);
+    assert!(comparison.conflicts.is_empty());
+    let SourceHasMore(mrc) = comparison.mrcud else { panic!("{:?}", comparison.mrcud) };
+    // The relationship of both sides stands, whichever of them are shown, and in whichever order.
+    let comparison = compare(&tank_webdata, &zelda_webdata, &CommOpts { only: Some(LEFT), ..CommOpts::default() });
+    assert!(comparison.tagged.iter().all(|(side, _)| *side == LEFT));
+    assert!(comparison.tagged.windows(2).all(|w| w[0].1.creation >= w[1].1.creation));
+    assert!(matches!(comparison.mrcud, SourceHasMore(same) if same == mrc));
+    // Nor do the snapshots left out by --exclude or --since change it, even the one in common.
+    let exclude = Regex::new(&format!("^{}$", mrc.name)).ok();
+    let comparison = compare(&tank_webdata, &zelda_webdata, &CommOpts { exclude, ..CommOpts::default() });
+    assert!(comparison.tagged.iter().all(|(_, snap)| snap.name != mrc.name));
+    assert_eq!(comparison.tagged.len(), tank_webdata.comm(&zelda_webdata).0.len() - 1);
+    assert!(matches!(comparison.mrcud, SourceHasMore(same) if same == mrc));
+    let window = DateWindow { since: Some(mrc.creation + chrono::Duration::seconds(1)), until: None };
+    let comparison = compare(&tank_webdata, &zelda_webdata, &CommOpts { window, ..CommOpts::default() });
+    assert!(comparison.tagged.iter().all(|(side, _)| *side == LEFT));
+    assert!(matches!(comparison.mrcud, SourceHasMore(same) if same == mrc));
+    // Where they diverge is where it is, shown or not.
+    let zelda_divergence = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata-divergence.list"));
+    let exclude = Regex::new("^2021-11-14$").ok();
+    let comparison = compare(&tank_webdata, &zelda_divergence, &CommOpts { diverged_only: true, exclude, order_asc: true, ..CommOpts::default() });
+    assert!(matches!(comparison.mrcud, Divergence(mrc) if mrc.name == "2021-11-14"));
+    assert_eq!(comparison.tagged[0].1.name, "2021-11-14DIVERGE");
+    assert!(comparison.tagged.iter().all(|(side, _)| *side != BOTH));
+}
+
+#[test]
+fn test_diverged_only() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let zelda_webdata = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata-divergence.list"));
+    let opts = CommOpts { diverged_only: true, order_asc: true, ..CommOpts::default() };
+    let Comparison { tagged, mrcud, .. } = compare(&tank_webdata, &zelda_webdata, &opts);
+    assert!(matches!(mrcud, Divergence(mrc) if mrc == tagged[0].1));
+    let (all, _) = tank_webdata.comm(&zelda_webdata);
+    // The most recent snapshot in common, then both tails.
+    assert_eq!(tagged[0].0, BOTH);
+    assert!(tagged[1..].iter().all(|(side, _)| *side != BOTH));
+    assert!(tagged.iter().any(|(side, _)| *side == LEFT) && tagged.iter().any(|(side, _)| *side == RIGHT));
+    assert!(tagged.len() < all.len());
+    assert_eq!(tagged.last(), all.last());
+    let lines = render_comm(&tank_webdata, &zelda_webdata, &opts);
+    assert_eq!(lines[..3], ["            2021-11-14", "                        2021-11-14DIVERGE", "2021-11-15"]);
+    assert_eq!(lines.last().unwrap(), "2021-11-21");
+
+    // With none in common, everything.
+    let tank_webdata = build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let tank_backups = build_fake_dataset("tank/backups", include_str!("dataset/tests/tank_backups-autosnap.list"));
+    let Comparison { tagged, mrcud, .. } = compare(&tank_webdata, &tank_backups, &opts);
+    assert!(matches!(mrcud, NoneInCommon));
+    assert_eq!(tagged.len(), tank_webdata.snaps.len() + tank_backups.snaps.len());
+    let lines = render_comm(&tank_webdata, &tank_backups, &CommOpts { collapse: true, ..opts });
+    assert_eq!(lines, [
+        "(No snapshot in common, so they diverge from the start; showing them all.)",
+        "2021-08-30",
+        "  (+81)",
+        "                        autosnap_2024-01-01_00:00:01_daily",
+        "                          (+8)",
+    ]);
+}
+
+#[test]
+fn test_sort_by() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let (tagged, _) = tank_webdata.comm(&zelda_webdata);
+    let mut sorted = tagged.clone();
+    SortBy::Creation.sort(&mut sorted, |(_, snap)| snap);
+    assert_eq!(sorted, tagged);
+    SortBy::Name.sort(&mut sorted, |(_, snap)| snap);
+    assert!(sorted.windows(2).all(|w| w[0].1.name <= w[1].1.name));
+    SortBy::Guid.sort(&mut sorted, |(_, snap)| snap);
+    assert!(sorted.windows(2).all(|w| w[0].1.guid <= w[1].1.guid));
+    assert_eq!(sorted.len(), tagged.len());
+    // Collapsing is given up on, rather than grouping snapshots that aren't adjacent in time.
+    let opts = CommOpts { collapse: true, sort_by: SortBy::Name, ..CommOpts::default() };
+    let lines = render_comm(&tank_webdata, &zelda_webdata, &opts);
+    assert_eq!(lines.len(), tagged.len());
+    assert!(lines.iter().all(|line| !line.contains("(+")));
+    assert_eq!(lines[..2], ["2021-11-21", "2021-11-20"]);
+}
+
+#[test]
+fn test_date_window() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let shown = |window: DateWindow| tank_webdata.snaps.iter().filter(|s| window.contains(s)).map(|s| s.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(shown(DateWindow::default()).len(), tank_webdata.snaps.len());
+    // Snapshots are taken a little after midnight, UTC.
+    let since = "2021-11-20T00:00:00Z".parse().ok();
+    assert_eq!(shown(DateWindow { since, until: None }), ["2021-11-20", "2021-11-21"]);
+    let until = "2021-09-02T23:59:59Z".parse().ok();
+    assert_eq!(shown(DateWindow { since: None, until }), ["2021-08-30", "2021-09-02"]);
+    let since = "2021-09-02T02:00:08Z".parse().ok();
+    assert_eq!(shown(DateWindow { since, until }), ["2021-09-02"]);
+    assert!(shown(DateWindow { since: until, until: since }).is_empty());
+}
+
+#[test]
+fn test_paint_side() {
+    assert_eq!(paint_side(LEFT, "2021-11-15".to_string(), false), "2021-11-15");
+    let painted = [LEFT, BOTH, RIGHT].map(|side| paint_side(side, "2021-11-15".to_string(), true));
+    assert!(painted.iter().all(|p| p.starts_with("\x1b[") && p.contains("2021-11-15")));
+    assert!(painted[0] != painted[1] && painted[1] != painted[2] && painted[0] != painted[2]);
+    let opts = CommOpts { collapse: true, color: true, ..CommOpts::default() };
+    let tank_webdata = crate::dataset::build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let zelda_webdata = crate::dataset::build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata.list"));
+    let lines = render_comm(&tank_webdata, &zelda_webdata, &opts);
+    // The indentation stays out of it.
+    assert_eq!(lines[..4], [
+        paint_side(LEFT, "2021-11-21".to_string(), true),
+        format!("  {}", paint_side(LEFT, "(+6)".to_string(), true)),
+        format!("{:12}{}", "", paint_side(BOTH, "2021-11-14".to_string(), true)),
+        format!("{:14}{}", "", paint_side(BOTH, "(+74)".to_string(), true)),
+    ]);
+}
+
+#[test]
+fn test_do_comm_only() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    // Each side on its own, unindented.
+    for (only, expected) in [(LEFT, ["2021-11-21", "  (+6)"]), (BOTH, ["2021-11-14", "  (+74)"]), (RIGHT, ["2021-08-29", "  (+102)"])] {
+        let opts = CommOpts { collapse: true, only: Some(only), ..CommOpts::default() };
+        assert_eq!(render_comm(&tank_webdata, &zelda_webdata, &opts), expected);
+    }
+}
+
+#[test]
+fn test_do_comm_show_dates() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let lines = render_comm(&tank_webdata, &zelda_webdata, &CommOpts { show_dates: true, ..CommOpts::default() });
+    assert_eq!(lines.len(), tank_webdata.comm(&zelda_webdata).0.len());
+    assert_eq!(lines[0], "2021-11-21  2021-11-21T02:00:06+00:00");
+    assert_eq!(lines.last().unwrap(), "                        2020-12-06  2020-12-06T02:04:12+00:00");
+    let lines = render_comm(&tank_webdata, &zelda_webdata, &CommOpts { collapse: true, show_dates: true, ..CommOpts::default() });
+    assert_eq!(lines[..2], ["2021-11-21  2021-11-21T02:00:06+00:00", "  (+6, until 2021-11-15T02:00:06+00:00)"]);
+    let opts = CommOpts { collapse_keep_both_ends: true, show_dates: true, date_format: Some("%F".to_string()), ..CommOpts::default() };
+    assert_eq!(render_comm(&tank_webdata, &zelda_webdata, &opts)[..3], ["2021-11-21  2021-11-21", "  (+5)", "2021-11-15  2021-11-15"]);
+    assert_eq!(render_date(tank_webdata.oldest_snap(), &None), "2021-08-30T02:00:07+00:00");
+    assert_eq!(render_date(tank_webdata.oldest_snap(), &Some("%F %H:%M".to_string())), "2021-08-30 02:00");
+}
+#[test]
+fn test_do_comm_autosnap_names() {
+    use crate::dataset::build_fake_dataset;
+    let autosnap = include_str!("dataset/tests/tank_backups-autosnap.list");
+    let tank_backups = build_fake_dataset("tank/backups", autosnap);
+    // The destination got as far as the fifth snapshot.
+    let backup_backups = build_fake_dataset(
+        "backup/backups",
+        &autosnap.replace("tank/", "backup/").lines().take(5).join("\n")
+    );
+    let (tagged, _) = tank_backups.comm(&backup_backups);
+    assert_eq!(tagged.iter().filter(|(side, _)| *side == BOTH).count(), 5);
+    assert_eq!(tagged.iter().find(|(side, _)| *side == LEFT).unwrap().1.name, "autosnap_2024-01-05_00:00:01_daily");
+    assert!(name_conflicts(&tagged).is_empty());
+    let opts = CommOpts { collapse: true, show_dates: true, ..CommOpts::default() };
+    assert_eq!(render_comm(&tank_backups, &backup_backups, &opts), [
+        "autosnap_2024-01-07_00:00:01_daily  2024-01-07T00:00:01+00:00",
+        "  (+3, until 2024-01-05T00:00:01+00:00)",
+        "            autosnap_2024-01-04_00:00:01_daily  2024-01-04T00:00:01+00:00",
+        "              (+4, until 2024-01-01T00:00:01+00:00)",
+    ]);
+}
+
+#[test]
+fn test_name_conflicts() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let (tagged, _) = tank_webdata.comm(&zelda_webdata);
+    assert!(name_conflicts(&tagged).is_empty());
+
+    // zelda/webdata@2021-11-15 was taken anew, rather than received from tank/webdata.
+    let zelda_webdata_recreated = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-recreated.list")
+    );
+    let (tagged, _) = tank_webdata.comm(&zelda_webdata_recreated);
+    assert_eq!(name_conflicts(&tagged), HashSet::from(["2021-11-15"]));
+    let res: serde_json::Value = serde_json::from_str(&render_json(&tagged, &name_conflicts(&tagged))).unwrap();
+    let conflicting = res.as_array().unwrap().iter().filter(|e| e["conflict"] == true).collect::<Vec<_>>();
+    assert_eq!(conflicting.len(), 2);
+    assert_eq!(conflicting[0]["side"], "left");
+    assert_eq!(conflicting[1]["side"], "right");
+
+    // Set apart from the runs around them, collapsed or not.
+    let lines = render_comm(&tank_webdata, &zelda_webdata_recreated, &CommOpts { show_guids: true, ..CommOpts::default() });
+    assert_eq!(lines.iter().filter(|line| line.ends_with("  [conflict]")).count(), 2);
+    assert!(lines.contains(&"2021-11-15  14847246883219886430  [conflict]".to_string()));
+    let lines = render_comm(&tank_webdata, &zelda_webdata_recreated, &CommOpts { collapse: true, show_guids: true, ..CommOpts::default() });
+    assert_eq!(lines[..6], [
+        "2021-11-21  8773152051219808400",
+        "  (+5)",
+        "                        2021-11-15  3177480281036429870  [conflict]",
+        "                          (+0)",
+        "2021-11-15  14847246883219886430  [conflict]",
+        "  (+0)",
+    ]);
+}
+
+#[test]
+fn test_pair_descendants() {
+    let listing = include_str!("dataset/tests/tank_lxc-recursive.list");
+    let tank_lxc = Dataset::from_str("tank/lxc").unwrap();
+    let backup_lxc = Dataset::from_str("backup/lxc").unwrap();
+    let src = parse_zfs_recursive(listing).unwrap();
+    // The backups have yet to get tank/lxc/db.
+    let dst = parse_zfs_recursive(&listing.replace("tank/", "backup/").lines().filter(|l| !l.contains("/db@")).join("\n")).unwrap();
+    let pairs = pair_descendants(&tank_lxc, src.clone(), &backup_lxc, dst.clone(), 1);
+    let names = pairs.iter().map(|(l, r)| (l.fullname(), r.fullname())).collect::<Vec<_>>();
+    assert_eq!(names, [("tank/lxc", "backup/lxc"), ("tank/lxc/db", "backup/lxc/db"), ("tank/lxc/web", "backup/lxc/web")]);
+    assert_eq!(pairs[0].0.snaps.len(), 2);
+    assert_eq!(pairs[1].0.snaps.len(), 1);
+    assert!(pairs[1].1.snaps.is_empty());
+    assert_eq!(pairs[2].1.snaps.len(), pairs[2].0.snaps.len());
+    // Only the roots, at depth 0.
+    let pairs = pair_descendants(&tank_lxc, src.clone(), &backup_lxc, dst, 0);
+    assert_eq!(pairs.len(), 1);
+    // Neither sibling datasets which share a prefix, nor the parent, belong.
+    let tank_lx = Dataset::from_str("tank/lx").unwrap();
+    assert!(pair_descendants(&tank_lx, src.clone(), &tank_lx, HashMap::new(), 1).is_empty());
+    let tank = Dataset::from_str("tank").unwrap();
+    assert_eq!(pair_descendants(&tank, src, &tank, HashMap::new(), 1).len(), 1);
+}
+
+#[test]
+fn test_snaps_from_file() {
+    use crate::dataset::build_fake_dataset;
+    let path = std::env::temp_dir().join(format!("zfs-rs-test-snaps-from-file-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, include_str!("dataset/tests/tank_lxc-recursive.list")).unwrap();
+    let tank_lxc_db = build_fake_dataset("tank/lxc/db", "");
+    let snaps = snaps_from_file(path, &tank_lxc_db).unwrap();
+    assert!(!snaps.is_empty());
+    let err = snaps_from_file(path, &build_fake_dataset("tank/lxc/mail", "")).unwrap_err();
+    assert_eq!(format!("{err:#}"), format!(r#"Unable to parse {path}: No snapshots of "tank/lxc/mail" in it, only of: tank/lxc, tank/lxc/db, tank/lxc/web."#));
+    std::fs::write(path, "").unwrap();
+    assert!(snaps_from_file(path, &tank_lxc_db).unwrap().is_empty());
+    std::fs::write(path, "tank/lxc/db\t1630288807\n").unwrap();
+    assert!(snaps_from_file(path, &tank_lxc_db).is_err());
+    std::fs::remove_file(path).unwrap();
+    assert!(snaps_from_file(path, &tank_lxc_db).is_err());
+}
+
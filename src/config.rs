@@ -0,0 +1,257 @@
+use anyhow::{bail, Context};
+use chrono::NaiveTime;
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::dataset::parse_spec;
+use crate::rate::parse_rate;
+use crate::replicate::{BufferTool, ReplicateDatasetOpts, ReplicateJob, replicate_dataset_cli};
+use crate::retention::{RetentionOpts, apply_retention};
+
+/// The config file of `zfs-rs run`, in TOML: a list of `[[job]]` tables.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(rename = "job", default)]
+    jobs: Vec<JobConfig>,
+}
+
+/// One `[[job]]` of the config file, as written.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobConfig {
+    /// How to refer to the job in the summary; "source -> destination" by default.
+    name: Option<String>,
+    source: String,
+    destination: String,
+    /// Time windows, "HH:MM-HH:MM" in local time, out of which the job is skipped.
+    #[serde(default)]
+    schedule: Vec<String>,
+    /// Take a snapshot named after the current local time before sending, as with
+    /// `replicate --snap-name-template`.
+    snap_name_template: Option<String>,
+    #[serde(default)]
+    replicate: ReplicateDatasetOpts,
+    /// Applied to the source, once replicated.
+    source_retention: Option<RetentionOpts>,
+    /// Applied to the destination, once replicated.
+    destination_retention: Option<RetentionOpts>,
+}
+
+/// A validated job of the config file, ready to run.
+pub struct Job {
+    pub name: String,
+    pub replicate: ReplicateJob,
+    schedule: Vec<(NaiveTime, NaiveTime)>,
+    snap_name_template: Option<String>,
+    opts: ReplicateDatasetOpts,
+    source_retention: Option<RetentionOpts>,
+    destination_retention: Option<RetentionOpts>,
+}
+
+impl Job {
+    /// Whether `now` falls within the job's schedule. A window may span midnight, e.g. 22:00-06:00.
+    fn is_scheduled_at(&self, now: NaiveTime) -> bool {
+        self.schedule.is_empty() || self.schedule.iter().any(|&(from, until)| match from < until {
+            true => from <= now && now < until,
+            false => from <= now || now < until,
+        })
+    }
+}
+
+fn parse_window(window: &str) -> Result<(NaiveTime, NaiveTime), String> {
+    let parse_time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| format!("{t} isn't a time of the form HH:MM."));
+    let (from, until) = window.split_once('-').ok_or_else(|| format!("{window} isn't of the form HH:MM-HH:MM."))?;
+    let (from, until) = (parse_time(from)?, parse_time(until)?);
+    if from == until {
+        return Err(format!("{window} is an empty window."));
+    }
+    Ok((from, until))
+}
+
+/// Parses and validates the config file. Every problem found is reported, one per line, rather
+/// than just the first one.
+pub fn load_config(content: &str) -> Result<Vec<Job>, anyhow::Error> {
+    let config: ConfigFile = toml::from_str(content).context("Invalid config file")?;
+    if config.jobs.is_empty() {
+        bail!("The config file defines no [[job]].");
+    }
+    let mut errors = Vec::new();
+    let mut jobs = Vec::new();
+    for job in config.jobs {
+        let name = job.name.clone().unwrap_or_else(|| format!("{} -> {}", job.source, job.destination));
+        let mut error = |msg: String| errors.push(format!("Job {name}: {msg}"));
+        let src = parse_spec(&job.source).map_err(|err| error(format!("can't parse {} as a valid ZFS dataset: {err}", job.source)));
+        let dst = parse_spec(&job.destination).map_err(|err| error(format!("can't parse {} as a valid ZFS dataset: {err}", job.destination)));
+        let schedule = job.schedule.iter()
+            .filter_map(|window| parse_window(window).map_err(|err| error(format!("invalid schedule: {err}"))).ok())
+            .collect();
+        if let Some(rate) = &job.replicate.ratelimit {
+            if let Err(err) = parse_rate(rate) {
+                error(format!("invalid rate limit {err}"));
+            }
+        }
+        if let Some(BufferTool::Mbuffer { size }) = &job.replicate.buffer {
+            if let Err(err) = parse_rate(size) {
+                error(format!("invalid buffer size {err}"));
+            }
+        }
+        if let Some(template) = &job.snap_name_template {
+            if job.replicate.take_snap_now.is_some() {
+                error("take_snap_now and snap_name_template are mutually exclusive.".to_string());
+            } else if let Err(err) = crate::render_snap_name_template(template, &chrono::Local::now()) {
+                error(format!("{err:#}"));
+            }
+        }
+        if job.replicate.until.is_some() && (job.replicate.take_snap_now.is_some() || job.snap_name_template.is_some()) {
+            error("until can't be combined with taking a snapshot (take_snap_now, snap_name_template).".to_string());
+        }
+        for (side, retention) in [("source", &job.source_retention), ("destination", &job.destination_retention)] {
+            if retention.as_ref().is_some_and(|r| !r.keeps_anything() && !r.allow_delete_all) {
+                error(format!("the {side}_retention policy doesn't keep anything; set some keep_* (or allow_delete_all)."));
+            }
+        }
+        if let (Ok((src_machine, src_ds)), Ok((dst_machine, dst_ds))) = (src, dst) {
+            jobs.push(Job {
+                name,
+                replicate: ReplicateJob { src_machine, src_ds, dst_machine, dst_ds },
+                schedule,
+                snap_name_template: job.snap_name_template,
+                opts: job.replicate,
+                source_retention: job.source_retention,
+                destination_retention: job.destination_retention,
+            });
+        }
+    }
+    if !errors.is_empty() {
+        bail!("Invalid config file:\n{}", errors.join("\n"));
+    }
+    Ok(jobs)
+}
+
+/// Runs `jobs` one after the other, those whose schedule includes `now`: replicates, then applies
+/// the retention policies. With `dry_run`, nothing is received, and the retention policies only
+/// print their zfs-destroy commands. Returns a summary with a line per job, as an error if any
+/// job failed.
+pub fn run_jobs_cli(jobs: Vec<Job>, now: NaiveTime, dry_run: bool) -> Result<String, anyhow::Error> {
+    let mut lines = Vec::new();
+    let mut failed = 0;
+    let total = jobs.len();
+    for mut job in jobs {
+        if !job.is_scheduled_at(now) {
+            lines.push(format!("SKIPPED {}: outside of its schedule ({}).", job.name, job.schedule.iter()
+                .map(|(from, until)| format!("{}-{}", from.format("%H:%M"), until.format("%H:%M")))
+                .join(", ")));
+            continue;
+        }
+        match run_job(&mut job, dry_run) {
+            Ok(message) => lines.push(format!("OK      {}: {message}", job.name)),
+            Err(err) => {
+                failed += 1;
+                lines.push(format!("FAILED  {}: {err:#}", job.name));
+            }
+        }
+    }
+    let summary = lines.join("\n");
+    match failed {
+        0 => Ok(summary),
+        _ => Err(anyhow::anyhow!("{summary}\n{failed} of {total} job(s) failed.")),
+    }
+}
+
+fn run_job(job: &mut Job, dry_run: bool) -> Result<String, anyhow::Error> {
+    let mut opts = job.opts.clone();
+    opts.dry_run = dry_run;
+    if let Some(template) = &job.snap_name_template {
+        opts.take_snap_now = Some(crate::render_snap_name_template(template, &chrono::Local::now())?);
+    }
+    let ReplicateJob { src_machine, src_ds, dst_machine, dst_ds } = &mut job.replicate;
+    let mut messages = vec![replicate_dataset_cli(src_machine, src_ds, dst_machine, dst_ds, opts)?.message];
+    let retentions = [
+        (&job.source_retention, src_machine, src_ds),
+        (&job.destination_retention, dst_machine, dst_ds),
+    ];
+    for (retention, machine, ds) in retentions {
+        if let Some(retention) = retention {
+            let mut retention = retention.clone();
+            retention.run_directly &= !dry_run;
+            messages.push(apply_retention(machine, ds, retention).context(format!("Retention on {machine}:{ds} failed"))?);
+        }
+    }
+    Ok(messages.join("\n"))
+}
+
+#[test]
+fn test_load_config() {
+    let jobs = load_config(r#"
+        [[job]]
+        name = "webserver"
+        source = "tank/webserver-data"
+        destination = "bk01:backup/webserver-data"
+        schedule = ["22:00-06:00"]
+        snap_name_template = "%Y-%m-%d"
+
+        [job.replicate]
+        ratelimit = "50M"
+        buffer = { mbuffer = { size = "256M" } }
+        transport_compress = "zstd"
+        send_flags = { raw = true }
+
+        [job.destination_retention]
+        keep_daily = 7
+        keep_since = "2w"
+        keep = ["hourly-*:24"]
+
+        [[job]]
+        source = "tank//containers"
+        destination = "backup"
+    "#).unwrap();
+    assert_eq!(jobs.len(), 2);
+    assert_eq!(jobs[0].name, "webserver");
+    assert_eq!(jobs[0].opts.ratelimit.as_deref(), Some("50M"));
+    assert_eq!(jobs[0].opts.buffer, Some(BufferTool::Mbuffer { size: "256M".to_string() }));
+    assert!(jobs[0].opts.send_flags.raw && jobs[0].opts.send_flags.compressed);
+    let retention = jobs[0].destination_retention.as_ref().unwrap();
+    assert_eq!((retention.keep_daily, retention.keep_last, retention.min_keep), (7, 0, 1));
+    assert_eq!(retention.keep_since, Some(chrono::Duration::days(14)));
+    assert_eq!(retention.keep[0].count, 24);
+    assert_eq!(jobs[1].name, "tank//containers -> backup");
+    assert_eq!(jobs[1].opts.buffer, Some(BufferTool::Pv));
+    assert!(jobs[1].source_retention.is_none());
+}
+
+#[test]
+fn test_load_config_reports_all_errors() {
+    let Err(err) = load_config(r#"
+        [[job]]
+        source = "tank/webserver-data/"
+        destination = "backup/webserver-data"
+        schedule = ["22:00-25:00"]
+        replicate = { ratelimit = "50Q" }
+        source_retention = { keep_unusual = false }
+    "#) else {
+        panic!("the config file should have been rejected");
+    };
+    let err = err.to_string();
+    assert_eq!(err.lines().count(), 5);
+    assert!(err.contains("can't parse tank/webserver-data/"));
+    assert!(err.contains("invalid schedule: 25:00"));
+    assert!(err.contains("invalid rate limit 50Q"));
+    assert!(err.contains("source_retention policy doesn't keep anything"));
+    assert!(load_config("[[job]]\nsource = \"tank/a\"\ndestination = \"backup/a\"\nratelimit = \"50M\"\n").is_err());
+    assert!(load_config("").is_err());
+}
+
+#[test]
+fn test_is_scheduled_at() {
+    let mut job = load_config("[[job]]\nsource = \"tank/a\"\ndestination = \"backup/a\"\n").unwrap().pop().unwrap();
+    let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    assert!(job.is_scheduled_at(at(12, 0)));
+    job.schedule = vec![parse_window("22:00-06:00").unwrap(), parse_window("12:00-13:00").unwrap()];
+    assert!(job.is_scheduled_at(at(23, 0)));
+    assert!(job.is_scheduled_at(at(5, 59)));
+    assert!(job.is_scheduled_at(at(12, 30)));
+    assert!(!job.is_scheduled_at(at(6, 0)));
+    assert!(!job.is_scheduled_at(at(13, 0)));
+    assert!(parse_window("06:00-06:00").is_err());
+}
@@ -0,0 +1,35 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set through the global `--yes`: take every [confirm] as answered yes.
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_assume_yes(assume_yes: bool) {
+    ASSUME_YES.store(assume_yes, Ordering::Relaxed);
+}
+
+/// Asks `prompt` on the terminal before some destructive operation, and returns whether the answer
+/// was yes. Without a terminal to ask on (e.g. when run from cron) the answer is no, unless `--yes`
+/// was given.
+pub fn confirm(prompt: &str) -> bool {
+    if ASSUME_YES.load(Ordering::Relaxed) {
+        return true;
+    }
+    if !std::io::stdin().is_terminal() {
+        eprintln!("{prompt} Not without a terminal to ask for confirmation on. Hint: use --yes to go ahead anyway.");
+        return false;
+    }
+    eprint!("{prompt} [y/N] ");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    return matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+}
+
+#[test]
+fn test_confirm_assume_yes() {
+    set_assume_yes(true);
+    assert!(confirm("Destroy everything?"));
+    set_assume_yes(false);
+}
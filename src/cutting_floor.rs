@@ -1,4 +1,4 @@
-#![cfg(never)]
+#![cfg(any())]  // Never built: any() of nothing is false.
 
 fn splice_with_progressbar(w: RawFd, r: RawFd, estimated: u64) -> Result<(), anyhow::Error> {
     use indicatif::ProgressBar;
@@ -3,15 +3,18 @@ use std::cmp::Ordering::{Less, Equal, Greater};
 use std::str::FromStr;
 use self::Comm::*;
 use self::MRCUD::*;
-use chrono::{Datelike, DateTime, Duration};
+use chrono::DateTime;
 use chrono::offset::Utc;
 use itertools::Itertools;
-use regex::Regex;
 use thiserror::Error;
-use crate::machine::Machine;
+use crate::machine::{Machine, ParseZfsError, parse_zfs_recursive, shell_quote_if_needed};
 
 #[cfg(test)]
-use crate::machine::parse_zfs;
+use chrono::{Datelike, Duration};
+#[cfg(test)]
+use regex::Regex;
+#[cfg(test)]
+use crate::machine::Location;
 
 /// Represents a ZFS dataset
 #[derive(Debug)]
@@ -36,11 +39,15 @@ pub struct Dataset {
     relative_idx: Option<usize>, // 1st '/' pool/dataset separator
     /// Snapshots must always be ordered by creation time, oldest first.
     pub snaps: Vec<Snap>,
+    /// Bookmarks, ordered like `snaps`. Only the name, creation and guid of a bookmark are
+    /// meaningful; the guid is that of the snapshot the bookmark was created from.
+    pub bookmarks: Vec<Snap>,
 }
 
 /// Describes the relationship of two sets of snapshots belonging to the same datset.
 /// Short for "Most Recent Common, Up-to-date, or Divergence"
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum MRCUD<'a> {
     /// No snapshots in common.
     NoneInCommon,
@@ -93,6 +100,17 @@ pub fn find_mrcud<'a>(source: &'a Dataset, destination: &'_ Dataset) -> MRCUD<'a
     }
 }
 
+/// For when [find_mrcud] finds [MRCUD::NoneInCommon]: find the most recent bookmark in `source`
+/// of a snapshot which `destination` still has, so that an incremental can be sent from it.
+/// Only bookmarks older than the newest snapshot in `source` are of any use.
+pub fn find_bookmark_base<'a>(source: &'a Dataset, destination: &'_ Dataset) -> Option<&'a Snap> {
+    let newest = source.snaps.last()?;
+    source.bookmarks.iter()
+        .rev()
+        .filter(|b| b.creation < newest.creation)
+        .find(|b| destination.snaps.iter().any(|s| s.guid == b.guid))
+}
+
 
 #[derive(Error, Debug)]
 pub enum SpecParseError {
@@ -106,9 +124,23 @@ pub enum SpecParseError {
     IllegalCharacters(String),
     #[error("{0}: empty dataset components (think \"zfs create testpool/////dataset\") are not allowed.")]
     EmptyComponent(String),
+    #[error("{0}: not a valid host name or IP address (with IPv6 addresses in brackets), optionally preceded by a user name and \"@\".")]
+    IllegalHostname(String),
+}
+
+/// Why [Dataset::with_snaps_from_listing] couldn't build a dataset.
+#[derive(Error, Debug)]
+pub enum ListingError {
+    #[error(transparent)]
+    Spec(#[from] SpecParseError),
+    #[error("Unexpected zfs list output: {0}")]
+    Listing(#[from] ParseZfsError),
+    #[error("No snapshots of \"{dataset}\" in it, only of: {}.", listed.join(", "))]
+    NotListed { dataset: String, listed: Vec<String> },
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Comm {
     LEFT,
     BOTH,
@@ -116,6 +148,20 @@ pub enum Comm {
 }
 
 impl Dataset {
+    /// The dataset named `spec` (see [Dataset::from_str]), with its snapshots in `listing`, as
+    /// [parse_zfs_recursive] takes it; i.e. built from `zfs list` output saved earlier, with no ZFS
+    /// involved. The listing may be that of a whole hierarchy (`zfs list -r`), of which only the
+    /// snapshots of `spec` are kept; should it have none of them, only an empty listing will do.
+    pub fn with_snaps_from_listing(spec: &str, listing: &str) -> Result<Dataset, ListingError> {
+        let mut ds = Dataset::from_str(spec)?;
+        let mut listed = parse_zfs_recursive(listing)?;
+        ds.snaps = match listed.remove(ds.fullname()) {
+            Some(snaps) => snaps,
+            None if listed.is_empty() => Vec::new(),
+            None => return Err(ListingError::NotListed { dataset: ds.fullname().to_string(), listed: listed.into_keys().sorted().collect() }),
+        };
+        Ok(ds)
+    }
     pub fn fullname(&self) -> &str { &self.fullname }
     pub fn pool(&self) -> &str { &self.fullname[0..self.pool_idx] }
     pub fn relative(&self) -> &str {
@@ -126,11 +172,16 @@ impl Dataset {
     pub fn is_pool_root(&self) -> bool {
         self.fullname().find('/').is_none()
     }
+    /// The full name of the parent dataset, if any.
+    pub fn parent(&self) -> Option<&str> {
+        self.fullname().rsplit_once('/').map(|(parent, _)| parent)
+    }
 
     /// Walk two time-ordered vectors of snapshots.
     /// Return:
     ///   * A single vector containing a reference to each unique snapshot, tagged as it appears on the left side, the right side, or both sides.
     ///   * The index within the previous vector of the last snapshot encountered that is in both collections, if any.
+    ///
     /// The return vector has the same sort order as the input vectors.
     /// If the input is sorted date ascending (oldest snapshot first), then the second return value indexes the "most recent common snapshot".
     /// The input vectors are not checked for proper sort order, and the results are undefined if they are not properly sorted.
@@ -148,22 +199,36 @@ impl Dataset {
             if snaps_other.peek().is_none() { break (LEFT, &mut snaps_self) }
             let snap_self = *snaps_self.peek().unwrap();
             let snap_other = *snaps_other.peek().unwrap();
-            match snap_self.partial_cmp(&snap_other) {
-                Some(Less) => {
+            match snap_self.creation.cmp(&snap_other.creation) {
+                Less => {
                     retval.push((LEFT, snap_self));
                     snaps_self.next();
                 }
-                Some(Equal) => {
-                    retval2 = Some(retval.len());
-                    retval.push((BOTH, snap_self));
-                    snaps_self.next();
-                    snaps_other.next();
-                }
-                Some(Greater) => {
+                Greater => {
                     retval.push((RIGHT, snap_other));
                     snaps_other.next();
                 }
-                None => panic!("Found two snapshots which aren't comparable (i.e. l.guid != r.guid && l.creation == r.creation")
+                // Snapshots taken within the same second are in the order of their own pool on
+                // either side, which needn't be that of the other pool; so they're matched up by
+                // guid, in the order of self, followed by those only other has.
+                Equal => {
+                    let creation = snap_self.creation;
+                    let group_self = snaps_self.peeking_take_while(|s| s.creation == creation).collect::<Vec<_>>();
+                    let group_other = snaps_other.peeking_take_while(|s| s.creation == creation).collect::<Vec<_>>();
+                    for snap in &group_self {
+                        if group_other.iter().any(|s| s.guid == snap.guid) {
+                            retval2 = Some(retval.len());
+                            retval.push((BOTH, *snap));
+                        } else {
+                            retval.push((LEFT, *snap));
+                        }
+                    }
+                    for snap in group_other {
+                        if !group_self.iter().any(|s| s.guid == snap.guid) {
+                            retval.push((RIGHT, snap));
+                        }
+                    }
+                }
             }
         };
         for remaining in snaps_left {
@@ -172,7 +237,7 @@ impl Dataset {
         (retval, retval2)
     }
 
-    fn tag_snaps_for_deletion<F>(&self, f: F) -> Vec<(bool, &Snap)>
+    pub fn tag_snaps_for_deletion<F>(&self, f: F) -> Vec<(bool, &Snap)>
         where
             F: Fn(&Snap) -> bool,
     // A Snap tagged with "true" is interpreted as being TO KEEP
@@ -194,32 +259,40 @@ impl Dataset {
     }
 
     pub fn newest_snap(&self) -> &Snap {
-        &self.snaps.last().expect("This dataset contains no snapshots.")
+        self.snaps.last().expect("This dataset contains no snapshots.")
     }
 
     pub fn append_relative(&mut self, other: &Self) {
-        if other.relative() != "" {
+        self.append_path(other.relative());
+    }
+    /// Appends `path`, made of one or more name components, to the name; nothing if it's empty.
+    pub fn append_path(&mut self, path: &str) {
+        if !path.is_empty() {
             self.fullname.push('/');
-            self.fullname.push_str(other.relative());
+            self.fullname.push_str(path);
         }
     }
 }
 
-fn render_tagged_snaps_for_deletion(tagged_snaps: Vec<(bool, &Snap)>) -> String {
+pub fn render_tagged_snaps_for_deletion(tagged_snaps: Vec<(bool, &Snap)>) -> String {
     // Returns a string of the form "2021-07-12%2021-07-17,2021-07-19%..." suitable for feeding
     // into "zfs destroy pool/dataset@<output>".
+    // Held snapshots are left out even if tagged for deletion: zfs-destroy refuses to destroy them,
+    // failing the whole batch. Names are quoted one by one, where needed, so the line continuations
+    // in between still work.
     let mut groups : Vec<Vec<&Snap>> = Vec::new();
-    for (key, grouped_snap_iter) in &tagged_snaps.into_iter().group_by(|tup| tup.0) {
+    for (key, grouped_snap_iter) in &tagged_snaps.into_iter().group_by(|tup| tup.0 || tup.1.holds != 0) {
         if !key { groups.push(grouped_snap_iter.map(|tup| tup.1).collect()); }
     }
     groups
         .into_iter()
         .map(|group| {
+            let first = shell_quote_if_needed(&group[0].name);
             if group.len() == 1 {
-                group[0].name.clone()
+                first
             }
             else {
-                format!("{}%{}", group[0].name, group.last().unwrap().name)
+                format!("{}%{}", first, shell_quote_if_needed(&group.last().unwrap().name))
             }})
         .join(",\\\n")
 }
@@ -234,6 +307,17 @@ fn render_tagged_snaps_for_deletion(tagged_snaps: Vec<(bool, &Snap)>) -> String
 
 // parse_spec defined as a free function because it uses both Machine and Dataset.
 pub fn parse_spec(value: &str) -> Result<(Machine, Dataset), SpecParseError> {
+    // IPv6 addresses have colons of their own, hence go in brackets: "[2001:db8::1]:tank/data".
+    let host_start = value.find('@').map_or(0, |at_idx| at_idx + 1);
+    if value[host_start..].starts_with('[') {
+        let Some(bracket_idx) = value.find("]:") else {
+            return Err(SpecParseError::IllegalHostname(value.into()));
+        };
+        let dataset_spec = &value[bracket_idx+2..];
+        if dataset_spec.is_empty() { return Err(SpecParseError::ZeroLengthAfterColon(value.into())); }
+        return Ok((Machine::from_str(&value[..=bracket_idx])?, Dataset::from_str(dataset_spec)?));
+    }
+
     let first_colon = value.find(':');
     let first_slash = value.find('/');
 
@@ -249,25 +333,25 @@ pub fn parse_spec(value: &str) -> Result<(Machine, Dataset), SpecParseError> {
         Some(colon_idx) => &value[0..colon_idx],
     };
     let dataset_spec = match first_colon {
-        None => &value[..],
+        None => value,
         Some(colon_idx) => &value[colon_idx+1..]
     };
 
-    if dataset_spec.len() == 0 { return Err(SpecParseError::ZeroLengthAfterColon(value.into())); }
+    if dataset_spec.is_empty() { return Err(SpecParseError::ZeroLengthAfterColon(value.into())); }
     Ok((Machine::from_str(machine_spec)?, Dataset::from_str(dataset_spec)?))
 }
 
 #[test]
 fn test_parse_spec() {
     let (m, d) = parse_spec("tank").unwrap();
-    assert_eq!(m, Machine::Local);
+    assert_eq!(m.location, Location::Local);
     assert_eq!(d.fullname(), "tank");
     assert_eq!(d.relative(), "");
     assert_eq!(d.pool(), "tank");
 
     let (m, d) = parse_spec("baal:tank").unwrap();
-    match m {  // TODO What a weird (?) way to check for equality on Machine{host: "baal".into()}... ?
-        Machine::Remote {ref host } if host == "baal" => (),
+    match m.location {  // TODO What a weird (?) way to check for equality on Machine{host: "baal".into()}... ?
+        Location::Remote { user: None, ref host, port: None } if host == "baal" => (),
         _ => panic!("Machine wasn't constructed properly!"),
     }
     assert_eq!(d.fullname(), "tank");
@@ -275,7 +359,7 @@ fn test_parse_spec() {
     assert_eq!(d.pool(), "tank");
 
     let (m, d) = parse_spec(":tank").unwrap();
-    assert_eq!(m, Machine::Local);
+    assert_eq!(m.location, Location::Local);
     assert_eq!(d.fullname(), "tank");
     assert_eq!(d.relative(), "");
     assert_eq!(d.pool(), "tank");
@@ -287,19 +371,43 @@ fn test_parse_spec() {
     assert!(matches!(err, Err(SpecParseError::IllegalCharacters(_))));
 
     let (m, d) = parse_spec("server.company.tld:tank/a/path//to/a/relative/dataset").unwrap();
-    match m {  // TODO What a weird (?) way to check for equality on Machine{host: "baal".into()}... ?
-        Machine::Remote {ref host } if host == "server.company.tld" => (),
+    match m.location {  // TODO What a weird (?) way to check for equality on Machine{host: "baal".into()}... ?
+        Location::Remote { user: None, ref host, port: None } if host == "server.company.tld" => (),
         _ => panic!("Machine wasn't constructed properly!"),
     }
     assert_eq!(d.fullname(), "tank/a/path/to/a/relative/dataset");
     assert_eq!(d.relative(), "to/a/relative/dataset");
     assert_eq!(d.pool(), "tank");
 
+    let (m, d) = parse_spec("root@baal:tank/data").unwrap();
+    assert_eq!(m.location, Location::Remote { user: Some("root".into()), host: "baal".into(), port: None });
+    assert_eq!(m.to_string(), "root@baal");
+    assert_eq!(d.fullname(), "tank/data");
+
     let err = parse_spec("somehost:an_invâlid_pòól/somedataset");
     assert!(matches!(err, Err(SpecParseError::IllegalCharacters(_))));
 
     let err = parse_spec("somehost:but/trailing/slash/");
     assert!(matches!(err, Err(SpecParseError::IllegalSlashes(_))));
+
+    let (m, d) = parse_spec("[2001:db8::1]:tank/data").unwrap();
+    assert_eq!(m.location, Location::Remote { user: None, host: "2001:db8::1".into(), port: None });
+    assert_eq!(m.to_string(), "[2001:db8::1]");
+    assert_eq!(d.fullname(), "tank/data");
+
+    // The port comes from --ssh-port rather than the spec.
+    let (mut m, d) = parse_spec("root@[2001:db8::1]:tank//data").unwrap();
+    m.set_ssh_port(Some(2222));
+    assert_eq!(m.location, Location::Remote { user: Some("root".into()), host: "2001:db8::1".into(), port: Some(2222) });
+    assert_eq!(m.to_string(), "root@[2001:db8::1]");
+    assert_eq!(d.relative(), "data");
+
+    let err = parse_spec("[2001:db8::1]tank/data");
+    assert!(matches!(err, Err(SpecParseError::IllegalHostname(_))));
+    let err = parse_spec("[2001:db8::1]:");
+    assert!(matches!(err, Err(SpecParseError::ZeroLengthAfterColon(_))));
+    let err = parse_spec("[2001:db8::1:tank/data");
+    assert!(matches!(err, Err(SpecParseError::IllegalHostname(_))));
 }
 
 #[test]
@@ -310,6 +418,8 @@ fn test_append_relative() {
     assert_eq!(d1.relative(), "lxc/web-ng");
     assert_eq!(d2.fullname(), "zelda/lxc/web-ng");
     assert_eq!(d2.pool(), "zelda");
+    assert_eq!(d2.parent(), Some("zelda/lxc"));
+    assert_eq!(d1.parent(), Some("ganon/lxc"));
 
     let (_, d1) = parse_spec("tank/deluge").unwrap();
     let (_, mut d2) = parse_spec("baccu/deluge").unwrap();
@@ -317,12 +427,14 @@ fn test_append_relative() {
     assert_eq!(d1.relative(), "");
     assert_eq!(d2.fullname(), "baccu/deluge");
     assert_eq!(d2.pool(), "baccu");
+    let (_, d3) = parse_spec("baccu").unwrap();
+    assert_eq!(d3.parent(), None);
 }
 
 impl std::str::FromStr for Dataset {
     type Err = SpecParseError;
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        assert!(value.len() > 0, "Passed a zero-length string to Dataset::from_str!");
+        assert!(!value.is_empty(), "Passed a zero-length string to Dataset::from_str!");
         for char in value.chars() {
             if ! (char.is_ascii_alphanumeric() || char == '-' || char == '_' || char == '/') {
                 return Err(SpecParseError::IllegalCharacters(value.into()));
@@ -346,7 +458,7 @@ impl std::str::FromStr for Dataset {
         let pool_idx = fullname.find('/').unwrap_or(fullname.len());
         let relative_idx = doubleslash;
 
-        Ok(Dataset { fullname, snaps: Vec::new(), pool_idx, relative_idx })
+        Ok(Dataset { fullname, snaps: Vec::new(), bookmarks: Vec::new(), pool_idx, relative_idx })
     }
 }
 
@@ -360,11 +472,7 @@ impl std::fmt::Display for Dataset {
 
 #[cfg(test)]
 pub(crate) fn build_fake_dataset(spec: &str, snaps_output_literal: &str) -> Dataset {
-    let mut ds = Dataset::from_str(spec).unwrap();
-    let snaps = parse_zfs(snaps_output_literal);
-    ds.snaps = snaps;
-
-    ds
+    Dataset::with_snaps_from_listing(spec, snaps_output_literal).unwrap()
 }
 
 #[test]
@@ -382,6 +490,11 @@ fn test_render_tagged_snaps_for_deletion() {
     let res = render_tagged_snaps_for_deletion(tagged_snaps);
     println!("zfs destroy -v zelda/webdata@\\\n{}", res);
     assert_eq!(res, include_str!("dataset/tests/test_render_tagged_snaps_for_deletion.result"));
+
+    // Not even if tagged for deletion.
+    let tagged_snaps = zelda_webdata.tag_snaps_for_deletion(|_| false);
+    let res = render_tagged_snaps_for_deletion(tagged_snaps);
+    assert_eq!(res, "2020-12-06%2021-11-11BOGUS-NAME,\\\n2021-11-13%2021-11-14");
 }
 
 
@@ -416,7 +529,7 @@ fn test_mrcud() {
         include_str!("dataset/tests/zelda_webdata-divergence.list")
     );
     let baal_phone = build_fake_dataset(
-        "zelda/webdata",
+        "tank/phone",
         include_str!("dataset/tests/baal_tank_phone.list")
     );
     let none = find_mrcud(&tank_webdata, &baal_phone);
@@ -428,6 +541,7 @@ fn test_mrcud() {
     assert_eq!(res, include_str!("dataset/tests/test_last_common_or_divergence.result"));
 }
 
+#[cfg(test)]
 fn __basic_snap_retention_criteria(s: &Snap, when: DateTime<Utc>) -> bool {
     // A "true" veredict is interpreted as TO KEEP
 
@@ -442,11 +556,6 @@ fn __basic_snap_retention_criteria(s: &Snap, when: DateTime<Utc>) -> bool {
     chrono_decision || name_decision || holds_decision
 }
 
-fn basic_snap_retention_criteria(s: &Snap) -> bool {
-    let when = Utc::now();
-    __basic_snap_retention_criteria(s, when)
-}
-
 #[test]
 fn test_tag_snaps_for_deletion() {
     fn retention_criteria(s: &Snap) -> bool {
@@ -464,18 +573,30 @@ fn test_tag_snaps_for_deletion() {
 }
 
 
-/// See the documentation in [the PartialOrd implementation](Snap::PartialOrd)
+/// See the documentation in [the Ord implementation](Snap::Ord)
 #[derive(Debug, Clone)]
 pub struct Snap {
     pub guid: u64,
     pub name: String,  // Only the snapshot name; i.e. to the right of '@'.
     pub creation: DateTime<Utc>,
     pub holds: u32,
+    /// Bytes held only by this snapshot; i.e. what destroying it alone would free.
+    pub used: u64,
+    /// Bytes of data accessible through this snapshot, shared or not.
+    pub referenced: u64,
+    /// Bytes written to the dataset between the previous snapshot and this one.
+    pub written: u64,
+    /// The transaction group in which the snapshot was taken. Only meaningful within a pool, where
+    /// it orders snapshots more finely than `creation`.
+    pub createtxg: u64,
 }
 
 impl Default for Snap {
     fn default() -> Self {
-        Snap {guid: u64::default(), name: String::default(), creation: Utc::now(), holds: u32::default() }
+        Snap {
+            guid: u64::default(), name: String::default(), creation: Utc::now(), holds: u32::default(),
+            used: u64::default(), referenced: u64::default(), written: u64::default(), createtxg: u64::default(),
+        }
     }
 }
 
@@ -487,18 +608,24 @@ impl PartialEq for Snap {
 
 impl Eq for Snap { }
 
-/// For simplicity's sake, the algorithms that work on snapshots assume that no two different snapshots are ever taken on the exact same instant.
-/// This PartialOrd implementation reflects that: in the unlikely case that the snapshots are not the same (l.guid != r.guid) but the creation times are equal (l.creation == r.creation), the ordering is undefined.
-impl PartialOrd for Snap {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+/// Snapshots are ordered by creation time. As `zfs list` only has 1-second granularity, two
+/// different snapshots (l.guid != r.guid) may well have the same creation time; such ties are broken
+/// by guid, which is arbitrary but the same on every instance of the dataset, on every pool.
+/// Not by createtxg, which only means something within one pool: the snapshots of one dataset are
+/// put in their actual order by [crate::machine::parse_zfs] instead, and [Dataset::comm] keeps to it.
+impl Ord for Snap {
+    fn cmp(&self, other: &Self) -> Ordering {
         if self == other {
-            return Some(Equal);
-        }
-        return match self.creation.cmp(&other.creation) {
-            Less => Some(Less),
-            Greater => Some(Greater),
-            Equal => None,
+            return Equal;
         }
+        return self.creation.cmp(&other.creation)
+            .then(self.guid.cmp(&other.guid));
+    }
+}
+
+impl PartialOrd for Snap {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -508,6 +635,65 @@ impl std::fmt::Display for Snap {
     }
 }
 
+#[test]
+fn test_comm_same_second() {
+    let creation = "2021-11-14T02:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let snap = |name: &str, guid, creation| Snap { name: name.to_string(), guid, creation, ..Snap::default() };
+    let (before, a, b, after) = (
+        snap("before", 1, creation - chrono::Duration::seconds(1)),
+        snap("a", 10, creation),
+        snap("b", 20, creation),
+        snap("after", 2, creation + chrono::Duration::seconds(1)),
+    );
+    assert_eq!(a.cmp(&b), Less);
+    assert_eq!(b.cmp(&a), Greater);
+    assert_ne!(a, b);
+    // Across pools, createtxg means nothing.
+    let (a_txg, b_txg) = (Snap { createtxg: 1001, ..a.clone() }, Snap { createtxg: 1000, ..b.clone() });
+    assert_eq!(a_txg.cmp(&b_txg), Less);
+    assert!(before < a && b < after);
+
+    let mut left = Dataset::from_str("tank/webdata").unwrap();
+    let mut right = Dataset::from_str("zelda/webdata").unwrap();
+    left.snaps = vec![before.clone(), a.clone(), b.clone(), after.clone()];
+    right.snaps = vec![before.clone(), a.clone(), b.clone()];
+    let (comm, idx) = left.comm(&right);
+    let comm = comm.iter().map(|(side, s)| (*side, s.name.as_str())).collect::<Vec<_>>();
+    assert_eq!(comm, vec![(BOTH, "before"), (BOTH, "a"), (BOTH, "b"), (LEFT, "after")]);
+    assert_eq!(idx, Some(2));
+
+    right.snaps = vec![before.clone(), b.clone()];
+    let (comm, idx) = left.comm(&right);
+    let comm = comm.iter().map(|(side, s)| (*side, s.name.as_str())).collect::<Vec<_>>();
+    assert_eq!(comm, vec![(BOTH, "before"), (LEFT, "a"), (BOTH, "b"), (LEFT, "after")]);
+    assert_eq!(idx, Some(2));
+}
+
+#[test]
+fn test_comm_same_second_other_pool() {
+    // Within the source's pool, a was taken before b, in the same second; their guids go the
+    // other way. b was received into the destination's pool at a txg of its own.
+    let creation = "2021-11-14T02:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let snap = |name: &str, guid, createtxg| Snap { name: name.to_string(), guid, creation, createtxg, ..Snap::default() };
+    let mut left = Dataset::from_str("tank/webdata").unwrap();
+    let mut right = Dataset::from_str("zelda/webdata").unwrap();
+    left.snaps = vec![snap("a", 20, 100), snap("b", 10, 101)];
+    right.snaps = vec![snap("b", 10, 50)];
+    let (comm, idx) = left.comm(&right);
+    let comm = comm.iter().map(|(side, s)| (*side, s.name.as_str())).collect::<Vec<_>>();
+    assert_eq!(comm, vec![(LEFT, "a"), (BOTH, "b")]);
+    assert_eq!(idx, Some(1));
+    assert!(matches!(find_mrcud(&left, &right), UpToDate(mrc) if mrc.name == "b"));
+    // The other way around, too.
+    let (comm, idx) = right.comm(&left);
+    let comm = comm.iter().map(|(side, s)| (*side, s.name.as_str())).collect::<Vec<_>>();
+    assert_eq!(comm, vec![(BOTH, "b"), (RIGHT, "a")]);
+    assert_eq!(idx, Some(0));
+
+    right.snaps = vec![snap("b", 10, 50), snap("c", 30, 51)];
+    assert!(matches!(find_mrcud(&left, &right), DestinationHasMore(mrc) if mrc.name == "b"));
+}
+
 #[test]
 fn snap_eq() {
     let mut s1 = Snap::default();
@@ -521,3 +707,37 @@ fn snap_eq() {
     assert_eq!(s1, s2);
 }
 
+
+#[test]
+fn test_find_bookmark_base() {
+    let mut tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    tank_webdata.bookmarks = crate::machine::parse_zfs_bookmarks(include_str!("dataset/tests/tank_webdata-bookmarks.list")).unwrap();
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-until-2021-08-29.list")
+    );
+    assert!(matches!(find_mrcud(&tank_webdata, &zelda_webdata), NoneInCommon));
+    // 2021-09-02 is newer, but zelda/webdata doesn't have it.
+    assert_eq!(find_bookmark_base(&tank_webdata, &zelda_webdata).unwrap().name, "2021-08-29");
+    tank_webdata.bookmarks.clear();
+    assert!(find_bookmark_base(&tank_webdata, &zelda_webdata).is_none());
+}
+
+#[test]
+fn test_with_snaps_from_listing() {
+    let ds = Dataset::with_snaps_from_listing("tank/phone", include_str!("dataset/tests/baal_tank_phone.list")).unwrap();
+    assert_eq!(ds.fullname(), "tank/phone");
+    assert_eq!(ds.newest_snap().name, "2021-07-19");
+    assert!(Dataset::with_snaps_from_listing("tank/empty", "").unwrap().snaps.is_empty());
+    assert!(matches!(Dataset::with_snaps_from_listing("tank/phone/", ""), Err(ListingError::Spec(_))));
+    assert!(matches!(Dataset::with_snaps_from_listing("tank/phone", "tank/phone@a\t1\n"), Err(ListingError::Listing(_))));
+    // Out of a whole hierarchy.
+    let listing = include_str!("dataset/tests/tank_lxc-recursive.list");
+    let ds = Dataset::with_snaps_from_listing("tank/lxc/web", listing).unwrap();
+    assert_eq!(ds.newest_snap().name, "before-upgrade");
+    let err = Dataset::with_snaps_from_listing("tank/lxc/mail", listing).unwrap_err();
+    assert_eq!(err.to_string(), r#"No snapshots of "tank/lxc/mail" in it, only of: tank/lxc, tank/lxc/db, tank/lxc/web."#);
+}
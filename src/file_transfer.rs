@@ -0,0 +1,106 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::thread;
+use anyhow::{bail, Context};
+use indicatif::HumanBytes;
+
+use crate::dataset::Dataset;
+use crate::machine::{Machine, RecvFlags, SendFlags};
+use crate::progressbar::{ProgressFormat, do_progressbar_from_zfs_send_stderr, drain_zfs_send_stderr, emit_progress_json_from_zfs_send_stderr};
+
+#[derive(Clone, Debug)]
+pub struct SendToFileOpts {
+    /// The snapshot to send incrementally from, i.e. the newest one the destination has. If None,
+    /// the newest snapshot is sent in full.
+    pub from: Option<String>,
+    pub simple_incremental: bool,
+    pub send_flags: SendFlags,
+    pub progress: ProgressFormat,
+}
+
+/// Writes a `zfs send` stream of `ds`, up to its newest snapshot, to `path` (standard output if
+/// "-"); for [recv_from_file_cli] to receive wherever the file ends up.
+pub fn send_to_file_cli(machine: &Machine, ds: &mut Dataset, path: &str, opts: SendToFileOpts) -> Result<String, anyhow::Error> {
+    machine.get_snaps(ds).context(format!(r#"Unable to get snapshots for "{machine}:{ds}"."#))?;
+    if ds.snaps.is_empty() {
+        bail!(r#"Dataset "{machine}:{ds}" has no snapshots to send."#);
+    }
+    let mut send_cmd = match &opts.from {
+        Some(from) => {
+            let Some(base) = ds.snaps.iter().find(|s| &s.name == from) else {
+                bail!(r#"Dataset "{machine}:{ds}" has no snapshot "{from}" to send incrementally from."#);
+            };
+            if base == ds.newest_snap() {
+                bail!(r#"Nothing to send: "{from}" is the newest snapshot of "{machine}:{ds}"."#);
+            }
+            machine.send_from_s_till_newest(ds, base, opts.simple_incremental, &opts.send_flags)
+        }
+        None => machine.fullsend_s(ds, ds.newest_snap(), &opts.send_flags),
+    };
+    let mut output = open_output(path)?;
+
+    let mut send_process = send_cmd.spawn().context("Failed to spawn zfs send.")?;
+    let mut stream = send_process.stdout.take().unwrap();
+    let writer = thread::spawn(move || -> io::Result<u64> {
+        let written = io::copy(&mut stream, &mut output)?;
+        output.flush()?;
+        Ok(written)
+    });
+    let send_stderr = send_process.stderr.take().unwrap();
+    match opts.progress {
+        ProgressFormat::Bars => do_progressbar_from_zfs_send_stderr(send_stderr, None, None),
+        ProgressFormat::Quiet => drain_zfs_send_stderr(send_stderr),
+        ProgressFormat::Json => emit_progress_json_from_zfs_send_stderr(send_stderr),
+    };
+    let written = writer.join().unwrap().context(format!("Failed to write the stream to {path}."))?;
+    let status = send_process.wait()?;
+    if !status.success() {
+        bail!("zfs send failed: {status}");
+    }
+    Ok(format!(r#"Wrote the stream of "{machine}:{ds}" up to "{}" ({}) to {path}."#, ds.newest_snap(), HumanBytes(written)))
+}
+
+/// Opens `path` to write a stream to: standard output if "-", a named pipe (or other special file)
+/// as is, otherwise a new file. Existing regular files are never overwritten.
+fn open_output(path: &str) -> Result<Box<dyn Write + Send>, anyhow::Error> {
+    if path == "-" {
+        return Ok(Box::new(io::stdout()));
+    }
+    let special = std::fs::metadata(path).is_ok_and(|m| !m.is_file());
+    let file = match special {
+        true => OpenOptions::new().write(true).open(path),
+        false => OpenOptions::new().write(true).create_new(true).open(path),
+    };
+    Ok(Box::new(file.context(format!("Unable to open {path} for writing the stream to."))?))
+}
+
+/// Receives into `ds` the stream in `path` (standard input if "-"), as written by [send_to_file_cli].
+pub fn recv_from_file_cli(machine: &Machine, ds: &mut Dataset, path: &str, rollback: bool) -> Result<String, anyhow::Error> {
+    let mut recv_cmd = machine.recv(ds, &RecvFlags { rollback, ..RecvFlags::default() }, &[]);
+    if path != "-" {
+        let file = File::open(path).context(format!("Unable to open {path} to receive the stream from."))?;
+        recv_cmd.stdin(file);
+    } else {
+        recv_cmd.stdin(std::process::Stdio::inherit());
+    }
+    let output = recv_cmd.output().context("Failed to spawn zfs recv.")?;
+    if !output.status.success() {
+        bail!(r#"zfs recv into "{machine}:{ds}" failed: {}
+{}"#, output.status, String::from_utf8_lossy(&output.stderr).trim_end());
+    }
+    machine.get_snaps(ds).context(format!(r#"Unable to get snapshots for "{machine}:{ds}"."#))?;
+    Ok(format!(r#"Received {path} into "{machine}:{ds}", whose newest snapshot is now "{}"."#, ds.newest_snap()))
+}
+
+#[test]
+fn test_open_output() {
+    let path = std::env::temp_dir().join(format!("zfs-rs-test-open-output-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    open_output(path).unwrap().write_all(b"stream").unwrap();
+    // A stream file already there is never clobbered.
+    assert!(open_output(path).is_err());
+    assert_eq!(std::fs::read(path).unwrap(), b"stream");
+    std::fs::remove_file(path).unwrap();
+    // Special files are written to as they are.
+    assert!(open_output("/dev/null").is_ok());
+}
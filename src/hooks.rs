@@ -0,0 +1,56 @@
+use std::process::{Command, Stdio};
+use anyhow::{bail, Context};
+use crate::machine::command_without_shell;
+
+/// The command to run `hook` with: through `sh -c` if it has any shell metacharacters in it
+/// (e.g. a pipeline, or quoted arguments); otherwise directly, split on whitespace, so that a
+/// plain `curl -fsS https://...` needs no shell to run.
+fn hook_command(hook: &str) -> Command {
+    command_without_shell(hook).unwrap_or_else(|| {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(hook);
+        cmd
+    })
+}
+
+/// Runs `hook` on the local machine, with `env` added to its environment, and waits for it. Its
+/// output goes to stderr, so as not to mix with ours (e.g. --output json) on stdout. Fails if it
+/// can't be run, or exits unsuccessfully.
+pub fn run_hook(hook: &str, env: &[(&str, String)]) -> Result<(), anyhow::Error> {
+    if hook.trim().is_empty() {
+        bail!("Empty hook command.");
+    }
+    log::info!("Running hook: {hook}");
+    let status = hook_command(hook)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(std::io::stderr())
+        .status()
+        .context(format!("Failed to run hook {hook:?}."))?;
+    if !status.success() {
+        bail!("Hook {hook:?} failed: {status}.");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_hook_command() {
+    let cmd = hook_command("curl -fsS  https://hc-ping.com/abc");
+    assert_eq!(cmd.get_program(), "curl");
+    assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["-fsS", "https://hc-ping.com/abc"]);
+    let cmd = hook_command("echo $ZFS_RS_STATUS | mail -s zfs-rs root");
+    assert_eq!(cmd.get_program(), "sh");
+    assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["-c", "echo $ZFS_RS_STATUS | mail -s zfs-rs root"]);
+}
+
+#[test]
+fn test_run_hook() {
+    let out = std::env::temp_dir().join(format!("zfs-rs-test-hook-{}", std::process::id()));
+    let hook = format!("echo \"$ZFS_RS_STATUS $ZFS_RS_BYTES\" > {}", out.display());
+    run_hook(&hook, &[("ZFS_RS_STATUS", "synchronized".to_string()), ("ZFS_RS_BYTES", "1024".to_string())]).unwrap();
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "synchronized 1024\n");
+    std::fs::remove_file(&out).unwrap();
+    assert!(run_hook("false", &[]).is_err());
+    assert!(run_hook("/nonexistent/hook", &[]).is_err());
+    assert!(run_hook(" ", &[]).is_err());
+}
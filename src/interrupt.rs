@@ -0,0 +1,76 @@
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Set once SIGINT or SIGTERM has been received.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The process groups of the zfs-send | zfs-recv pipelines underway. Each pipeline runs in a
+/// process group of its own, so that it can be stopped as a whole; see [register_pipeline].
+static PIPELINE_GROUPS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Handles SIGINT (e.g. Ctrl-C) and SIGTERM: the pipelines underway, if any, are sent SIGTERM, and
+/// their failure is then reported as usual (see [interrupted]); should they still be around 10
+/// seconds later, zfs-rs exits anyway. Otherwise, or upon a second signal, it exits right away.
+pub fn install_handler() {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            exit(130);
+        }
+        if !terminate_pipelines() {
+            eprintln!("Interrupted.");
+            exit(130);
+        }
+        eprintln!("Interrupted; stopping the transfer.");
+        // Should the pipeline not go away, don't wait on it forever. Not from here, though: the
+        // handler has to return for the next signal to be handled.
+        thread::spawn(|| {
+            thread::sleep(Duration::from_secs(10));
+            // Once it's gone, the main thread has its own wrapping up to do (e.g. the hint on
+            // resuming), and is left to it.
+            if PIPELINE_GROUPS.lock().unwrap().is_empty() {
+                return;
+            }
+            eprintln!("The transfer didn't stop within 10 seconds; exiting anyway.");
+            exit(130);
+        });
+    }).expect("Unable to install the handler for SIGINT/SIGTERM.");
+}
+
+/// Whether zfs-rs has been interrupted; the failure of a pipeline is then down to that.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Registers the process group `pgid` of a pipeline, to be terminated if zfs-rs is interrupted.
+pub fn register_pipeline(pgid: u32) {
+    PIPELINE_GROUPS.lock().unwrap().push(pgid);
+}
+
+/// Undoes [register_pipeline], once the pipeline is done with.
+pub fn unregister_pipeline(pgid: u32) {
+    PIPELINE_GROUPS.lock().unwrap().retain(|&group| group != pgid);
+}
+
+/// Sends SIGTERM to every registered pipeline. Returns whether there was any.
+fn terminate_pipelines() -> bool {
+    let groups = PIPELINE_GROUPS.lock().unwrap();
+    for &pgid in groups.iter() {
+        // SAFETY: kill(2) has no memory-safety preconditions.
+        unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGTERM); }
+    }
+    !groups.is_empty()
+}
+
+#[test]
+fn test_terminate_pipelines() {
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    let mut child = std::process::Command::new("sh").args(["-c", "sleep 30 | cat"]).process_group(0).spawn().unwrap();
+    register_pipeline(child.id());
+    assert!(terminate_pipelines());
+    unregister_pipeline(child.id());
+    assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+    assert!(!PIPELINE_GROUPS.lock().unwrap().contains(&child.id()));
+}
@@ -0,0 +1,115 @@
+use anyhow::Context;
+use itertools::Itertools;
+use serde_json::json;
+use crate::comm::{DateWindow, OutputFormat, SortBy};
+use crate::dataset::{Dataset, Snap};
+use crate::machine::Machine;
+
+#[derive(Default)]
+pub struct ListOpts {
+    pub order_asc: bool,
+    pub format: OutputFormat,
+    pub sort_by: SortBy,
+    /// List the snapshots of the descendants too, up to this many levels below.
+    pub depth: Option<u32>,
+    /// Only list the snapshots taken within this window.
+    pub window: DateWindow,
+}
+
+pub fn list_cli(machine: Machine, mut ds: Dataset, opts: ListOpts) -> Result<String, anyhow::Error> {
+    if let Some(depth) = opts.depth {
+        return list_hierarchy_cli(machine, ds, depth, opts);
+    }
+    machine.get_snaps(&mut ds).context(format!(r#"Unable to get snapshots for "{machine}:{ds}"."#))?;
+    let snaps = sorted(&ds.snaps, &opts);
+    return Ok(match opts.format {
+        OutputFormat::Text => render_table(&snaps),
+        OutputFormat::Json => render_json(&snaps).to_string(),
+    });
+}
+
+/// Like [list_cli], for `ds` along with its descendants up to `depth` levels below, one table per
+/// dataset under a "==> dataset <==" line; in JSON, an array of {dataset, snapshots} objects.
+/// Datasets without snapshots are left out.
+fn list_hierarchy_cli(machine: Machine, ds: Dataset, depth: u32, opts: ListOpts) -> Result<String, anyhow::Error> {
+    let listed = machine.get_snaps_recursive(&ds, Some(depth)).context(format!(r#"Unable to get snapshots for "{machine}:{ds}" and its descendants."#))?;
+    let listed = listed.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)).collect::<Vec<_>>();
+    return Ok(match opts.format {
+        OutputFormat::Text => listed.iter()
+            .map(|(name, snaps)| format!("==> {machine}:{name} <==\n{}", render_table(&sorted(snaps, &opts))))
+            .join("\n\n"),
+        OutputFormat::Json => serde_json::Value::Array(listed.iter()
+            .map(|(name, snaps)| json!({
+                "dataset": name,
+                "snapshots": render_json(&sorted(snaps, &opts)),
+            }))
+            .collect()
+        ).to_string(),
+    });
+}
+
+/// The snapshots to list, in the order to list them.
+fn sorted<'a>(snaps: &'a [Snap], opts: &ListOpts) -> Vec<&'a Snap> {
+    let mut snaps = snaps.iter().filter(|s| opts.window.contains(s)).collect::<Vec<_>>();
+    opts.sort_by.sort(&mut snaps, |snap| snap);
+    if !opts.order_asc {
+        snaps.reverse();
+    }
+    snaps
+}
+
+/// One line per snapshot, under a header, with the columns aligned.
+fn render_table(snaps: &[&Snap]) -> String {
+    let name_width = snaps.iter().map(|s| s.name.len()).max().unwrap_or(0).max("NAME".len());
+    let mut lines = vec![format!("{:<name_width$}  {:<25}  {:<20}  HOLDS", "NAME", "CREATION", "GUID")];
+    for snap in snaps {
+        lines.push(format!("{:<name_width$}  {:<25}  {:<20}  {}", snap.name, snap.creation.to_rfc3339(), snap.guid, snap.holds));
+    }
+    lines.join("\n")
+}
+
+fn render_json(snaps: &[&Snap]) -> serde_json::Value {
+    let entries = snaps.iter()
+        .map(|snap| json!({
+            "name": snap.name,
+            "creation": snap.creation.to_rfc3339(),
+            "guid": snap.guid,
+            "holds": snap.holds,
+        }))
+        .collect::<Vec<_>>();
+    serde_json::Value::Array(entries)
+}
+
+#[test]
+fn test_render_table() {
+    use crate::dataset::build_fake_dataset;
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-holds-and-weird-name.list")
+    );
+    let snaps = zelda_webdata.snaps.iter().collect::<Vec<_>>();
+    let table = render_table(&snaps);
+    let lines = table.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), snaps.len() + 1);
+    assert!(lines[0].starts_with("NAME"));
+    assert!(lines[0].ends_with("HOLDS"));
+    // Every column starts at the same offset on every line.
+    let creation_col = lines[0].find("CREATION").unwrap();
+    assert!(lines[1..].iter().all(|l| l[creation_col..].starts_with("20")));
+    assert!(snaps.iter().any(|s| s.holds > 0));
+}
+
+#[test]
+fn test_render_json() {
+    use crate::dataset::build_fake_dataset;
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-holds-and-weird-name.list")
+    );
+    let snaps = zelda_webdata.snaps.iter().collect::<Vec<_>>();
+    let res = render_json(&snaps);
+    assert_eq!(res.as_array().unwrap().len(), snaps.len());
+    assert_eq!(res[0]["name"], snaps[0].name);
+    assert_eq!(res[0]["guid"], snaps[0].guid);
+    assert_eq!(res[0]["holds"], snaps[0].holds);
+}
@@ -1,4 +1,6 @@
 #![deny(unused_must_use)]
+// Explicit returns are this codebase's style, tail position included.
+#![allow(clippy::needless_return)]
 // #![allow(unused_imports)]  // TODO: REMOVE WITH FINAL PRODUCTION CODE!
 
 mod dataset;
@@ -8,11 +10,29 @@ mod retention;
 mod progressbar;
 mod cutting_floor;
 mod comm;
+mod abort_resume;
+mod confirm;
+mod config;
+mod file_transfer;
+mod hooks;
+mod interrupt;
+mod list;
+mod plan;
+mod rate;
 
+use std::io::IsTerminal;
 use std::process::exit;
-use clap::{Command, Arg, ArgAction};
-use crate::comm::CommOpts;
-use crate::dataset::{parse_spec};
+use std::time::Duration;
+use anyhow::Context;
+use chrono::NaiveTime;
+use clap::{Command, Arg, ArgAction, ArgMatches};
+use regex::Regex;
+use crate::comm::{CommOpts, DateWindow, OutputFormat, SortBy};
+use crate::dataset::{parse_spec, Comm};
+use crate::list::ListOpts;
+use crate::machine::{Machine, RecvPathRemap, SendFlags, is_valid_property_name};
+use crate::progressbar::ProgressFormat;
+use crate::rate::parse_rate;
 use crate::replicate::{*};
 use crate::retention::{*};
 
@@ -32,53 +52,325 @@ fn get_n_random_chars(n: usize) -> String {
     chars
 }
 
-fn verify_pv_rate(rate: &str) -> Result<(),()> {
-    let suffixes = "KGMT";
-    let num;
-    let last = rate.chars().last().ok_or(())?;
-    if last.is_numeric() {
-        num = rate;
-    } else {
-        if suffixes.contains(last) == false {
-            return Err(());
+/// One range of --ratelimit-schedule: from, until, and the rate in between (None: unlimited).
+type RateScheduleEntry = (NaiveTime, NaiveTime, Option<String>);
+
+fn parse_ratelimit_schedule(s: &str) -> Result<Vec<RateScheduleEntry>, String> {
+    let parse_time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| format!("{t} isn't a time of the form HH:MM."));
+    s.split(',')
+        .map(|entry| {
+            let (range, rate) = entry.split_once('=').ok_or_else(|| format!("{entry} isn't of the form HH:MM-HH:MM=RATE."))?;
+            let (from, until) = range.split_once('-').ok_or_else(|| format!("{entry} isn't of the form HH:MM-HH:MM=RATE."))?;
+            let rate = match rate {
+                "0" => None,
+                _ => {
+                    parse_rate(rate).map_err(|err| format!("invalid rate limit {err} Hint: use something like `50M`."))?;
+                    Some(rate.to_owned())
+                }
+            };
+            Ok((parse_time(from)?, parse_time(until)?, rate))
+        })
+        .collect()
+}
+
+/// The rate of the first range of `schedule` which `now` falls in. Ranges may wrap around midnight.
+fn scheduled_ratelimit(schedule: &[RateScheduleEntry], now: NaiveTime) -> Option<String> {
+    schedule.iter()
+        .find(|(from, until, _)| match from <= until {
+            true => *from <= now && now < *until,
+            false => *from <= now || now < *until,
+        })
+        .and_then(|(_, _, rate)| rate.clone())
+}
+
+#[test]
+fn test_scheduled_ratelimit() {
+    let at = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+    let schedule = parse_ratelimit_schedule("08:00-18:00=10M,18:00-08:00=0").unwrap();
+    assert_eq!(scheduled_ratelimit(&schedule, at("08:00")), Some("10M".to_string()));
+    assert_eq!(scheduled_ratelimit(&schedule, at("17:59")), Some("10M".to_string()));
+    assert_eq!(scheduled_ratelimit(&schedule, at("18:00")), None);
+    assert_eq!(scheduled_ratelimit(&schedule, at("03:00")), None);
+    let schedule = parse_ratelimit_schedule("22:00-06:00=50M").unwrap();
+    assert_eq!(scheduled_ratelimit(&schedule, at("23:30")), Some("50M".to_string()));
+    assert_eq!(scheduled_ratelimit(&schedule, at("05:59")), Some("50M".to_string()));
+    assert_eq!(scheduled_ratelimit(&schedule, at("12:00")), None);
+    assert!(parse_ratelimit_schedule("08:00-18:00=10j").is_err());
+    assert!(parse_ratelimit_schedule("8am-6pm=10M").is_err());
+    assert!(parse_ratelimit_schedule("08:00-18:00").is_err());
+}
+
+fn src_dst_zfs_binary_args() -> [Arg; 2] {
+    [
+        Arg::new("src-zfs-binary")
+            .help("Path to the zfs binary on the source machine. Overrides --zfs-binary.")
+            .long("src-zfs-binary")
+            .value_name("PATH"),
+        Arg::new("dst-zfs-binary")
+            .help("Path to the zfs binary on the destination machine. Overrides --zfs-binary.")
+            .long("dst-zfs-binary")
+            .value_name("PATH"),
+    ]
+}
+
+/// Applies the options from [src_dst_zfs_binary_args], on top of [configure_machine].
+fn configure_src_dst_zfs_binary(src_machine: &mut Machine, dst_machine: &mut Machine, matches: &ArgMatches) {
+    if let Some(zfs_path) = matches.get_one::<String>("src-zfs-binary") {
+        src_machine.zfs_path = zfs_path.clone();
+    }
+    if let Some(zfs_path) = matches.get_one::<String>("dst-zfs-binary") {
+        dst_machine.zfs_path = zfs_path.clone();
+    }
+}
+
+/// Parses the file of --datasets-from into (source, destination) pairs of dataset specs.
+fn parse_datasets_file(content: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let mut pairs = Vec::new();
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-        let mut t = rate.chars();
-        t.next_back();
-        num = t.as_str();
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let [source, destination] = fields[..] else {
+            anyhow::bail!("Line {} of --datasets-from isn't of the form \"<source> <destination>\": {line}", idx + 1);
+        };
+        pairs.push((source.to_owned(), destination.to_owned()));
     }
-    let parseable = num.parse::<u64>();
-    return match parseable {
-        Ok(_) => Ok(()),
-        Err(_) => Err(())
+    if pairs.is_empty() {
+        anyhow::bail!("No datasets listed in --datasets-from.");
     }
+    Ok(pairs)
 }
 
 #[test]
-fn test_verify_pv_rate() {
-    assert_eq!(verify_pv_rate("1234M"), Ok(()));
-    assert_eq!(verify_pv_rate("1234j"), Err(()));
-    assert_eq!(verify_pv_rate("-1234M"), Err(()));
-    assert_eq!(verify_pv_rate("50M"), Ok(()));
-    assert_eq!(verify_pv_rate("50"), Ok(()));
+fn test_parse_datasets_file() {
+    let pairs = parse_datasets_file("# Nightly\ntank/webdata bk01:backup/webdata\n\n  tank//mail\tbk01:backup  \n").unwrap();
+    assert_eq!(pairs, vec![
+        ("tank/webdata".to_string(), "bk01:backup/webdata".to_string()),
+        ("tank//mail".to_string(), "bk01:backup".to_string()),
+    ]);
+    assert!(parse_datasets_file("tank/webdata\n").is_err());
+    assert!(parse_datasets_file("tank/webdata bk01:backup/webdata extra\n").is_err());
+    assert!(parse_datasets_file("# Nothing yet\n").is_err());
 }
 
-fn main() {
-    let replicate = Command::new("replicate")
+fn parse_set_property(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s.split_once('=').ok_or_else(|| format!("{s} isn't of the form NAME=VALUE."))?;
+    let name = parse_inherit_property(name)?;
+    Ok((name, value.to_owned()))
+}
+
+fn parse_inherit_property(s: &str) -> Result<String, String> {
+    if !is_valid_property_name(s) {
+        return Err(format!("{s} isn't a valid ZFS property name."));
+    }
+    Ok(s.to_owned())
+}
+
+#[test]
+fn test_parse_set_property() {
+    assert_eq!(parse_set_property("mountpoint=none"), Ok(("mountpoint".to_string(), "none".to_string())));
+    assert_eq!(parse_set_property("com.example:note=a=b"), Ok(("com.example:note".to_string(), "a=b".to_string())));
+    assert!(parse_set_property("mountpoint").is_err());
+    assert!(parse_set_property("$(reboot)=x").is_err());
+}
+
+/// Renders --snap-name-template at `now`, through chrono's strftime-like format.
+fn render_snap_name_template<Tz: chrono::TimeZone>(template: &str, now: &chrono::DateTime<Tz>) -> Result<String, anyhow::Error>
+    where Tz::Offset: std::fmt::Display
+{
+    use std::fmt::Write;
+    let mut name = String::new();
+    write!(name, "{}", now.format(template)).map_err(|_| anyhow::anyhow!("{template} isn't a valid template for --snap-name-template."))?;
+    // Same as what Dataset::from_str accepts, minus the slashes.
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        anyhow::bail!("--snap-name-template {template} renders to {name:?}; snapshot names may only contain ASCII alphanumerics, dashes and underscores.");
+    }
+    Ok(name)
+}
+
+#[test]
+fn test_render_snap_name_template() {
+    use chrono::TimeZone;
+    let now = chrono::Utc.with_ymd_and_hms(2021, 8, 30, 2, 0, 7).unwrap();
+    assert_eq!(render_snap_name_template("auto-%Y-%m-%d_%H%M%S", &now).unwrap(), "auto-2021-08-30_020007");
+    assert_eq!(render_snap_name_template("nightly", &now).unwrap(), "nightly");
+    assert!(render_snap_name_template("auto-%F %T", &now).is_err());
+    assert!(render_snap_name_template("auto-%Q", &now).is_err());
+    assert!(render_snap_name_template("", &now).is_err());
+}
+
+fn parse_date_format(s: &str) -> Result<String, String> {
+    use std::fmt::Write;
+    let mut probe = String::new();
+    write!(probe, "{}", chrono::Utc::now().format(s)).map_err(|_| format!("{s} isn't a valid date format."))?;
+    Ok(s.to_owned())
+}
+
+fn parse_reference_time(s: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&chrono::Utc))
+        .map_err(|err| format!("{s} isn't an RFC 3339 timestamp, like 2021-11-14T00:00:00Z: {err}."))
+}
+
+#[test]
+fn test_parse_reference_time() {
+    assert_eq!(parse_reference_time("2021-11-14T02:00:00+02:00").unwrap().to_rfc3339(), "2021-11-14T00:00:00+00:00");
+    assert!(parse_reference_time("2021-11-14").is_err());
+    assert!(parse_reference_time("next sunday").is_err());
+}
+
+/// Parses --since and --until for comm and list: an RFC 3339 timestamp, or a date (YYYY-MM-DD, in
+/// UTC), which --until takes to include the whole day.
+fn parse_date_bound(s: &str, end_of_day: bool) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        let time = match end_of_day {
+            true => chrono::NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+            false => chrono::NaiveTime::MIN,
+        };
+        return Ok(date.and_time(time).and_utc());
+    }
+    parse_reference_time(s).map_err(|_| format!("{s} is neither a date like 2021-11-14 nor an RFC 3339 timestamp like 2021-11-14T00:00:00Z."))
+}
+
+#[test]
+fn test_parse_date_bound() {
+    assert_eq!(parse_date_bound("2021-11-14", false).unwrap().to_rfc3339(), "2021-11-14T00:00:00+00:00");
+    assert_eq!(parse_date_bound("2021-11-14", true).unwrap().to_rfc3339(), "2021-11-14T23:59:59+00:00");
+    assert_eq!(parse_date_bound("2021-11-14T02:00:00+02:00", true).unwrap().to_rfc3339(), "2021-11-14T00:00:00+00:00");
+    assert!(parse_date_bound("2021-11-31", false).is_err());
+    assert!(parse_date_bound("last week", false).is_err());
+}
+
+/// --since and --until, for comm and list.
+fn date_window_args() -> [Arg; 2] {
+    [
+        Arg::new("since")
+            .help("Only show the snapshots taken at or after this date (YYYY-MM-DD, UTC) or RFC 3339 timestamp. For display only: the snapshots left out still count towards which ones the datasets have in common.")
+            .long("since")
+            .value_name("DATE")
+            .value_parser(|s: &str| parse_date_bound(s, false)),
+        Arg::new("until")
+            .help("Only show the snapshots taken at or before this date (YYYY-MM-DD, UTC, the whole day included) or RFC 3339 timestamp. For display only, like --since.")
+            .long("until")
+            .value_name("DATE")
+            .value_parser(|s: &str| parse_date_bound(s, true)),
+    ]
+}
+
+fn parse_date_window(matches: &ArgMatches) -> DateWindow {
+    DateWindow {
+        since: matches.get_one::<chrono::DateTime<chrono::Utc>>("since").copied(),
+        until: matches.get_one::<chrono::DateTime<chrono::Utc>>("until").copied(),
+    }
+}
+
+fn exclude_arg() -> Arg {
+    Arg::new("exclude")
+        .help("Ignore snapshots whose name matches this regular expression (e.g. \"^hourly-\"). Ignored snapshots are never destroyed.")
+        .long("exclude")
+        .value_name("REGEX")
+}
+
+fn max_snapshots_arg() -> Arg {
+    Arg::new("max-snapshots")
+        .help("Refuse to go on if either dataset has more than N snapshots, e.g. because an autosnapshot job ran amok. No limit by default.")
+        .long("max-snapshots")
+        .value_name("N")
+        .value_parser(clap::value_parser!(usize))
+}
+
+/// `--depth N`, for comm and list; each gives its own help.
+fn depth_arg() -> Arg {
+    Arg::new("depth")
+        .long("depth")
+        .value_name("N")
+        .value_parser(clap::value_parser!(u32))
+}
+
+fn parse_exclude(matches: &ArgMatches) -> Result<Option<Regex>, anyhow::Error> {
+    let Some(pattern) = matches.get_one::<String>("exclude") else {
+        return Ok(None);
+    };
+    let re = Regex::new(pattern).context(format!("{pattern} isn't a valid regular expression for --exclude"))?;
+    Ok(Some(re))
+}
+
+/// The --sort-by option of comm and list.
+fn parse_sort_by(matches: &ArgMatches) -> SortBy {
+    match matches.get_one::<String>("sort-by").unwrap().as_str() {
+        "name" => SortBy::Name,
+        "guid" => SortBy::Guid,
+        _ => SortBy::Creation,
+    }
+}
+
+/// Applies the global, machine-related options to `machine`.
+fn configure_machine(machine: &mut Machine, matches: &ArgMatches) {
+    machine.set_ssh_port(matches.get_one::<u16>("ssh-port").copied());
+    machine.cmd_timeout = matches.get_one::<u64>("command-timeout").map(|secs| Duration::from_secs(*secs));
+    if let Some(zfs_path) = matches.get_one::<String>("zfs-binary") {
+        machine.zfs_path = zfs_path.clone();
+    }
+    machine.set_privilege_escalation(matches.get_one::<String>("remote-sudo").cloned());
+    let ssh_options = matches.get_many::<String>("ssh-option").unwrap_or_default().cloned().collect::<Vec<_>>();
+    machine.set_ssh_options(matches.get_one::<String>("identity-file").map(String::as_str), &ssh_options);
+    if matches.get_flag("ssh-multiplex") {
+        machine.enable_ssh_multiplexing();
+    }
+}
+
+/// Sets up `log` output on stderr, from the most specific setting given: --log-level, then
+/// replicate's --verbose, then RUST_LOG.
+fn init_logging(matches: &ArgMatches) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    let verbose = match matches.subcommand() {
+        Some(("replicate", sub_matches)) => sub_matches.get_flag("verbose"),
+        _ => false,
+    };
+    if let Some(level) = matches.get_one::<String>("log-level") {
+        builder.parse_filters(level);
+    } else if verbose {
+        builder.filter_level(log::LevelFilter::Debug);
+    }
+    builder.init();
+}
+
+/// The replicate subcommand.
+fn replicate_command() -> Command {
+    Command::new("replicate")
         .about("Synchronize snapshots between two copies of the same dataset.")
         .arg(
             Arg::new("source")
                 .help("Source dataset to replicate.")
-                .required(true)
+                .required_unless_present("datasets-from")
         )
         .arg(
             Arg::new("destination")
                 .help("Destination dataset into which to replicate.")
-                .required(true)
+                .required_unless_present("datasets-from")
+        )
+        .arg(
+            Arg::new("datasets-from")
+                .help("Replicate every pair of datasets listed in this file, one \"<source> <destination>\" pair per line, instead of <source> and <destination>. Blank lines, and lines starting with #, are ignored. A summary of each is printed once all are done; if any failed, the exit status is non-zero.")
+                .long("datasets-from")
+                .value_name("FILE")
+                .conflicts_with_all(["source", "destination"])
+        )
+        .arg(
+            Arg::new("jobs")
+                .help("With --datasets-from, replicate up to N pairs at a time. Progress bars are not shown with more than one.")
+                .long("jobs")
+                .short('j')
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .default_value("1")
         )
         .arg(
             Arg::new("verbose")
                 .action(ArgAction::SetTrue)
-                .help("Increase verbosity and display ZFS commands as they are executed.")
+                .help("Increase verbosity and display ZFS commands as they are executed, and everything zfs recv outputs (otherwise, only its last lines, should it fail). Same as --log-level debug.")
                 .short('v')
                 .long("verbose")
         )
@@ -92,6 +384,13 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
                 .short('i')
                 .long("simple-incremental")
         )
+        .arg(
+            Arg::new("until")
+                .help("Replicate up to this snapshot of <source> (its name, without the '@'), rather than up to its newest one.")
+                .long("until")
+                .value_name("SNAPSHOT")
+                .conflicts_with("take-snap-now")
+        )
         .arg(
             Arg::new("rollback")
                 .action(ArgAction::SetTrue)
@@ -99,6 +398,46 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
                 .short('F')
                 .long("rollback")
         )
+        .arg(
+            Arg::new("rollback-to")
+                .help("If <destination> has snapshots newer than the most recent one in common with <source> (whether or not they diverge), roll it back to SNAP first (zfs rollback -r), destroying those after it; once confirmed. SNAP must be a snapshot <source> has too. A controlled alternative to --rollback.")
+                .long("rollback-to")
+                .value_name("SNAP")
+        )
+        .arg(
+            Arg::new("no-mount")
+                .action(ArgAction::SetTrue)
+                .help("Don't mount the received filesystem (zfs recv -u); e.g. if its mountpoint is in use at the destination.")
+                .short('u')
+                .long("no-mount")
+        )
+        .arg(
+            Arg::new("safe-recv")
+                .action(ArgAction::SetTrue)
+                .help("Receive without disturbing the destination system: don't mount the received filesystem (-u), and set canmount=noauto and readonly=on on it, unless --set-property or --inherit-property say otherwise. Filesystems only; zfs recv refuses canmount on volumes.")
+                .long("safe-recv")
+                .overrides_with("no-safe-recv")
+        )
+        .arg(
+            Arg::new("no-safe-recv")
+                .action(ArgAction::SetTrue)
+                .help("Undo an earlier --safe-recv (the default).")
+                .long("no-safe-recv")
+                .overrides_with("safe-recv")
+        )
+        .arg(
+            Arg::new("use-d")
+                .action(ArgAction::SetTrue)
+                .help("Receive under the destination, naming the received dataset after all of the source's name but the pool (zfs recv -d); e.g. tank/lxc/web into backup/lxc/web. Not with a relative (\"//\") source.")
+                .long("use-d")
+        )
+        .arg(
+            Arg::new("use-e")
+                .action(ArgAction::SetTrue)
+                .help("Receive under the destination, naming the received dataset after the last element of the source's name (zfs recv -e); e.g. tank/lxc/web into backup/web. Not with a relative (\"//\") source.")
+                .long("use-e")
+                .conflicts_with("use-d")
+        )
         .arg(
             Arg::new("allow-divergent-destination")
                 .action(ArgAction::SetTrue)
@@ -107,17 +446,246 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
                 .long("allow-divergent-destination")
                 .requires("rollback")
         )
+        .arg(
+            Arg::new("create-parents")
+                .action(ArgAction::SetTrue)
+                .help("When initializing the destination, create its missing ancestors first (the default).")
+                .long("create-parents")
+                .overrides_with("no-create-parents")
+        )
+        .arg(
+            Arg::new("no-create-parents")
+                .action(ArgAction::SetTrue)
+                .help("When initializing the destination, fail if its parent doesn't exist, rather than creating it; e.g. so that a typo in the destination doesn't go unnoticed.")
+                .long("no-create-parents")
+                .overrides_with("create-parents")
+        )
         .arg(
             Arg::new("init-nonexistent-destination")
                 .action(ArgAction::SetTrue)
                 .help("Initialize the destination by first sending a base snapshot in full if the dataset to be synchronized does not exist in the destination.")
                 .long("init")
         )
+        .arg(
+            Arg::new("init-latest")
+                .action(ArgAction::SetTrue)
+                .help("When initializing the destination, send the newest snapshot of the source in full, rather than the oldest one followed by all the rest. The destination then has none of the snapshots before it.")
+                .long("init-latest")
+        )
         .arg(
             Arg::new("ratelimit")
-                .help("Limit the transfer rate as per `pv -L`")
+                .help("Limit the transfer rate as per `pv -L` (or `mbuffer -r`, with --buffer-tool mbuffer).")
                 .long("ratelimit")
         )
+        .arg(
+            Arg::new("ratelimit-schedule")
+                .help("Pick the rate limit by the (local) time of day at which the transfer starts, e.g. \"08:00-18:00=10M,18:00-08:00=0\". A rate of 0, or a time not covered by any range, means no limit.")
+                .long("ratelimit-schedule")
+                .value_name("SCHEDULE")
+                .value_parser(parse_ratelimit_schedule)
+                .conflicts_with("ratelimit")
+        )
+        .arg(
+            Arg::new("buffer-tool")
+                .help("Program to put between zfs-send and zfs-recv. pv is only used with --ratelimit; mbuffer is always used, and buffers the stream in memory, which much improves throughput over high-latency links.")
+                .long("buffer-tool")
+                .value_parser(["pv", "mbuffer"])
+                .default_value("pv")
+        )
+        .arg(
+            Arg::new("ratelimit-side")
+                .help("Where to run the rate-limiting (or buffering) program. \"remote\" runs it on the destination when pushing, or on the source when pulling, which keeps the data from piling up in the network in between; \"local\" works even when neither side is remote, and shows in the local process list.")
+                .long("ratelimit-side")
+                .value_parser(["local", "remote"])
+                .default_value("local")
+        )
+        .arg(
+            Arg::new("transport-compress")
+                .help("Compress the stream on the source, and decompress it on the destination, for it to cross SSH compressed. Pointless (and left out) unless used along with --no-compressed and without --raw.")
+                .long("transport-compress")
+                .value_name("COMPRESSOR")
+                .value_parser(["zstd", "gzip"])
+        )
+        .arg(
+            Arg::new("buffer-size")
+                .help("Size of mbuffer's in-memory buffer, as per `mbuffer -m`.")
+                .long("buffer-size")
+                .value_name("SIZE")
+                .default_value("256M")
+        )
+        .arg(
+            Arg::new("set-property")
+                .action(ArgAction::Append)
+                .help("Set property NAME to VALUE on the received dataset (zfs recv -o), e.g. mountpoint=none. May be given multiple times.")
+                .long("set-property")
+                .value_name("NAME=VALUE")
+                .value_parser(parse_set_property)
+        )
+        .arg(
+            Arg::new("inherit-property")
+                .action(ArgAction::Append)
+                .help("Have the received dataset inherit property NAME instead of taking it from the stream (zfs recv -x). May be given multiple times.")
+                .long("inherit-property")
+                .value_name("NAME")
+                .value_parser(parse_inherit_property)
+        )
+        .arg(
+            Arg::new("recursive")
+                .action(ArgAction::SetTrue)
+                .help(
+"Replicate <source> and all of its descendant datasets (zfs send -R). Only the snapshots of <source> itself are compared against <destination>.
+Descendants created after the incremental base snapshot are sent in full, but a descendant which has the base snapshot and is missing at the destination will make the receive fail; initialize it on its own first."
+                )
+                .short('R')
+                .long("recursive")
+        )
+        .arg(
+            Arg::new("raw")
+                .action(ArgAction::SetTrue)
+                .help("Send encrypted datasets raw (zfs send -w). The data stays encrypted in transit and at <destination>, which will remain locked until its key is loaded there.")
+                .short('w')
+                .long("raw")
+        )
+        .arg(
+            Arg::new("no-compressed")
+                .action(ArgAction::SetTrue)
+                .help("Don't send blocks compressed as they are on disk (omit zfs send -c); <destination> will compress them according to its own settings.")
+                .long("no-compressed")
+        )
+        .arg(
+            Arg::new("no-large-blocks")
+                .action(ArgAction::SetTrue)
+                .help("Don't send blocks larger than 128KiB as such (omit zfs send -L). Needed if <destination>'s pool lacks the large_blocks feature.")
+                .long("no-large-blocks")
+        )
+        .arg(
+            Arg::new("no-embed-data")
+                .action(ArgAction::SetTrue)
+                .help("Don't send embedded blocks as such (omit zfs send -e). Needed if <destination>'s pool lacks the embedded_data feature.")
+                .long("no-embed-data")
+        )
+        .arg(
+            Arg::new("no-props")
+                .action(ArgAction::SetTrue)
+                .help("Don't send the dataset's properties (omit zfs send -p).")
+                .long("no-props")
+        )
+        .arg(
+            Arg::new("no-resume")
+                .action(ArgAction::SetTrue)
+                .help("If a previous transfer into <destination> was interrupted, discard its partial state (zfs recv -A) instead of resuming it.")
+                .long("no-resume")
+        )
+        .arg(
+            Arg::new("prune-destination")
+                .action(ArgAction::SetTrue)
+                .help("After the transfer, DESTROY the snapshots in <destination> which no longer exist in <source>. Refuses to run if <destination> has snapshots newer than the last one in common.")
+                .long("prune-destination")
+        )
+        .arg(
+            Arg::new("bookmark-on-send")
+                .action(ArgAction::SetTrue)
+                .help("After the transfer, bookmark the newest snapshot of <source> (zfs bookmark), so that later runs can send incrementally from it even once the snapshot is pruned from <source>.")
+                .long("bookmark-on-send")
+        )
+        .arg(
+            Arg::new("hold-latest")
+                .help("After the transfer, hold the newest snapshot of <destination> with TAG (zfs hold), and release TAG from the previous incremental base, so that pruning <destination> can't destroy the base for the next run.")
+                .long("hold-latest")
+                .value_name("TAG")
+        )
+        .arg(
+            Arg::new("quiet")
+                .action(ArgAction::SetTrue)
+                .help("Don't draw progress bars. This is the default when standard error isn't a terminal.")
+                .short('q')
+                .long("quiet")
+                .conflicts_with("progress-format")
+        )
+        .arg(
+            Arg::new("progress")
+                .action(ArgAction::SetTrue)
+                .help("Draw progress bars even if standard error isn't a terminal.")
+                .long("progress")
+                .conflicts_with("quiet")
+        )
+        .arg(
+            Arg::new("progress-format")
+                .help("How to report progress. \"json\" prints one {snapshot, sent_bytes, snap_total, overall_sent, overall_total} object per line to standard output.")
+                .long("progress-format")
+                .value_parser(["bars", "json"])
+                .default_value("bars")
+        )
+        .arg(
+            Arg::new("force")
+                .action(ArgAction::SetTrue)
+                .help("Go ahead despite the checks made before sending, e.g. that a raw send (--raw) of a dataset which isn't its own encryption root has somewhere to go at the destination.")
+                .long("force")
+        )
+        .arg(
+            Arg::new("force-full")
+                .action(ArgAction::SetTrue)
+                .help("If the destination has no snapshots in common with the source, or has diverged from it, destroy it (along with its snapshots and descendants) and send the source in full. Asks for confirmation first.")
+                .long("force-full")
+        )
+        .arg(
+            Arg::new("verify")
+                .help("Once done, check that the newest snapshot of <destination> is that of <source>, i.e. has the same guid. With \"deep\", also compare the stream size that zfs send -nP estimates for it on both sides.")
+                .long("verify")
+                .value_name("HOW")
+                .value_parser(["guid", "deep"])
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("guid")
+        )
+        .arg(
+            Arg::new("on-success")
+                .help("Run CMD on this machine once replicated, e.g. to ping a monitoring service. It's given ZFS_RS_SOURCE, ZFS_RS_DEST, ZFS_RS_STATUS, ZFS_RS_BYTES and ZFS_RS_BASE_SNAP in its environment. Run through sh -c only if it looks like a shell command line (quotes, pipes, variables...). Not run on dry runs.")
+                .long("on-success")
+                .value_name("CMD")
+        )
+        .arg(
+            Arg::new("on-failure")
+                .help("Like --on-success, once failed to replicate; ZFS_RS_STATUS is \"failed\", and ZFS_RS_ERROR says why.")
+                .long("on-failure")
+                .value_name("CMD")
+        )
+        .arg(
+            Arg::new("strict-hooks")
+                .action(ArgAction::SetTrue)
+                .help("Fail if --on-success or --on-failure does, rather than only warning about it.")
+                .long("strict-hooks")
+        )
+        .arg(
+            Arg::new("retries")
+                .help("If a transfer is cut short, e.g. by a network failure, resume it up to N times, waiting longer every time. Transfers which zfs-recv refuses outright aren't retried.")
+                .long("retries")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("0")
+        )
+        .arg(max_snapshots_arg())
+        .arg(
+            Arg::new("output")
+                .help("Format of the final report. \"json\" prints a {source, destination, base, snapshots_sent, bytes_sent, status} object.")
+                .long("output")
+                .value_parser(["text", "json"])
+                .default_value("text")
+        )
+        .arg(
+            Arg::new("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Analyze both datasets and print the zfs-send/zfs-recv commands that would be run, without running them.")
+                .short('n')
+                .long("dry-run")
+        )
+        .arg(
+            Arg::new("estimate")
+                .action(ArgAction::SetTrue)
+                .help("Only report how much would be sent, as estimated by zfs send -nvP, without sending anything.")
+                .long("estimate")
+                .conflicts_with_all(["datasets-from", "dry-run"])
+        )
         .arg(
             Arg::new("take-snap-now")
                 .action(ArgAction::SetTrue)
@@ -133,7 +701,27 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
                 .long("snap-name")
                 .short('T')
                 .requires("take-snap-now")  //TODO the auto-generated error message isn't very friendly; maybe we can move this into custom logic, or look into embettering the default message?
-        );
+        )
+        .arg(
+            Arg::new("recursive-snap")
+                .action(ArgAction::SetTrue)
+                .help("Take the snapshot of --take-snap-now recursively (zfs snapshot -r), for a consistent point in time across all descendants. Only the source dataset itself is replicated, unless --recursive is given too.")
+                .long("recursive-snap")
+                .requires("take-snap-now")
+        )
+        .arg(
+            Arg::new("snap-name-template")
+                .help("Name the snapshot created by --take-snap-now after the current (local) time, e.g. \"auto-%Y-%m-%d_%H%M%S\". See chrono's strftime documentation for the format.")
+                .long("snap-name-template")
+                .value_name("TEMPLATE")
+                .requires("take-snap-now")
+                .conflicts_with("take-snap-now-name")
+        )
+        .args(src_dst_zfs_binary_args())
+}
+
+fn main() {
+    let replicate = replicate_command();
 
     let apply_retention = Command::new("apply-retention")
         .about("Apply a retention policy to a dataset.")
@@ -144,13 +732,111 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
         )
         .arg(
             Arg::new("no-keep-unusual")
-                .help("[Pangea specific] Also considers snapshots not named \"YYYY-MM-DD\" for deletion.")
+                .action(ArgAction::SetTrue)
+                .help("Also consider snapshots whose name doesn't match --normal-name-pattern for deletion. By default, they are always kept.")
                 .long("no-keep-unusual")
         )
+        .arg(
+            Arg::new("now")
+                .help("Apply the policy as of this time, in RFC 3339 format (e.g. 2021-11-14T00:00:00Z), rather than the current time; e.g. to preview what it will destroy next Sunday.")
+                .long("now")
+                .alias("reference-time")
+                .value_name("TIMESTAMP")
+                .value_parser(parse_reference_time)
+        )
+        .arg(
+            Arg::new("min-keep")
+                .help("Always keep the N newest snapshots, whatever the --keep-* options say.")
+                .long("min-keep")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("1")
+        )
+        .arg(
+            Arg::new("allow-delete-all")
+                .action(ArgAction::SetTrue)
+                .help("Go ahead even if the policy would destroy every single snapshot of the dataset (which takes --min-keep 0); otherwise, that is refused as a likely mistake.")
+                .long("allow-delete-all")
+        )
+        .arg(
+            Arg::new("ignore-holds")
+                .action(ArgAction::SetTrue)
+                .help("Don't keep held snapshots just for being held (see zfs-hold(8)). Those that the policy doesn't keep are destroyed with zfs destroy -d, i.e. once their holds are released.")
+                .long("ignore-holds")
+        )
+        .arg(
+            Arg::new("release-holds")
+                .help("Release the hold by TAG (e.g. as placed by replicate --hold-latest) from the snapshots that the policy doesn't keep, so that they can be destroyed. Holds by other tags are never released.")
+                .long("release-holds")
+                .value_name("TAG")
+        )
+        .arg(
+            Arg::new("normal-name-pattern")
+                .help("Regular expression matching the names of the snapshots taken on schedule, e.g. \"^daily-\\d{8}$\". Snapshots whose name doesn't match are always kept, unless --no-keep-unusual is given.")
+                .long("normal-name-pattern")
+                .value_name("REGEX")
+                .default_value(DEFAULT_NORMAL_NAME_PATTERN)
+        )
         .arg(
             Arg::new("run-directly")
+                .action(ArgAction::SetTrue)
                 .help("Run the zfs-destroy command directly instead of printing it for manual review.")
                 .long("run-directly")
+        )
+        .arg(
+            Arg::new("keep-last")
+                .help("Keep the N most recent snapshots.")
+                .long("keep-last")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+        )
+        .arg(
+            Arg::new("keep-daily")
+                .help("Keep the most recent snapshot of each of the last N days.")
+                .long("keep-daily")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+        )
+        .arg(
+            Arg::new("keep-weekly")
+                .help("Keep the most recent snapshot of each of the last N weeks.")
+                .long("keep-weekly")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+        )
+        .arg(
+            Arg::new("keep-monthly")
+                .help("Keep the most recent snapshot of each of the last N months.")
+                .long("keep-monthly")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+        )
+        .arg(
+            Arg::new("keep-since")
+                .help("Keep every snapshot taken within this long, e.g. 14d, 2w, 6m (of 30 days) or 1y (of 365 days).")
+                .long("keep-since")
+                .value_name("DURATION")
+                .value_parser(parse_keep_since)
+        )
+        .arg(
+            Arg::new("keep-yearly")
+                .help("Keep the most recent snapshot of each of the last N years.")
+                .long("keep-yearly")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+        )
+        .arg(
+            Arg::new("keep")
+                .help("Keep the N most recent snapshots whose name matches GLOB (with * and ?, matching the whole name), e.g. --keep 'hourly-*:24' --keep 'daily-*:14'. May be given several times; a snapshot counts towards the first GLOB it matches only. Snapshots matching any GLOB aren't unusual (see --no-keep-unusual).")
+                .long("keep")
+                .value_name("GLOB:N")
+                .action(ArgAction::Append)
+                .value_parser(parse_keep_rule)
+        )
+        .arg(exclude_arg())
+        .after_help(
+"If none of the --keep* options is given, the default policy is: --keep-last 10 --keep-daily 7 --keep-weekly 4 --keep-monthly 12.
+If any of them is given, the ones left out default to 0."
         );
 
     let comm = Command::new("comm")
@@ -165,6 +851,12 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
                 .help("Right-hand side, or destination, dataset.")
                 .required(true)
         )
+        .arg(
+            Arg::new("right-from-file")
+                .help("Read the snapshots of <destination> from FILE, as saved from `zfs list -Hp -o name,creation,guid,userrefs,used,referenced,written,createtxg -t snapshot [-r]`, rather than asking its host; e.g. to compare against what a backup host reported yesterday.")
+                .long("right-from-file")
+                .value_name("FILE")
+        )
         .arg(
             Arg::new("collapse")
                 .help("Group consecutive runs for a terser output.")
@@ -173,91 +865,680 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
         )
         .arg(
             Arg::new("collapse-keep-both-ends")
-                .help("Group consecutive runs for a terser output; display both first and last element in each group.")
+                .help("Group consecutive runs for a terser output; display both first and last element in each group. Along with -c, also display how many there are in each group, all told.")
                 .short('C')
                 .action(ArgAction::SetTrue)
-                .conflicts_with("collapse")
         )
         .arg(
             Arg::new("reverse-sort")
                 .help("Display snapshots in descending chronological order (newest first).")
                 .short('r')
                 .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("sort-by")
+                .help("Order in which to display snapshots. Sorting by anything but creation disables --collapse and --collapse-keep-both-ends, whose runs only make sense between chronologically adjacent snapshots.")
+                .long("sort-by")
+                .value_parser(["creation", "name", "guid"])
+                .default_value("creation")
+        )
+        .arg(
+            Arg::new("format")
+                .help("Output format. \"json\" prints an array of {side, name, guid, creation, used, referenced, written, conflict} objects.")
+                .long("format")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .conflicts_with_all(["collapse", "collapse-keep-both-ends"])
+        )
+        .arg(
+            Arg::new("show-dates")
+                .action(ArgAction::SetTrue)
+                .help("Follow each snapshot name with its creation date (RFC 3339, unless --date-format is given). When collapsing, the date of the last snapshot of each run is shown too.")
+                .long("show-dates")
+        )
+        .arg(
+            Arg::new("show-guids")
+                .action(ArgAction::SetTrue)
+                .help("Follow each snapshot name with its guid. Either way, snapshots found by the same name on both sides, but with different guids, are marked \"[conflict]\".")
+                .long("show-guids")
+        )
+        .arg(
+            Arg::new("date-format")
+                .help("Format for --show-dates, with chrono's strftime-like syntax, e.g. \"%F %R\". Implies --show-dates.")
+                .long("date-format")
+                .value_name("FORMAT")
+                .value_parser(parse_date_format)
+        )
+        .arg(
+            Arg::new("only-left")
+                .action(ArgAction::SetTrue)
+                .help("Only show the snapshots found just on the left-hand side; i.e. what replicate would send.")
+                .long("only-left")
+                .conflicts_with_all(["only-common", "only-right"])
+        )
+        .arg(
+            Arg::new("only-common")
+                .action(ArgAction::SetTrue)
+                .help("Only show the snapshots found on both sides.")
+                .long("only-common")
+                .conflicts_with("only-right")
+        )
+        .arg(
+            Arg::new("only-right")
+                .action(ArgAction::SetTrue)
+                .help("Only show the snapshots found just on the right-hand side.")
+                .long("only-right")
+        )
+        .arg(exclude_arg())
+        .arg(max_snapshots_arg())
+        .arg(
+            Arg::new("diverged-only")
+                .action(ArgAction::SetTrue)
+                .help("Only show where <source> and <destination> diverge: their most recent snapshot in common, and those after it on either side. If they have none in common, all of them, under a note saying so.")
+                .long("diverged-only")
+        )
+        .arg(
+            Arg::new("color")
+                .help("Color the snapshots after the side they're on: red for <source> only, green for both, blue for <destination> only. \"auto\" colors only if standard output is a terminal and NO_COLOR isn't set. Never applies to --format json.")
+                .long("color")
+                .value_name("WHEN")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto")
+        )
+        .args(date_window_args())
+        .arg(depth_arg()
+            .help("Also compare the descendants of <source> and <destination>, up to N levels below them (0 for neither), paired up by their names relative to either; one section per pair. Snapshots are listed with a single zfs list per side.")
+        )
+        .args(src_dst_zfs_binary_args());
+
+    let list = Command::new("list")
+        .about("List the snapshots of a dataset, with their creation date, guid and number of holds.")
+        .arg(
+            Arg::new("dataset")
+                .help("Dataset on which to operate.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("reverse-sort")
+                .help("Display snapshots in descending chronological order (newest first).")
+                .short('r')
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("sort-by")
+                .help("Order in which to display snapshots.")
+                .long("sort-by")
+                .value_parser(["creation", "name", "guid"])
+                .default_value("creation")
+        )
+        .arg(
+            Arg::new("format")
+                .help("Output format. \"json\" prints an array of {name, creation, guid, holds} objects.")
+                .long("format")
+                .value_parser(["text", "json"])
+                .default_value("text")
+        )
+        .args(date_window_args())
+        .arg(depth_arg()
+            .help("Also list the snapshots of the descendants of the dataset, up to N levels below it, one table per dataset. \"json\" then prints an array of {dataset, snapshots} objects.")
+        );
+
+    let plan = Command::new("plan")
+        .about("Work out what replicate would do, without sending anything: how the two datasets compare, which snapshot to send from, how many snapshots to send, and which options replicate would need.")
+        .arg(
+            Arg::new("source")
+                .help("Source dataset.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("destination")
+                .help("Destination dataset.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("format")
+                .help("Output format. \"json\" prints a single {source, destination, case, base, snapshots_to_send, needs_init, requires} object.")
+                .long("format")
+                .value_parser(["text", "json"])
+                .default_value("text")
+        )
+        .args(src_dst_zfs_binary_args());
+
+    let abort_resume = Command::new("abort-resume")
+        .about("Discard the partial state left behind in a dataset by an interrupted, resumable receive (zfs recv -A).")
+        .arg(
+            Arg::new("dataset")
+                .help("Dataset on which to operate.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Only report whether there is any partial receive state.")
+                .short('n')
+                .long("dry-run")
+        );
+
+    let send_to_file = Command::new("send-to-file")
+        .about("Write the zfs-send stream of a dataset to a file, for recv-from-file to receive elsewhere; e.g. where no SSH connection can be made between source and destination.")
+        .arg(
+            Arg::new("source")
+                .help("Dataset to send, up to its newest snapshot.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("output")
+                .help("File to write the stream to, which must not exist yet (named pipes excepted); \"-\" for standard output.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("from")
+                .help("Send incrementally from this snapshot, i.e. the newest one the destination has. Without it, the newest snapshot is sent in full.")
+                .long("from")
+                .value_name("SNAPSHOT")
+        )
+        .arg(
+            Arg::new("simple-incremental")
+                .action(ArgAction::SetTrue)
+                .help("With --from, leave out the intervening snapshots, i.e. use zfs send -i instead of -I.")
+                .short('i')
+                .long("simple-incremental")
+                .requires("from")
+        )
+        .arg(
+            Arg::new("raw")
+                .action(ArgAction::SetTrue)
+                .help("Send encrypted datasets raw (zfs send -w).")
+                .short('w')
+                .long("raw")
+        );
+
+    let recv_from_file = Command::new("recv-from-file")
+        .about("Receive a stream written by send-to-file into a dataset.")
+        .arg(
+            Arg::new("input")
+                .help("File to read the stream from; \"-\" for standard input.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("destination")
+                .help("Dataset to receive into.")
+                .required(true)
+        )
+        .arg(
+            Arg::new("rollback")
+                .action(ArgAction::SetTrue)
+                .help("Allow rolling back the destination (zfs recv -F).")
+                .short('F')
+                .long("rollback")
+        );
+
+    let run = Command::new("run")
+        .about("Run the replication jobs described in a TOML config file, each followed by the retention policies it defines; e.g. from cron. All of the file is checked before anything is done.")
+        .arg(
+            Arg::new("config")
+                .help("Config file describing the jobs to run, as [[job]] tables; see zfs-rs(8).")
+                .long("config")
+                .short('c')
+                .value_name("FILE")
+                .required(true)
+        )
+        .arg(
+            Arg::new("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Do not actually receive anything, and only print the zfs-destroy commands of the retention policies.")
+                .short('n')
+                .long("dry-run")
+        )
+        .arg(
+            Arg::new("check")
+                .action(ArgAction::SetTrue)
+                .help("Only check the config file.")
+                .long("check")
         );
 
     let mut main_parser = Command::new("zfs-rs")
         .about("Toolkit for common ZFS administrative tasks.")
+        .arg(
+            Arg::new("ssh-port")
+                .help("Port to connect to on remote machines, for any dataset spec of the form host:dataset.")
+                .long("ssh-port")
+                .value_name("PORT")
+                .value_parser(clap::value_parser!(u16))
+                .global(true)
+        )
+        .arg(
+            Arg::new("ssh-multiplex")
+                .action(ArgAction::SetTrue)
+                .help("Run all commands on each remote machine over a single, shared SSH connection (ssh -o ControlMaster).")
+                .long("ssh-multiplex")
+                .global(true)
+        )
+        .arg(
+            Arg::new("identity-file")
+                .help("Private key to authenticate with on remote machines (ssh -i).")
+                .long("identity-file")
+                .value_name("FILE")
+                .global(true)
+        )
+        .arg(
+            Arg::new("ssh-option")
+                .help("Option to pass on to ssh(1) as -o OPTION when connecting to remote machines, e.g. \"ConnectTimeout=10\". May be given several times.")
+                .long("ssh-option")
+                .value_name("OPTION")
+                .action(ArgAction::Append)
+                .global(true)
+        )
+        .arg(
+            Arg::new("remote-sudo")
+                .help("Run zfs on remote machines through this privilege escalation command, for when logging in as a non-root user. Must not prompt for a password.")
+                .long("remote-sudo")
+                .value_name("COMMAND")
+                .num_args(0..=1)
+                .default_missing_value("sudo -n")
+                .global(true)
+        )
+        .arg(
+            Arg::new("zfs-binary")
+                .help("Path to the zfs binary, on every machine involved. Defaults to looking up \"zfs\" in PATH.")
+                .long("zfs-binary")
+                .value_name("PATH")
+                .global(true)
+        )
+        .arg(
+            Arg::new("command-timeout")
+                .help("Give up on ZFS commands (other than zfs-send and zfs-recv themselves) which take longer than this many seconds.")
+                .long("command-timeout")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .global(true)
+        )
+        .arg(
+            Arg::new("log-level")
+                .help("Show diagnostics of this level and above. Overrides RUST_LOG; defaults to \"warn\", or \"debug\" with replicate --verbose.")
+                .long("log-level")
+                .value_name("LEVEL")
+                .value_parser(["error", "warn", "info", "debug", "trace"])
+                .global(true)
+        )
+        .arg(
+            Arg::new("yes")
+                .action(ArgAction::SetTrue)
+                .help("Don't ask for confirmation before destroying data: with replicate --force-full, or --rollback when it discards snapshots, and apply-retention --run-directly. Without a terminal to ask on, these are refused unless --yes is given.")
+                .long("yes")
+                .short('y')
+                .global(true)
+        )
         .subcommand(replicate)
         .subcommand(apply_retention)
-        .subcommand(comm);
+        .subcommand(comm)
+        .subcommand(list)
+        .subcommand(plan)
+        .subcommand(abort_resume)
+        .subcommand(send_to_file)
+        .subcommand(recv_from_file)
+        .subcommand(run);
 
     let main_matches = main_parser.get_matches_mut();
+    init_logging(&main_matches);
+    confirm::set_assume_yes(main_matches.get_flag("yes"));
+    interrupt::install_handler();
 
     let result : anyhow::Result<String> = match main_matches.subcommand() {
         Some(("replicate", sub_matches)) => {
-            let (mut src_machine, mut src_ds) = parse_spec(sub_matches.get_one::<String>("source").unwrap()).unwrap_or_else(|err| {
-                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("source").unwrap(), err );
-                exit(1);
-            });
-            let (mut dst_machine, mut dst_ds) = parse_spec(sub_matches.get_one::<String>("destination").unwrap()).unwrap_or_else(|err| {
-                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("destination").unwrap(), err);
-                exit(1);
-            });
+            let pairs = match sub_matches.get_one::<String>("datasets-from") {
+                Some(path) => std::fs::read_to_string(path)
+                    .context(format!("Unable to read {path}"))
+                    .and_then(|content| parse_datasets_file(&content))
+                    .unwrap_or_else(|err| {
+                        eprintln!("{:#}", err);
+                        exit(1);
+                    }),
+                None => vec![(
+                    sub_matches.get_one::<String>("source").unwrap().to_owned(),
+                    sub_matches.get_one::<String>("destination").unwrap().to_owned(),
+                )],
+            };
+            let mut jobs = pairs.iter()
+                .map(|(source, destination)| {
+                    let (mut src_machine, src_ds) = parse_spec(source).unwrap_or_else(|err| {
+                        eprintln!("Can't parse {} as a valid ZFS dataset: {}", source, err);
+                        exit(1);
+                    });
+                    let (mut dst_machine, dst_ds) = parse_spec(destination).unwrap_or_else(|err| {
+                        eprintln!("Can't parse {} as a valid ZFS dataset: {}", destination, err);
+                        exit(1);
+                    });
+                    configure_machine(&mut src_machine, sub_matches);
+                    configure_machine(&mut dst_machine, sub_matches);
+                    configure_src_dst_zfs_binary(&mut src_machine, &mut dst_machine, sub_matches);
+                    ReplicateJob { src_machine, src_ds, dst_machine, dst_ds }
+                })
+                .collect::<Vec<_>>();
+            let concurrency = *sub_matches.get_one::<u32>("jobs").unwrap() as usize;
             let take_snap_now: Option<String> =
                 if sub_matches.get_flag("take-snap-now") {
                     if let Some(name) = sub_matches.get_one::<String>("take-snap-now-name") {
                         Some(name.to_owned())
+                    } else if let Some(template) = sub_matches.get_one::<String>("snap-name-template") {
+                        Some(render_snap_name_template(template, &chrono::Local::now()).unwrap_or_else(|err| {
+                            eprintln!("{:#}", err);
+                            exit(1);
+                        }))
                     } else {
                         Some(format!("zfs-rs-{}", get_n_random_chars(7)))
                     }
                 } else {
                     None
                 };
-            let ratelimit = sub_matches.get_one::<String>("ratelimit");
-            if let Some(rate) = ratelimit {
-                if let Err(_) = verify_pv_rate(rate) {
-                    eprintln!("{} isn't a valid rate limit for `pv -L`. Hint: use something like `50M`.", rate);
+            let mut ratelimit = sub_matches.get_one::<String>("ratelimit").cloned();
+            if let Some(schedule) = sub_matches.get_one::<Vec<RateScheduleEntry>>("ratelimit-schedule") {
+                ratelimit = scheduled_ratelimit(schedule, chrono::Local::now().time());
+                eprintln!("Rate limit as per --ratelimit-schedule: {}.", ratelimit.as_deref().unwrap_or("none"));
+            }
+            if let Some(rate) = &ratelimit {
+                if let Err(err) = parse_rate(rate) {
+                    eprintln!("Invalid rate limit {err} Hint: use something like `50M`.");
                     exit(1);
                 }
             }
+            let buffer_size = sub_matches.get_one::<String>("buffer-size").unwrap();
+            if let Err(err) = parse_rate(buffer_size) {
+                eprintln!("Invalid buffer size {err} Hint: use something like `256M`.");
+                exit(1);
+            }
             let opts = ReplicateDatasetOpts {
-                app_verbose: sub_matches.get_flag("verbose"),
                 simple_incremental: sub_matches.get_flag("simple-incremental"),
+                until: sub_matches.get_one::<String>("until").cloned(),
                 use_rollback_flag_on_recv: sub_matches.get_flag("rollback"),
                 allow_divergent_destination: sub_matches.get_flag("allow-divergent-destination"),
                 init_nonexistent_destination: sub_matches.get_flag("init-nonexistent-destination"),
+                init_latest: sub_matches.get_flag("init-latest"),
+                create_parents: !sub_matches.get_flag("no-create-parents"),
                 take_snap_now,
-                ratelimit: ratelimit.map(|s| s.to_owned()),
+                recursive_snap: sub_matches.get_flag("recursive-snap"),
+                ratelimit,
+                ratelimit_side: match sub_matches.get_one::<String>("ratelimit-side").unwrap().as_str() {
+                    "remote" => RatelimitSide::Remote,
+                    _ => RatelimitSide::Local,
+                },
+                transport_compress: sub_matches.get_one::<String>("transport-compress").map(|c| match c.as_str() {
+                    "gzip" => TransportCompression::Gzip,
+                    _ => TransportCompression::Zstd,
+                }),
+                recv_properties: sub_matches.get_many::<(String, String)>("set-property").into_iter().flatten()
+                    .map(|(name, value)| (name.clone(), Some(value.clone())))
+                    .chain(sub_matches.get_many::<String>("inherit-property").into_iter().flatten()
+                        .map(|name| (name.clone(), None)))
+                    .collect(),
+                no_mount: sub_matches.get_flag("no-mount"),
+                safe_recv: sub_matches.get_flag("safe-recv"),
+                recv_path_remap: match (sub_matches.get_flag("use-d"), sub_matches.get_flag("use-e")) {
+                    (true, _) => Some(RecvPathRemap::AllButPool),
+                    (_, true) => Some(RecvPathRemap::LastElement),
+                    _ => None,
+                },
+                buffer: match sub_matches.get_one::<String>("buffer-tool").unwrap().as_str() {
+                    "mbuffer" => Some(BufferTool::Mbuffer { size: buffer_size.to_owned() }),
+                    _ => Some(BufferTool::Pv),
+                },
+                progress: match sub_matches.get_one::<String>("progress-format").unwrap().as_str() {
+                    // The progress of concurrent jobs would be all mixed up.
+                    _ if concurrency > 1 && jobs.len() > 1 => ProgressFormat::Quiet,
+                    "json" => ProgressFormat::Json,
+                    _ if sub_matches.get_flag("quiet") => ProgressFormat::Quiet,
+                    _ if !sub_matches.get_flag("progress") && !std::io::stderr().is_terminal() => ProgressFormat::Quiet,
+                    _ => ProgressFormat::Bars,
+                },
+                dry_run: sub_matches.get_flag("dry-run"),
+                no_resume: sub_matches.get_flag("no-resume"),
+                prune_destination: sub_matches.get_flag("prune-destination"),
+                bookmark_on_send: sub_matches.get_flag("bookmark-on-send"),
+                hold_latest: sub_matches.get_one::<String>("hold-latest").cloned(),
+                rollback_to: sub_matches.get_one::<String>("rollback-to").cloned(),
+                force: sub_matches.get_flag("force"),
+                force_full: sub_matches.get_flag("force-full"),
+                verify: sub_matches.get_one::<String>("verify").map(|how| match how.as_str() {
+                    "deep" => Verify::Deep,
+                    _ => Verify::Guid,
+                }),
+                on_success: sub_matches.get_one::<String>("on-success").cloned(),
+                on_failure: sub_matches.get_one::<String>("on-failure").cloned(),
+                strict_hooks: sub_matches.get_flag("strict-hooks"),
+                retries: *sub_matches.get_one::<u32>("retries").unwrap(),
+                max_snapshots: sub_matches.get_one::<usize>("max-snapshots").copied(),
+                send_flags: SendFlags {
+                    compressed: !sub_matches.get_flag("no-compressed"),
+                    large_blocks: !sub_matches.get_flag("no-large-blocks"),
+                    embed_data: !sub_matches.get_flag("no-embed-data"),
+                    props: !sub_matches.get_flag("no-props"),
+                    raw: sub_matches.get_flag("raw"),
+                    recursive: sub_matches.get_flag("recursive"),
+                    dry_run: false,
+                },
             };
-            replicate_dataset_cli(&mut src_machine, &mut src_ds, &mut dst_machine, &mut dst_ds, opts)
+            let output = sub_matches.get_one::<String>("output").unwrap();
+            if sub_matches.contains_id("datasets-from") {
+                let outcomes = replicate_many_cli(jobs, &opts, concurrency);
+                let summary = render_many_summary(&outcomes, output == "json");
+                match outcomes.iter().filter(|(_, result)| result.is_err()).count() {
+                    0 => Ok(summary),
+                    failed => Err(anyhow::anyhow!("{summary}\n{failed} of {} replication(s) failed.", outcomes.len())),
+                }
+            } else if sub_matches.get_flag("estimate") {
+                let ReplicateJob { mut src_machine, mut src_ds, mut dst_machine, mut dst_ds } = jobs.pop().unwrap();
+                estimate_replication_cli(&mut src_machine, &mut src_ds, &mut dst_machine, &mut dst_ds, opts)
+            } else {
+                let ReplicateJob { mut src_machine, mut src_ds, mut dst_machine, mut dst_ds } = jobs.pop().unwrap();
+                replicate_dataset_cli(&mut src_machine, &mut src_ds, &mut dst_machine, &mut dst_ds, opts)
+                    .map(|result| match output.as_str() {
+                        "json" => result.to_json().to_string(),
+                        _ => result.message,
+                    })
+            }
         }
 
         Some(("apply-retention", sub_matches)) => {
             let (mut machine, mut ds) = parse_spec(sub_matches.get_one::<String>("dataset").unwrap()).unwrap();
+            configure_machine(&mut machine, sub_matches);
+            let exclude = parse_exclude(sub_matches).unwrap_or_else(|err| {
+                eprintln!("{:#}", err);
+                exit(1);
+            });
+            let normal_name_pattern = sub_matches.get_one::<String>("normal-name-pattern").unwrap();
+            let normal_name = Regex::new(normal_name_pattern).unwrap_or_else(|err| {
+                eprintln!("{normal_name_pattern} isn't a valid regular expression for --normal-name-pattern: {err}");
+                exit(1);
+            });
+            let keep_since = sub_matches.get_one::<chrono::Duration>("keep-since").copied();
+            let keep = sub_matches.get_many::<KeepRule>("keep").unwrap_or_default().cloned().collect::<Vec<_>>();
+            let buckets = ["keep-last", "keep-daily", "keep-weekly", "keep-monthly", "keep-yearly"]
+                .map(|arg| sub_matches.get_one::<u32>(arg).copied());
+            let [keep_last, keep_daily, keep_weekly, keep_monthly, keep_yearly] =
+                if buckets.iter().all(Option::is_none) && keep_since.is_none() && keep.is_empty() {
+                    [10, 7, 4, 12, 0]
+                } else {
+                    buckets.map(|n| n.unwrap_or(0))
+                };
             let opts = RetentionOpts {
                 keep_unusual: !sub_matches.get_flag("no-keep-unusual"),
-                run_directly: sub_matches.get_flag("run-directly")
+                normal_name,
+                ignore_holds: sub_matches.get_flag("ignore-holds"),
+                release_holds: sub_matches.get_one::<String>("release-holds").cloned(),
+                min_keep: *sub_matches.get_one::<u32>("min-keep").unwrap(),
+                allow_delete_all: sub_matches.get_flag("allow-delete-all"),
+                now: sub_matches.get_one::<chrono::DateTime<chrono::Utc>>("now").copied(),
+                run_directly: sub_matches.get_flag("run-directly"),
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                keep_yearly,
+                keep_since,
+                keep,
+                exclude,
             };
             retention::apply_retention(&mut machine, &mut ds, opts)
         }
 
         Some(("comm", sub_matches)) => {
-            let (src_machine, src_ds) = parse_spec(sub_matches.get_one::<String>("source").unwrap()).unwrap_or_else(|err| {
+            let (mut src_machine, src_ds) = parse_spec(sub_matches.get_one::<String>("source").unwrap()).unwrap_or_else(|err| {
                 eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("source").unwrap(), err );
                 exit(1);
             });
-            let (dst_machine, dst_ds) = parse_spec(sub_matches.get_one::<String>("destination").unwrap()).unwrap_or_else(|err| {
+            let (mut dst_machine, dst_ds) = parse_spec(sub_matches.get_one::<String>("destination").unwrap()).unwrap_or_else(|err| {
                 eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("destination").unwrap(), err);
                 exit(1);
             });
+            configure_machine(&mut src_machine, sub_matches);
+            configure_machine(&mut dst_machine, sub_matches);
+            configure_src_dst_zfs_binary(&mut src_machine, &mut dst_machine, sub_matches);
+            let exclude = parse_exclude(sub_matches).unwrap_or_else(|err| {
+                eprintln!("{:#}", err);
+                exit(1);
+            });
             let opts = CommOpts {
                 order_asc: !sub_matches.get_flag("reverse-sort"),
                 collapse: sub_matches.get_flag("collapse"),
-                collapse_keep_both_ends: sub_matches.get_flag("collapse-keep-both-ends")
+                collapse_keep_both_ends: sub_matches.get_flag("collapse-keep-both-ends"),
+                format: match sub_matches.get_one::<String>("format").unwrap().as_str() {
+                    "json" => OutputFormat::Json,
+                    _ => OutputFormat::Text,
+                },
+                sort_by: parse_sort_by(sub_matches),
+                right_from_file: sub_matches.get_one::<String>("right-from-file").cloned(),
+                max_snapshots: sub_matches.get_one::<usize>("max-snapshots").copied(),
+                depth: sub_matches.get_one::<u32>("depth").copied(),
+                window: parse_date_window(sub_matches),
+                diverged_only: sub_matches.get_flag("diverged-only"),
+                color: match sub_matches.get_one::<String>("color").unwrap().as_str() {
+                    "always" => true,
+                    "never" => false,
+                    _ => std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+                },
+                exclude,
+                show_dates: sub_matches.get_flag("show-dates") || sub_matches.contains_id("date-format"),
+                date_format: sub_matches.get_one::<String>("date-format").cloned(),
+                show_guids: sub_matches.get_flag("show-guids"),
+                only: match (sub_matches.get_flag("only-left"), sub_matches.get_flag("only-common"), sub_matches.get_flag("only-right")) {
+                    (true, _, _) => Some(Comm::LEFT),
+                    (_, true, _) => Some(Comm::BOTH),
+                    (_, _, true) => Some(Comm::RIGHT),
+                    _ => None,
+                },
             };
             comm::comm_cli(src_machine, src_ds, dst_machine, dst_ds, opts)
         }
 
+        Some(("list", sub_matches)) => {
+            let (mut machine, ds) = parse_spec(sub_matches.get_one::<String>("dataset").unwrap()).unwrap_or_else(|err| {
+                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("dataset").unwrap(), err);
+                exit(1);
+            });
+            configure_machine(&mut machine, sub_matches);
+            let opts = ListOpts {
+                order_asc: !sub_matches.get_flag("reverse-sort"),
+                format: match sub_matches.get_one::<String>("format").unwrap().as_str() {
+                    "json" => OutputFormat::Json,
+                    _ => OutputFormat::Text,
+                },
+                sort_by: parse_sort_by(sub_matches),
+                depth: sub_matches.get_one::<u32>("depth").copied(),
+                window: parse_date_window(sub_matches),
+            };
+            list::list_cli(machine, ds, opts)
+        }
+
+        Some(("plan", sub_matches)) => {
+            let (mut src_machine, src_ds) = parse_spec(sub_matches.get_one::<String>("source").unwrap()).unwrap_or_else(|err| {
+                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("source").unwrap(), err);
+                exit(1);
+            });
+            let (mut dst_machine, dst_ds) = parse_spec(sub_matches.get_one::<String>("destination").unwrap()).unwrap_or_else(|err| {
+                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("destination").unwrap(), err);
+                exit(1);
+            });
+            configure_machine(&mut src_machine, sub_matches);
+            configure_machine(&mut dst_machine, sub_matches);
+            configure_src_dst_zfs_binary(&mut src_machine, &mut dst_machine, sub_matches);
+            let format = match sub_matches.get_one::<String>("format").unwrap().as_str() {
+                "json" => OutputFormat::Json,
+                _ => OutputFormat::Text,
+            };
+            plan::plan_cli(src_machine, src_ds, dst_machine, dst_ds, format)
+        }
+
+        Some(("abort-resume", sub_matches)) => {
+            let (mut machine, ds) = parse_spec(sub_matches.get_one::<String>("dataset").unwrap()).unwrap_or_else(|err| {
+                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("dataset").unwrap(), err);
+                exit(1);
+            });
+            configure_machine(&mut machine, sub_matches);
+            abort_resume::abort_resume_cli(&machine, &ds, sub_matches.get_flag("dry-run"))
+        }
+
+        Some(("send-to-file", sub_matches)) => {
+            let (mut machine, mut ds) = parse_spec(sub_matches.get_one::<String>("source").unwrap()).unwrap_or_else(|err| {
+                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("source").unwrap(), err);
+                exit(1);
+            });
+            configure_machine(&mut machine, sub_matches);
+            let opts = file_transfer::SendToFileOpts {
+                from: sub_matches.get_one::<String>("from").cloned(),
+                simple_incremental: sub_matches.get_flag("simple-incremental"),
+                send_flags: SendFlags { raw: sub_matches.get_flag("raw"), ..SendFlags::default() },
+                progress: match std::io::stderr().is_terminal() {
+                    true => ProgressFormat::Bars,
+                    false => ProgressFormat::Quiet,
+                },
+            };
+            let output = sub_matches.get_one::<String>("output").unwrap();
+            let result = file_transfer::send_to_file_cli(&machine, &mut ds, output, opts);
+            // The stream went to stdout: our say goes to stderr instead.
+            if output == "-" {
+                match result {
+                    Ok(msg) => {
+                        eprintln!("{}", msg);
+                        exit(0);
+                    }
+                    Err(err) => {
+                        eprintln!("{:#}", err);
+                        exit(if interrupt::interrupted() { 130 } else { 1 });
+                    }
+                }
+            }
+            result
+        }
+
+        Some(("recv-from-file", sub_matches)) => {
+            let (mut machine, mut ds) = parse_spec(sub_matches.get_one::<String>("destination").unwrap()).unwrap_or_else(|err| {
+                eprintln!("Can't parse {} as a valid ZFS dataset: {}", sub_matches.get_one::<String>("destination").unwrap(), err);
+                exit(1);
+            });
+            configure_machine(&mut machine, sub_matches);
+            file_transfer::recv_from_file_cli(&machine, &mut ds, sub_matches.get_one::<String>("input").unwrap(), sub_matches.get_flag("rollback"))
+        }
+
+        Some(("run", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("config").unwrap();
+            let mut jobs = std::fs::read_to_string(path)
+                .context(format!("Unable to read {path}"))
+                .and_then(|content| config::load_config(&content))
+                .unwrap_or_else(|err| {
+                    eprintln!("{:#}", err);
+                    exit(1);
+                });
+            for job in &mut jobs {
+                configure_machine(&mut job.replicate.src_machine, sub_matches);
+                configure_machine(&mut job.replicate.dst_machine, sub_matches);
+            }
+            if sub_matches.get_flag("check") {
+                Ok(format!("{path}: {} job(s), OK.", jobs.len()))
+            } else {
+                config::run_jobs_cli(jobs, chrono::Local::now().time(), sub_matches.get_flag("dry-run"))
+            }
+        }
+
         None => {
             main_parser.print_long_help().unwrap();
             exit(0);
@@ -275,7 +1556,7 @@ Defaults to sending all intervening snapshots between the last snapshot in commo
             println!("{:#}", reason);
 
             // match reason.
-            exit(1);
+            exit(if interrupt::interrupted() { 130 } else { 1 });
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,162 @@
+use anyhow::{bail, Context};
+use serde_json::json;
+use crate::comm::OutputFormat;
+use crate::dataset::{Dataset, Snap, MRCUD::{*}, find_bookmark_base, find_mrcud};
+use crate::machine::Machine;
+use crate::replicate::{count_snaps_after, get_destination_snaps};
+
+/// What replicate would make of a source and a destination, as worked out by [make_plan].
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    /// The [crate::dataset::MRCUD] case, by name; or "DestinationMissing".
+    pub case: &'static str,
+    /// The incremental base: the most recent snapshot in common, or a bookmark of it.
+    pub base: Option<Snap>,
+    pub base_is_bookmark: bool,
+    /// How many snapshots replicate would send, including the full one to initialize with.
+    pub snapshots_to_send: usize,
+    /// The options replicate would need to go ahead at all.
+    pub requires: Vec<&'static str>,
+}
+
+/// Compares `src_ds` and `dst_ds` the way replicate would, and reports what it would do, without
+/// sending anything. Read-only.
+pub fn plan_cli(
+    src_machine: Machine,
+    mut src_ds: Dataset,
+    dst_machine: Machine,
+    mut dst_ds: Dataset,
+    format: OutputFormat,
+) -> Result<String, anyhow::Error> {
+    dst_ds.append_relative(&src_ds);
+    src_machine.get_snaps(&mut src_ds).context(format!(r#"Unable to get snapshots for "{src_machine}:{src_ds}"."#))?;
+    if src_ds.snaps.is_empty() {
+        bail!(r#"Source dataset "{src_machine}:{src_ds}" has no snapshots to send."#);
+    }
+    let dst_exists = get_destination_snaps(&dst_machine, &mut dst_ds)?;
+    if dst_exists && matches!(find_mrcud(&src_ds, &dst_ds), NoneInCommon) {
+        src_machine.get_bookmarks(&mut src_ds).context(format!(r#"Unable to get bookmarks for "{src_machine}:{src_ds}"."#))?;
+    }
+    let plan = make_plan(&src_ds, &dst_ds, dst_exists);
+    let source = format!("{src_machine}:{src_ds}");
+    let destination = format!("{dst_machine}:{dst_ds}");
+    return Ok(match format {
+        OutputFormat::Json => json!({
+            "source": source,
+            "destination": destination,
+            "case": plan.case,
+            "base": plan.base.as_ref().map(|base| json!({"name": base.name, "guid": base.guid, "bookmark": plan.base_is_bookmark})),
+            "snapshots_to_send": plan.snapshots_to_send,
+            "needs_init": !dst_exists,
+            "requires": plan.requires,
+        }).to_string(),
+        OutputFormat::Text => render_plan(&plan, &source, src_ds.snaps.len(), &destination, dst_exists.then_some(dst_ds.snaps.len())),
+    });
+}
+
+/// Works out what replicate would do from `src_ds` to `dst_ds`, whose snapshots (and, if they have
+/// none in common, the source's bookmarks) have been fetched already.
+pub fn make_plan(src_ds: &Dataset, dst_ds: &Dataset, dst_exists: bool) -> Plan {
+    if !dst_exists {
+        return Plan {
+            case: "DestinationMissing",
+            base: None,
+            base_is_bookmark: false,
+            snapshots_to_send: src_ds.snaps.len(),
+            requires: vec!["--init"],
+        };
+    }
+    let plan = |case, base: &Snap, snapshots_to_send, requires| Plan {
+        case, base: Some(base.clone()), base_is_bookmark: false, snapshots_to_send, requires,
+    };
+    match find_mrcud(src_ds, dst_ds) {
+        UpToDate(mrc) => plan("UpToDate", mrc, 0, vec![]),
+        SourceHasMore(mrc) => plan("SourceHasMore", mrc, count_snaps_after(src_ds, mrc), vec![]),
+        // Nothing to send, short of taking a snapshot first, with which they'd diverge.
+        DestinationHasMore(mrc) => plan("DestinationHasMore", mrc, 1, vec!["--take-snap-now", "--allow-divergent-destination", "--rollback"]),
+        Divergence(mrc) => plan("Divergence", mrc, count_snaps_after(src_ds, mrc), vec!["--allow-divergent-destination", "--rollback"]),
+        NoneInCommon => match find_bookmark_base(src_ds, dst_ds) {
+            // zfs-send doesn't take -I from a bookmark: only the newest snapshot would be sent.
+            Some(bookmark) => Plan {
+                case: "NoneInCommon",
+                base: Some(bookmark.clone()),
+                base_is_bookmark: true,
+                snapshots_to_send: 1,
+                requires: match dst_ds.newest_snap().guid == bookmark.guid {
+                    true => vec![],
+                    false => vec!["--allow-divergent-destination", "--rollback"],
+                },
+            },
+            None => Plan {
+                case: "NoneInCommon",
+                base: None,
+                base_is_bookmark: false,
+                snapshots_to_send: src_ds.snaps.len(),
+                requires: vec!["--force-full"],
+            },
+        },
+    }
+}
+
+fn render_plan(plan: &Plan, source: &str, src_count: usize, destination: &str, dst_count: Option<usize>) -> String {
+    let mut lines = vec![
+        format!("Source:       {source} ({src_count} snapshot(s))"),
+        match dst_count {
+            Some(dst_count) => format!("Destination:  {destination} ({dst_count} snapshot(s))"),
+            None => format!("Destination:  {destination} (does not exist)"),
+        },
+        format!("Case:         {}", plan.case),
+    ];
+    match &plan.base {
+        Some(base) if plan.base_is_bookmark => lines.push(format!("Base:         bookmark #{base} (guid {})", base.guid)),
+        Some(base) => lines.push(format!("Base:         {base} (guid {})", base.guid)),
+        None => lines.push("Base:         none".to_string()),
+    }
+    lines.push(format!("To send:      {} snapshot(s)", plan.snapshots_to_send));
+    if !plan.requires.is_empty() {
+        lines.push(format!("Requires:     {}", plan.requires.join(" ")));
+    }
+    lines.join("\n")
+}
+
+#[test]
+fn test_make_plan() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let zelda_webdata = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata.list"));
+    let plan = make_plan(&tank_webdata, &zelda_webdata, true);
+    assert_eq!(plan.case, "SourceHasMore");
+    let base = plan.base.as_ref().unwrap();
+    assert_eq!(plan.snapshots_to_send, count_snaps_after(&tank_webdata, base));
+    assert!(plan.snapshots_to_send > 0);
+    assert!(plan.requires.is_empty());
+    assert_eq!(make_plan(&tank_webdata, &tank_webdata, true).case, "UpToDate");
+    let plan = make_plan(&zelda_webdata, &tank_webdata, true);
+    assert_eq!((plan.case, plan.snapshots_to_send), ("DestinationHasMore", 1));
+    assert_eq!(plan.requires, ["--take-snap-now", "--allow-divergent-destination", "--rollback"]);
+
+    let zelda_divergence = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata-divergence.list"));
+    let plan = make_plan(&tank_webdata, &zelda_divergence, true);
+    assert_eq!(plan.case, "Divergence");
+    assert_eq!(plan.requires, ["--allow-divergent-destination", "--rollback"]);
+
+    let plan = make_plan(&tank_webdata, &Dataset::with_snaps_from_listing("zelda/webdata", "").unwrap(), false);
+    let init = replicate_long("init-nonexistent-destination");
+    assert_eq!((plan.case, plan.base.as_ref(), plan.base_is_bookmark, plan.snapshots_to_send), ("DestinationMissing", None, false, 82));
+    assert_eq!(plan.requires, [init.as_str()]);
+    let text = render_plan(&plan, "localhost:tank/webdata", 82, "localhost:zelda/webdata", None);
+    assert!(text.contains("(does not exist)"));
+    assert!(text.ends_with(&format!("Requires:     {init}")));
+    assert_eq!(replicate_long("allow-divergent-destination"), "--allow-divergent-destination");
+    assert_eq!(replicate_long("rollback"), "--rollback");
+    assert_eq!(replicate_long("take-snap-now"), "--take-snap-now");
+    assert_eq!(replicate_long("force-full"), "--force-full");
+}
+
+/// The long flag of replicate's argument `id`, as the user types it.
+#[cfg(test)]
+fn replicate_long(id: &str) -> String {
+    let replicate = crate::replicate_command();
+    let arg = replicate.get_arguments().find(|arg| arg.get_id() == id).unwrap();
+    format!("--{}", arg.get_long().unwrap())
+}
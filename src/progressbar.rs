@@ -1,44 +1,146 @@
-use std::io::{BufRead, BufReader};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::json;
+use thiserror::Error;
 
-/// Draw a progress bar by consuming the diagnostic output of `zfs send -vP`
+/// How to report the progress of a transfer, by consuming the diagnostic output of `zfs send -vP`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProgressFormat {
+    /// Progress bars; see [do_progressbar_from_zfs_send_stderr].
+    Bars,
+    /// Nothing at all; see [drain_zfs_send_stderr].
+    Quiet,
+    /// JSON lines on stdout; see [emit_progress_json_from_zfs_send_stderr].
+    Json,
+}
+
+/// Consume the diagnostic output of `zfs send -vP` without drawing anything, so that zfs-send
+/// doesn't block on a full pipe. Error messages (which zfs prefixes with "cannot") are passed on.
+/// Returns the total size announced by zfs-send.
+pub fn drain_zfs_send_stderr<R: std::io::Read>(stream: R) -> u64 {
+    let mut total_size = 0;
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { continue };
+        if is_foreign_line(&line) {
+            eprintln!("{}", line);
+        }
+        if let Some(size) = line.strip_prefix("size\t") {
+            total_size = size.parse().unwrap_or(0);
+        }
+    }
+    total_size
+}
+
+/// Whether `line`, from the stderr of `zfs send -vP`, is none of what we parse or expect there;
+/// e.g. an error message from zfs (which it prefixes with "cannot"), or from ssh(1). Those are
+/// for the user to see.
+fn is_foreign_line(line: &str) -> bool {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    let expected = match fields[..] {
+        [""] => true,
+        ["full" | "incremental" | "size", ..] => true,
+        [_, xfer, _] => xfer.parse::<u64>().is_ok(),
+        // The dump of the resume token contents that `zfs send -vP -t <token>` prints.
+        ["", ..] => true,
+        [first] => first == "resume token contents:" || first.starts_with("nvlist version:"),
+        _ => false,
+    };
+    !expected
+}
+
+/// A header of `zfs send -vP` output which [parse_send_header] can't make sense of.
+#[derive(Error, Debug)]
+pub enum ProgressParseError {
+    #[error("zfs-send's output ended before announcing the total size; it probably failed.")]
+    NoTotalSize,
+    #[error("Unexpected line in zfs-send's output: {0:?}")]
+    IllegalLine(String),
+    #[error("Unable to read zfs-send's output: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Consumes the header of the diagnostic output of `zfs send -vP`, up to and including the line
+/// with the total size. Returns the name and size of every stream to be sent, and the total size.
 /// Samples of this output are included for developer reference under /misc.
-pub fn do_progressbar_from_zfs_send_stderr<R: std::io::Read>(stream: R, ) {
-    // Buffer the stderr stream to take advantage of line-oriented processing.
-    let mut stream = BufReader::new(stream);
-    // Process headers
+pub fn parse_send_header<R: BufRead>(r: &mut R) -> Result<(Vec<(String, u64)>, u64), ProgressParseError> {
     // itemized_header_lines = vec![
-    //     ("second", 525195304),
-    //     ("third", 574823742),
+    //     ("test1/webdata@second", 525195304),
+    //     ("test1/webdata@third", 574823742),
     //     [...]
     // ]
     let mut itemized_header_lines = Vec::new();
-    let total_size : u64 = loop {
+    loop {
         let mut tmpline = String::new();
-        let line = stream.read_line(&mut tmpline).unwrap();
-        assert_ne!(line, 0);
-        tmpline.truncate(tmpline.len() - 1);  // Strip line break in-place.
-        let fields = tmpline.split("\t").collect::<Vec<_>>();
-        if fields[0] == "size" {
-            // We've stumbled upon the final header line, which contains the total size of the
-            // stream to be sent.
-            break fields[1].parse().unwrap();
+        if r.read_line(&mut tmpline)? == 0 {
+            // zfs-send went away before telling the total size; e.g. because it failed.
+            return Err(ProgressParseError::NoTotalSize);
         }
-        let to;
-        let size;
-        match fields[0] {
-            "full" => {to = fields[1]; size = fields[2];}
-            "incremental" => {to = fields[2]; size = fields[3];}
-            _ => unimplemented!("Unknown form of `zfs send -vP` output.")
-        }
-        let _from = fields[1].to_owned();
-        let to = to.split("@").last().unwrap().to_owned();
-        let size : u64 = size.parse().unwrap();
-        itemized_header_lines.push((to, size));
-    };
+        let line = tmpline.trim_end_matches('\n');
+        let illegal = || ProgressParseError::IllegalLine(line.to_owned());
+        let fields = line.split('\t').collect::<Vec<_>>();
+        // Keep the full dataset@snapshot name: with `zfs send -R`, the same snapshot name appears
+        // once for each descendant dataset.
+        let (to, size) = match fields[..] {
+            // The final header line, with the total size of the stream to be sent.
+            ["size", total] => {
+                let total_size = total.parse().map_err(|_| illegal())?;
+                return Ok((itemized_header_lines, total_size));
+            }
+            ["full", to, size] => (to, size),
+            ["incremental", _from, to, size] => (to, size),
+            ["size" | "full" | "incremental", ..] => return Err(illegal()),
+            // Anything else precedes the header proper; e.g. the dump of the resume token contents
+            // that `zfs send -vP -t <token>` prints, or an error.
+            _ => {
+                if is_foreign_line(line) {
+                    eprintln!("{}", line);
+                }
+                continue
+            },
+        };
+        let size : u64 = size.parse().map_err(|_| illegal())?;
+        itemized_header_lines.push((to.to_owned(), size));
+    }
+}
+
+/// Draw a progress bar by consuming the diagnostic output of `zfs send -vP`
+/// Samples of this output are included for developer reference under /misc.
+/// Returns the number of bytes sent: the total size announced by zfs-send, or more, if the
+/// progress updates went over it. `rate_cap` is the rate limit (in bytes per second) in effect, if
+/// any, for the overall bar to show next to the actual rate.
+///
+/// The bars are, from top to bottom: the snapshot being sent, out of how many; the bytes of the
+/// whole transfer; those of the snapshot being sent. Those bytes are as zfs-send counts them:
+/// logical ones, before the compression of `-c` (and of --transport-compress) is taken off, so
+/// they go by faster than the link would seem to allow. Given `wire_bytes`, a counter of the bytes
+/// which actually went down the pipe (see [crate::replicate]'s relay), a last line shows those,
+/// and the rate at which they do.
+pub fn do_progressbar_from_zfs_send_stderr<R: std::io::Read>(stream: R, rate_cap: Option<u64>, wire_bytes: Option<Arc<AtomicU64>>) -> u64 {
+    // Buffer the stderr stream to take advantage of line-oriented processing.
+    let mut stream = BufReader::new(stream);
+    let (itemized_header_lines, total_size) = parse_send_header(&mut stream).unwrap_or_else(|err| {
+        log::warn!("{err}");
+        (Vec::new(), 0)
+    });
+
+    if total_size == 0 || itemized_header_lines.is_empty() {
+        // Nothing to send; e.g. an incremental between two snapshots with no changes in between.
+        let pb = ProgressBar::new(0);
+        pb.set_style(ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan} {bytes:>12}/{total_bytes:<12}"
+        ).unwrap().progress_chars("##-"));
+        pb.finish();
+        // Keep reading, lest zfs-send block on a full pipe.
+        for _ in stream.lines() {}
+        return 0;
+    }
 
     let mut cur_xfer = 0;
     let mut cur_idx = 0;
+    let mut sent = 0;  // Like pb_total_bytes' position, which is capped at its length, but isn't.
     let mut cur_snap_name = itemized_header_lines[0].0.clone();
     let mut cur_snap_bytes = itemized_header_lines[0].1;
     // let mut cur_iter = itemized_header_lines.into_iter();
@@ -51,36 +153,57 @@ pub fn do_progressbar_from_zfs_send_stderr<R: std::io::Read>(stream: R, ) {
     pb_total_items.set_style(ProgressStyle::with_template(
         "Sending snapshot {pos} of {len}:"
     ).unwrap());
-    pb_total_bytes.set_style(ProgressStyle::with_template(
-        "[{elapsed_precise}] {bar:40.cyan} {bytes:>12}/{total_bytes:<12} {binary_bytes_per_sec}"
-    ).unwrap().progress_chars("##-"));
+    // The ETA is for the whole transfer, which this bar accounts for, jumps between snapshots and all.
+    pb_total_bytes.set_style(ProgressStyle::with_template(&total_bytes_template(rate_cap)).unwrap().progress_chars("##-"));
     pb_current_bytes.set_style(ProgressStyle::with_template(
         "[{elapsed_precise}] {bar:40.cyan} {bytes:>12}/{total_bytes:<12} {binary_bytes_per_sec}"
     ).unwrap().progress_chars("##-"));
+    let pb_wire_bytes = wire_bytes.as_ref().map(|_| {
+        let pb = group.add(ProgressBar::no_length());
+        pb.set_style(ProgressStyle::with_template(
+            "[{elapsed_precise}] On the wire:                             {bytes:>12}              {binary_bytes_per_sec}"
+        ).unwrap());
+        pb
+    });
+    let update_wire_bytes = || {
+        if let (Some(pb), Some(wire_bytes)) = (&pb_wire_bytes, &wire_bytes) {
+            pb.set_position(wire_bytes.load(Ordering::Relaxed));
+        }
+    };
 
     for line in stream.lines() {
-        let progress = line.expect("What do you mean, it wasn't UTF-8!?");
-        let fields = progress.split("\t").collect::<Vec<_>>();
-        let name = fields[2].split("@").last().unwrap().to_owned();
-        let xfer: u64 = fields[1].parse().unwrap();
-        assert_eq!(fields.len(), 3);
+        let Ok(progress) = line else { continue };
+        if is_foreign_line(&progress) {
+            group.suspend(|| eprintln!("{}", progress));
+            continue;
+        }
+        let fields = progress.split('\t').collect::<Vec<_>>();
+        let [_, xfer, name] = fields[..] else { continue };
+        let Ok(xfer) = xfer.parse::<u64>() else { continue };
+        update_wire_bytes();
         // Did we move onto a new snapshot, or are we still working the previous one?
         if cur_snap_name != name {
+            // Ignore anything which isn't one of the snapshots announced in the header.
+            if !itemized_header_lines[cur_idx+1..].iter().any(|(n, _)| n == name) {
+                continue;
+            }
             // see how many snapshots we've advanced (probably one, but maybe more)
             // calculate how much total_size bytes we've advanced based on that
-            let delta = cur_snap_bytes - cur_xfer;  // Remainder of the snap we were last working on.
+            // zfs-send's size estimates can fall short, hence the saturating_sub here and below.
+            let delta = cur_snap_bytes.saturating_sub(cur_xfer);  // Remainder of the snap we were last working on.
             pb_current_bytes.set_position(0);
             pb_current_bytes.reset();
             pb_total_bytes.inc(delta);
+            sent += delta;
             pb_total_items.inc(1);
             cur_idx += 1;
             // Search which snap we're on now.
             // Any snap that doesn't match the name has been sent in full and must be accounted.
             while itemized_header_lines[cur_idx].0 != name {
                 pb_total_bytes.inc(itemized_header_lines[cur_idx].1);
+                sent += itemized_header_lines[cur_idx].1;
                 pb_total_items.inc(1);
                 cur_idx += 1;
-                assert!(cur_idx < itemized_header_lines.len());
             }
             // we've found the work item we're looking for. Update state to reflect we're working
             // on this snapshot now.
@@ -92,12 +215,14 @@ pub fn do_progressbar_from_zfs_send_stderr<R: std::io::Read>(stream: R, ) {
             pb_current_bytes.set_length(cur_snap_bytes);
             pb_current_bytes.set_position(xfer);
             pb_total_bytes.inc(xfer);
+            sent += xfer;
             cur_xfer = xfer;
         }
         else {
-            let delta = xfer - cur_xfer;
+            let delta = xfer.saturating_sub(cur_xfer);
             pb_current_bytes.inc(delta);
             pb_total_bytes.inc(delta);
+            sent += delta;
             pb_total_items.tick();
             cur_xfer = xfer;
         }
@@ -105,4 +230,207 @@ pub fn do_progressbar_from_zfs_send_stderr<R: std::io::Read>(stream: R, ) {
     pb_total_items.finish();
     pb_total_bytes.finish();
     pb_current_bytes.finish();
-}
\ No newline at end of file
+    update_wire_bytes();
+    if let Some(pb) = &pb_wire_bytes {
+        pb.finish();
+    }
+    // The last progress update predates the end of the transfer by up to a second.
+    let sent = sent.max(total_size);
+    match &wire_bytes {
+        Some(wire_bytes) => eprintln!("{} {} of it on the wire.", transfer_summary(sent, pb_total_bytes.elapsed()), HumanBytes(wire_bytes.load(Ordering::Relaxed))),
+        None => eprintln!("{}", transfer_summary(sent, pb_total_bytes.elapsed())),
+    }
+    return sent;
+}
+
+/// The template of the overall bar; which, under a rate limit, shows it next to the actual rate, so
+/// that it's plain to see whether it's the limit or the link that holds the transfer back.
+fn total_bytes_template(rate_cap: Option<u64>) -> String {
+    let cap = match rate_cap {
+        Some(rate_cap) => format!(" (cap: {}/s)", HumanBytes(rate_cap)),
+        None => String::new(),
+    };
+    format!("[{{elapsed_precise}}] {{bar:40.cyan}} {{bytes:>12}}/{{total_bytes:<12}} {{binary_bytes_per_sec}}{cap} ETA {{eta_precise}}")
+}
+
+fn transfer_summary(sent: u64, elapsed: Duration) -> String {
+    let rate = sent as f64 / elapsed.as_secs_f64().max(1.0);
+    format!("Sent {} in {}, at {}/s on average.", HumanBytes(sent), HumanDuration(elapsed), HumanBytes(rate as u64))
+}
+
+/// Print one JSON object per progress update of `zfs send -vP`, on stdout, for external monitors:
+/// `{"snapshot": name, "sent_bytes": x, "snap_total": y, "overall_sent": z, "overall_total": t}`
+/// Returns the number of bytes sent, like [do_progressbar_from_zfs_send_stderr].
+pub fn emit_progress_json_from_zfs_send_stderr<R: std::io::Read>(stream: R) -> u64 {
+    write_progress_json(stream, std::io::stdout())
+}
+
+fn write_progress_json<R: std::io::Read, W: Write>(stream: R, mut out: W) -> u64 {
+    let mut stream = BufReader::new(stream);
+    let (itemized_header_lines, total_size) = parse_send_header(&mut stream).unwrap_or_else(|err| {
+        log::warn!("{err}");
+        (Vec::new(), 0)
+    });
+    let mut cur_idx = 0;
+    let mut sent_before_cur = 0;  // Sum of the sizes of the snapshots already sent in full.
+    let mut overall_sent = 0;
+    for line in stream.lines() {
+        let Ok(progress) = line else { continue };
+        if is_foreign_line(&progress) {
+            eprintln!("{}", progress);
+            continue;
+        }
+        let fields = progress.split('\t').collect::<Vec<_>>();
+        let [_, xfer, name] = fields[..] else { continue };
+        let Ok(xfer) = xfer.parse::<u64>() else { continue };
+        // Skip ahead to the snapshot being sent; any skipped over has been sent in full.
+        let Some(offset) = itemized_header_lines[cur_idx..].iter().position(|(n, _)| n == name) else { continue };
+        sent_before_cur += itemized_header_lines[cur_idx..cur_idx + offset].iter().map(|(_, size)| size).sum::<u64>();
+        cur_idx += offset;
+        overall_sent = sent_before_cur + xfer;
+        let event = json!({
+            "snapshot": name,
+            "sent_bytes": xfer,
+            "snap_total": itemized_header_lines[cur_idx].1,
+            "overall_sent": overall_sent,
+            "overall_total": total_size,
+        });
+        // Whoever reads this may have gone away; keep draining zfs-send's output regardless.
+        let _ = writeln!(out, "{}", event);
+        let _ = out.flush();
+    }
+    overall_sent.max(total_size)
+}
+
+#[test]
+fn test_write_progress_json() {
+    let mut out = Vec::new();
+    let sent = write_progress_json(include_str!("../misc/zfs-send-vP-intervening-1").as_bytes(), &mut out);
+    assert_eq!(sent, 1628116184);
+    let events = String::from_utf8(out).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(events.len(), 13);
+    assert_eq!(events[0], json!({
+        "snapshot": "test1/webdata@second",
+        "sent_bytes": 105109248,
+        "snap_total": 525195304,
+        "overall_sent": 105109248,
+        "overall_total": 1628116184u64,
+    }));
+    // Both @second and @third have been sent in full by now.
+    assert_eq!(events[12]["snapshot"], "test1/webdata@fourth");
+    assert_eq!(events[12]["overall_sent"], 525195304u64 + 525197352 + 417151304);
+}
+
+#[test]
+fn test_progressbar_nothing_to_send() {
+    assert_eq!(do_progressbar_from_zfs_send_stderr(include_str!("../misc/zfs-send-vP-zero-size-1").as_bytes(), None, None), 0);
+    assert_eq!(do_progressbar_from_zfs_send_stderr("incremental\tsecond\ttest1/webdata@third\t0\nsize\t0\n".as_bytes(), None, None), 0);
+    // zfs-send failed before printing any header at all.
+    assert_eq!(do_progressbar_from_zfs_send_stderr("".as_bytes(), None, None), 0);
+    // Transfers can overshoot the estimates in the header.
+    let sent = do_progressbar_from_zfs_send_stderr(
+        "full\ttest1/webdata@first\t100\nsize\t100\n15:50:03\t150\ttest1/webdata@first\n15:50:04\t120\ttest1/webdata@first\n".as_bytes(), None, None
+    );
+    assert_eq!(sent, 150);
+}
+
+#[test]
+fn test_progress_full_send() {
+    // What every first run goes through: a lone "full" stream, whose name never changes.
+    let sample = include_str!("../misc/zfs-send-vP-full-1");
+    // The sample stops short of the end of the transfer, as zfs-send's last update may well do.
+    assert_eq!(do_progressbar_from_zfs_send_stderr(sample.as_bytes(), None, None), 1575576472);
+    let mut out = Vec::new();
+    assert_eq!(write_progress_json(sample.as_bytes(), &mut out), 1575576472);
+    let events = String::from_utf8(out).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(events.len(), 4);
+    for event in &events {
+        assert_eq!(event["snapshot"], "test1/webdata@fourth");
+        assert_eq!(event["overall_sent"], event["sent_bytes"]);
+        assert_eq!(event["snap_total"], 1575576472u64);
+    }
+    assert_eq!(events[3]["sent_bytes"], 42177136u64);
+}
+
+#[test]
+fn test_total_bytes_template() {
+    assert_eq!(total_bytes_template(None), "[{elapsed_precise}] {bar:40.cyan} {bytes:>12}/{total_bytes:<12} {binary_bytes_per_sec} ETA {eta_precise}");
+    assert_eq!(total_bytes_template(Some(50 << 20)), "[{elapsed_precise}] {bar:40.cyan} {bytes:>12}/{total_bytes:<12} {binary_bytes_per_sec} (cap: 50.00 MiB/s) ETA {eta_precise}");
+    assert!(ProgressStyle::with_template(&total_bytes_template(Some(50 << 20))).is_ok());
+    let sample = include_str!("../misc/zfs-send-vP-full-1");
+    assert_eq!(do_progressbar_from_zfs_send_stderr(sample.as_bytes(), Some(50 << 20), None), 1575576472);
+}
+
+#[test]
+fn test_progressbar_wire_bytes() {
+    // A compressed stream: fewer bytes on the wire than zfs-send reports sending.
+    let wire_bytes = Arc::new(AtomicU64::new(1575576472 / 3));
+    let sample = include_str!("../misc/zfs-send-vP-full-1");
+    assert_eq!(do_progressbar_from_zfs_send_stderr(sample.as_bytes(), None, Some(wire_bytes.clone())), 1575576472);
+    assert_eq!(do_progressbar_from_zfs_send_stderr("".as_bytes(), None, Some(wire_bytes)), 0);
+}
+
+#[test]
+fn test_transfer_summary() {
+    assert_eq!(transfer_summary(1628116184, Duration::from_secs(16)), "Sent 1.52 GiB in 16 seconds, at 97.04 MiB/s on average.");
+    // Transfers shorter than a second are reported as if they took one.
+    assert_eq!(transfer_summary(1024, Duration::from_millis(10)), "Sent 1.00 KiB in 0 seconds, at 1.00 KiB/s on average.");
+}
+
+#[test]
+fn test_is_foreign_line() {
+    for sample in [include_str!("../misc/zfs-send-vP-full-1"), include_str!("../misc/zfs-send-vP-intervening-1"),
+                   include_str!("../misc/zfs-send-vP-intervening-2"), include_str!("../misc/zfs-send-vP-intervening-3"),
+                   include_str!("../misc/zfs-send-vP-zero-size-1")] {
+        assert!(sample.lines().all(|line| !is_foreign_line(line)));
+    }
+    assert!(!is_foreign_line("resume token contents:"));
+    assert!(!is_foreign_line("nvlist version: 0"));
+    assert!(!is_foreign_line("\ttoname = test1/webdata@third"));
+    assert!(is_foreign_line("cannot open 'test1/webdata': dataset does not exist"));
+    assert!(is_foreign_line("ssh: connect to host baal port 22: Connection refused"));
+    assert!(is_foreign_line("Warning: Permanently added 'baal' (ED25519) to the list of known hosts."));
+}
+
+#[test]
+fn test_drain_zfs_send_stderr() {
+    assert_eq!(drain_zfs_send_stderr(include_str!("../misc/zfs-send-vP-intervening-1").as_bytes()), 1628116184);
+    assert_eq!(drain_zfs_send_stderr("cannot open 'test1/webdata': dataset does not exist\n".as_bytes()), 0);
+}
+
+#[test]
+fn test_parse_send_header() {
+    let (items, total) = parse_send_header(&mut include_str!("../misc/zfs-send-vP-full-1").as_bytes()).unwrap();
+    assert_eq!(items, [("test1/webdata@fourth".to_string(), 1575576472)]);
+    assert_eq!(total, 1575576472);
+    let mut stream = include_str!("../misc/zfs-send-vP-intervening-1").as_bytes();
+    let (items, total) = parse_send_header(&mut stream).unwrap();
+    assert_eq!(items.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["test1/webdata@second", "test1/webdata@third", "test1/webdata@fourth", "test1/webdata@fifth", "test1/webdata@sixth"]);
+    assert_eq!(items.iter().map(|(_, size)| size).sum::<u64>(), total);
+    // Only the header is consumed; the progress updates are left for the caller.
+    assert!(stream.starts_with(b"15:50:03\t"));
+    for (sample, count) in [(include_str!("../misc/zfs-send-vP-intervening-2"), 5), (include_str!("../misc/zfs-send-vP-intervening-3"), 3)] {
+        let (items, total) = parse_send_header(&mut sample.as_bytes()).unwrap();
+        assert_eq!(items.len(), count);
+        assert_eq!(items.iter().map(|(_, size)| size).sum::<u64>(), total);
+    }
+    assert_eq!(parse_send_header(&mut include_str!("../misc/zfs-send-vP-zero-size-1").as_bytes()).unwrap(), (vec![], 0));
+    // Anything before the header proper is skipped.
+    let (items, _) = parse_send_header(&mut "resume token contents:\nnvlist version: 0\nfull\ttest1/webdata@a\t5\nsize\t5\n".as_bytes()).unwrap();
+    assert_eq!(items.len(), 1);
+}
+
+#[test]
+fn test_parse_send_header_malformed() {
+    assert!(matches!(parse_send_header(&mut "".as_bytes()), Err(ProgressParseError::NoTotalSize)));
+    assert!(matches!(parse_send_header(&mut "cannot open 'test1/webdata': dataset does not exist\n".as_bytes()), Err(ProgressParseError::NoTotalSize)));
+    assert!(matches!(parse_send_header(&mut "full\ttest1/webdata@a\tlots\nsize\t5\n".as_bytes()), Err(ProgressParseError::IllegalLine(_))));
+    assert!(matches!(parse_send_header(&mut "full\ttest1/webdata@a\n".as_bytes()), Err(ProgressParseError::IllegalLine(_))));
+    assert!(matches!(parse_send_header(&mut "size\t-1\n".as_bytes()), Err(ProgressParseError::IllegalLine(_))));
+}
@@ -0,0 +1,45 @@
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RateParseError {
+    #[error("empty rate.")]
+    Empty,
+    #[error("{0}: only the suffixes K, M, G and T are allowed.")]
+    IllegalSuffix(String),
+    #[error("{0}: not a non-negative integer, optionally followed by K, M, G or T.")]
+    IllegalNumber(String),
+    #[error("{0}: too large.")]
+    TooLarge(String),
+}
+
+/// Parses a rate (or size) as taken by `pv -L` and `mbuffer -r`/`-m`: a non-negative integer,
+/// optionally followed by one of the K, M, G or T suffixes, which are powers of 1024.
+/// Returns its value in bytes (per second).
+pub fn parse_rate(rate: &str) -> Result<u64, RateParseError> {
+    let suffixes = "KMGT";
+    let last = rate.chars().last().ok_or(RateParseError::Empty)?;
+    let (num, multiplier) = if last.is_numeric() {
+        (rate, 1)
+    } else {
+        let Some(power) = suffixes.find(last) else {
+            return Err(RateParseError::IllegalSuffix(rate.into()));
+        };
+        (&rate[..rate.len()-1], 1u64 << (10 * (power + 1)))
+    };
+    let num = num.parse::<u64>().map_err(|_| RateParseError::IllegalNumber(rate.into()))?;
+    return num.checked_mul(multiplier).ok_or_else(|| RateParseError::TooLarge(rate.into()));
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(parse_rate("1234M"), Ok(1234 * 1024 * 1024));
+    assert!(matches!(parse_rate("1234j"), Err(RateParseError::IllegalSuffix(_))));
+    assert!(matches!(parse_rate("-1234M"), Err(RateParseError::IllegalNumber(_))));
+    assert_eq!(parse_rate("50M"), Ok(50 * 1024 * 1024));
+    assert_eq!(parse_rate("50"), Ok(50));
+    assert_eq!(parse_rate("1K"), Ok(1024));
+    assert_eq!(parse_rate("2T"), Ok(2 << 40));
+    assert_eq!(parse_rate(""), Err(RateParseError::Empty));
+    assert!(matches!(parse_rate("M"), Err(RateParseError::IllegalNumber(_))));
+    assert!(matches!(parse_rate("99999999T"), Err(RateParseError::TooLarge(_))));
+}
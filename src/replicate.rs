@@ -1,99 +1,825 @@
+use std::collections::{HashSet, VecDeque};
 use std::fmt::Debug;
-use std::process::{Child, Command, Stdio};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use anyhow::{anyhow, bail, Context};
-use crate::machine::{Machine, MachineError};
-use crate::dataset::{Dataset, find_mrcud};
+use itertools::Itertools;
+use indicatif::HumanBytes;
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use crate::confirm::confirm;
+use crate::hooks::run_hook;
+use crate::interrupt;
+use crate::rate::parse_rate;
+use crate::machine::{Location, Machine, MachineError, RecvFlags, RecvPathRemap, SendFlags, classify_zfs_error, local_cmd_without_shell};
+use crate::dataset::{Dataset, Snap, Comm, find_mrcud, find_bookmark_base, render_tagged_snaps_for_deletion};
 use crate::dataset::MRCUD::*;
-use crate::progressbar::do_progressbar_from_zfs_send_stderr;
+use crate::progressbar::{ProgressFormat, do_progressbar_from_zfs_send_stderr, drain_zfs_send_stderr, emit_progress_json_from_zfs_send_stderr, parse_send_header};
 
-#[derive(Clone, Debug)]
+/// What a job of the config file (see [crate::config]) may set goes by the same names, in TOML.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ReplicateDatasetOpts {
     pub use_rollback_flag_on_recv: bool,
     pub allow_divergent_destination: bool,
     pub init_nonexistent_destination: bool,
+    /// When initializing the destination, send the newest snapshot in full, rather than the oldest
+    /// one followed by all the rest; the destination then has none of the history before it.
+    pub init_latest: bool,
+    /// When initializing the destination, create its missing ancestors first (`zfs create -p`),
+    /// rather than failing for lack of them.
+    pub create_parents: bool,
     pub simple_incremental: bool,
-    pub app_verbose: bool,
+    /// Replicate up to this snapshot of the source, rather than up to its newest one; see
+    /// [truncate_until].
+    pub until: Option<String>,
     pub take_snap_now: Option<String>,
-    pub ratelimit: Option<String>
+    /// Take the snapshot above with `zfs snapshot -r`, i.e. of all descendants too, all at once.
+    pub recursive_snap: bool,
+    pub ratelimit: Option<String>,
+    /// What to put between zfs-send and zfs-recv. If None, `pv`, and only if rate-limiting.
+    pub buffer: Option<BufferTool>,
+    /// Where to run the program chosen through `buffer`.
+    pub ratelimit_side: RatelimitSide,
+    /// Compress the stream on the source and decompress it on the destination, so that it crosses
+    /// SSH compressed. Left out if the stream already is (`send_flags.compressed` or `raw`).
+    pub transport_compress: Option<TransportCompression>,
+    /// Properties to override (`zfs recv -o name=value`) or, if without a value, to have inherited
+    /// (`zfs recv -x name`) on the destination.
+    #[serde(skip)]
+    pub recv_properties: Vec<(String, Option<String>)>,
+    /// Don't mount the received filesystem (`zfs recv -u`).
+    pub no_mount: bool,
+    /// Receive without disturbing the destination system: `no_mount`, plus the
+    /// [SAFE_RECV_PROPERTIES] not already in `recv_properties`.
+    pub safe_recv: bool,
+    /// Have zfs-recv name the destination after the source, under the one given (`zfs recv -d`/`-e`).
+    /// The destination is then the name zfs-recv comes up with; see [destination_name].
+    pub recv_path_remap: Option<RecvPathRemap>,
+    #[serde(skip)]
+    pub progress: ProgressFormat,
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// Discard the partial state of an interrupted receive instead of resuming it.
+    pub no_resume: bool,
+    /// With `send_flags.recursive`, snapshots are still only compared on the top-level dataset.
+    /// Descendants created on the source after the incremental base snapshot are sent in full, but
+    /// a descendant which has the base snapshot on the source and is missing on the destination
+    /// (e.g. because earlier runs were not recursive) makes the receive fail; it must be initialized
+    /// on its own first.
+    pub send_flags: SendFlags,
+    /// After the transfer, destroy the destination's snapshots which the source no longer has.
+    pub prune_destination: bool,
+    /// After the transfer, bookmark the source's newest snapshot, so that it keeps serving as
+    /// incremental base even if the source prunes the snapshot itself.
+    pub bookmark_on_send: bool,
+    /// After the transfer, hold the destination's newest snapshot with this tag, and release it
+    /// from the previous incremental base, so that the destination's own pruning can't destroy
+    /// the base for the next run.
+    pub hold_latest: Option<String>,
+    /// Retry this many times, after a while, if a transfer is cut short, picking up where it left.
+    pub retries: u32,
+    /// Fail if either side has more snapshots than this; see [check_max_snapshots].
+    pub max_snapshots: Option<usize>,
+    /// If the destination has snapshots newer than the most recent one in common (whether or not
+    /// it diverges), roll it back to this one first; see [rollback_destination].
+    pub rollback_to: Option<String>,
+    /// Go ahead despite the preflight checks which would otherwise stop the replication.
+    pub force: bool,
+    /// If the destination has no snapshot in common with the source, or diverges from it, destroy
+    /// it and send the source in full instead of failing.
+    pub force_full: bool,
+    /// Once done, check that the destination did end up like the source.
+    pub verify: Option<Verify>,
+    /// Run this (locally) once replicated, or once failed to, respectively; see [run_replicate_hooks].
+    pub on_success: Option<String>,
+    pub on_failure: Option<String>,
+    /// Fail if a hook above does; otherwise, it's only warned about.
+    pub strict_hooks: bool,
+}
+
+impl Default for ReplicateDatasetOpts {
+    /// Same as `zfs-rs replicate` without any options.
+    fn default() -> Self {
+        ReplicateDatasetOpts {
+            use_rollback_flag_on_recv: false,
+            allow_divergent_destination: false,
+            init_nonexistent_destination: false,
+            init_latest: false,
+            create_parents: true,
+            simple_incremental: false,
+            until: None,
+            take_snap_now: None,
+            recursive_snap: false,
+            ratelimit: None,
+            buffer: Some(BufferTool::Pv),
+            ratelimit_side: RatelimitSide::Local,
+            transport_compress: None,
+            recv_properties: Vec::new(),
+            no_mount: false,
+            safe_recv: false,
+            recv_path_remap: None,
+            progress: ProgressFormat::Quiet,
+            dry_run: false,
+            no_resume: false,
+            send_flags: SendFlags::default(),
+            prune_destination: false,
+            bookmark_on_send: false,
+            hold_latest: None,
+            retries: 0,
+            max_snapshots: None,
+            rollback_to: None,
+            force: false,
+            force_full: false,
+            verify: None,
+            on_success: None,
+            on_failure: None,
+            strict_hooks: false,
+        }
+    }
+}
+
+/// A program to place between zfs-send and zfs-recv, for rate-limiting and/or buffering.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BufferTool {
+    /// `pv -q -L <rate>`. Only rate-limits; left out entirely if not rate-limiting.
+    Pv,
+    /// `mbuffer -q -m <size> -s 128k [-r <rate>]`. Smooths over the bursty output of zfs-send,
+    /// which helps a lot over high-latency links.
+    Mbuffer {
+        /// Size of the in-memory buffer, as taken by `mbuffer -m`; e.g. "256M".
+        size: String,
+    },
+}
+
+/// The outcome of [replicate_dataset_cli], for reporting in either plain text or JSON.
+#[derive(Clone, Debug)]
+pub struct ReplicateResult {
+    pub source: String,
+    pub destination: String,
+    /// The incremental base: the most recent snapshot in common, or bookmark. None if the
+    /// destination was initialized through a dry-run full send.
+    pub base: Option<Snap>,
+    pub snapshots_sent: usize,
+    /// As reported by zfs-send; 0 for dry runs.
+    pub bytes_sent: u64,
+    pub status: ReplicateStatus,
+    /// Human-readable summary, for the plain-text output.
+    pub message: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReplicateStatus {
+    Synchronized,
+    UpToDate,
+    DryRun,
+}
+
+impl ReplicateResult {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "source": self.source,
+            "destination": self.destination,
+            "base": self.base.as_ref().map(|snap| json!({"name": snap.name, "guid": snap.guid})),
+            "snapshots_sent": self.snapshots_sent,
+            "bytes_sent": self.bytes_sent,
+            "status": match self.status {
+                ReplicateStatus::Synchronized => "synchronized",
+                ReplicateStatus::UpToDate => "up_to_date",
+                ReplicateStatus::DryRun => "dry_run",
+            },
+        })
+    }
+}
+
+/// Which machine runs the rate-limiting (or buffering) program of a transfer.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RatelimitSide {
+    /// The machine running zfs-rs; in the middle of the pipeline.
+    Local,
+    /// The remote end of the transfer: the destination when pushing, the source when pulling.
+    Remote,
+}
+
+/// How thoroughly to check the destination against the source, after replicating.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verify {
+    /// Both have the same newest snapshot, as identified by its guid, which send/recv preserves.
+    Guid,
+    /// On top of that, `zfs send -nP` estimates the same stream size for it on both.
+    Deep,
+}
+
+/// A compressor to wrap the data stream in while in transit, see [ReplicateDatasetOpts].
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportCompression {
+    Gzip,
+    Zstd,
+}
+
+impl TransportCompression {
+    fn compress_cmd(&self) -> &'static str {
+        match self {
+            TransportCompression::Gzip => "gzip -c",
+            TransportCompression::Zstd => "zstd -q -c",
+        }
+    }
+
+    fn decompress_cmd(&self) -> &'static str {
+        match self {
+            TransportCompression::Gzip => "gunzip -c",
+            TransportCompression::Zstd => "zstdcat -q",
+        }
+    }
+}
+
+/// A failed zfs-send | zfs-recv pipeline.
+#[derive(Error, Debug)]
+#[error("There was a problem with the zfs-send|zfs-recv processes. Exit status: send {send}, recv {recv}{}", render_recv_stderr(.recv_stderr))]
+pub struct PipelineError {
+    send: ExitStatus,
+    recv: ExitStatus,
+    /// The last lines zfs-recv (or ssh(1), on its way) wrote to stderr; see [drain_recv_stderr].
+    recv_stderr: Vec<String>,
+}
+
+impl PipelineError {
+    /// Why zfs-recv failed, as per [classify_zfs_error], if it did.
+    pub fn recv_error(&self) -> Option<MachineError> {
+        if self.recv.success() {
+            return None;
+        }
+        Some(classify_zfs_error(&self.recv_stderr.join("\n"), self.recv.code().unwrap_or(-1)))
+    }
+}
+
+fn render_recv_stderr(lines: &[String]) -> String {
+    lines.iter().map(|line| format!("\n  zfs recv: {line}")).collect()
 }
 
 pub fn replicate_dataset_cli(
+    src_machine : &mut Machine,
+    src_ds : &mut Dataset,
+    dst_machine : &mut Machine,
+    dst_ds: &mut Dataset,
+    opts: ReplicateDatasetOpts,
+) -> Result<ReplicateResult, anyhow::Error> {
+    let hooks = opts.clone();
+    let outcome = replicate_with_retries(src_machine, src_ds, dst_machine, dst_ds, opts);
+    run_replicate_hooks(&format!("{src_machine}:{src_ds}"), &format!("{dst_machine}:{dst_ds}"), outcome, &hooks)
+}
+
+/// Runs `opts.on_success` or `opts.on_failure`, as per `outcome`, with its details in the
+/// environment: ZFS_RS_SOURCE, ZFS_RS_DEST, ZFS_RS_STATUS ("synchronized", "up_to_date", or
+/// "failed"), ZFS_RS_BYTES, ZFS_RS_BASE_SNAP (empty if none), and on failure ZFS_RS_ERROR.
+/// Not on dry runs. A failing hook fails the replication only with `opts.strict_hooks`.
+fn run_replicate_hooks(
+    source: &str,
+    destination: &str,
+    outcome: Result<ReplicateResult, anyhow::Error>,
+    opts: &ReplicateDatasetOpts,
+) -> Result<ReplicateResult, anyhow::Error> {
+    let hook = match &outcome {
+        Ok(_) => &opts.on_success,
+        Err(_) => &opts.on_failure,
+    };
+    let Some(hook) = hook.as_deref().filter(|_| !opts.dry_run) else {
+        return outcome;
+    };
+    let mut env = vec![
+        ("ZFS_RS_SOURCE", source.to_string()),
+        ("ZFS_RS_DEST", destination.to_string()),
+    ];
+    match &outcome {
+        Ok(result) => env.extend([
+            ("ZFS_RS_STATUS", result.to_json()["status"].as_str().unwrap().to_string()),
+            ("ZFS_RS_BYTES", result.bytes_sent.to_string()),
+            ("ZFS_RS_BASE_SNAP", result.base.as_ref().map(|base| base.name.clone()).unwrap_or_default()),
+        ]),
+        Err(err) => env.extend([
+            ("ZFS_RS_STATUS", "failed".to_string()),
+            ("ZFS_RS_BYTES", "0".to_string()),
+            ("ZFS_RS_BASE_SNAP", String::new()),
+            ("ZFS_RS_ERROR", format!("{err:#}")),
+        ]),
+    }
+    let Err(hook_err) = run_hook(hook, &env) else {
+        return outcome;
+    };
+    if !opts.strict_hooks {
+        log::warn!("{hook_err:#}");
+        return outcome;
+    }
+    match outcome {
+        Ok(_) => Err(hook_err.context("Replicated, but the --on-success hook failed (--strict-hooks).")),
+        Err(err) => Err(err.context(format!("Besides, the --on-failure hook failed (--strict-hooks): {hook_err:#}"))),
+    }
+}
+
+fn replicate_with_retries(
     src_machine : &mut Machine,
     src_ds : &mut Dataset,
     dst_machine : &mut Machine,
     dst_ds: &mut Dataset,
     mut opts: ReplicateDatasetOpts,
+) -> Result<ReplicateResult, anyhow::Error> {
+    destination_name(src_ds, dst_ds, opts.recv_path_remap)?;
+    set_up_stream_filters(src_machine, dst_machine, &opts)?;
+    let mut attempt = 0;
+    loop {
+        let err = match replicate_dataset(src_machine, src_ds, dst_machine, dst_ds, opts.clone()) {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        if interrupt::interrupted() {
+            let hint = match dst_machine.get_resume_token(dst_ds) {
+                Ok(Some(_)) => format!(r#" Run zfs-rs again to resume the receive into "{dst_machine}:{dst_ds}" from where it stopped, or abort-resume to discard it."#),
+                _ => String::new(),
+            };
+            bail!("Interrupted; the transfer was stopped.{hint}");
+        }
+        let Some(pipeline_err) = err.downcast_ref::<PipelineError>() else {
+            return Err(explain_recv_failure(dst_machine, dst_ds, err));
+        };
+        // Trying again would only fill the pool up again.
+        if attempt == opts.retries || matches!(pipeline_err.recv_error(), Some(MachineError::OutOfSpace)) {
+            return Err(explain_recv_failure(dst_machine, dst_ds, err));
+        }
+        // A receive cut short (e.g. by the network) leaves a resume token behind, from which the next
+        // attempt picks up. One which zfs-recv refused outright doesn't, and would only fail again.
+        let Ok(Some(_)) = dst_machine.get_resume_token(dst_ds) else {
+            return Err(explain_recv_failure(dst_machine, dst_ds, err));
+        };
+        attempt += 1;
+        let delay = retry_delay(attempt);
+        log::warn!("{err:#}; retrying in {}s (attempt {attempt} of {}).", delay.as_secs(), opts.retries);
+        thread::sleep(delay);
+        // Don't take the snapshot of --take-snap-now all over again.
+        if let Some(snap_name) = &opts.take_snap_now {
+            if src_ds.snaps.iter().any(|s| &s.name == snap_name) {
+                opts.take_snap_now = None;
+            }
+        }
+    }
+}
+
+/// Whether `dst_ds` exists; and if so, populates its snapshots. A destination that doesn't exist
+/// yet isn't an error: it's up to the caller whether to initialize it.
+pub fn get_destination_snaps(dst_machine: &Machine, dst_ds: &mut Dataset) -> Result<bool, anyhow::Error> {
+    if !dst_machine.dataset_exists(dst_ds.fullname()).context(format!(r#"Unable to check whether "{dst_machine}:{dst_ds}" exists."#))? {
+        return Ok(false);
+    }
+    dst_machine.get_snaps(dst_ds).context(format!(r#"Unable to get snapshots for "{dst_machine}:{dst_ds}"."#))?;
+    Ok(true)
+}
+
+/// Completes the name of `dst_ds`, as given, into that of the dataset actually received into: with
+/// the relative path of `src_ds` ("//"), or as zfs-recv names it with `path_remap`. Which are not
+/// to be combined, or the path would be appended twice.
+fn destination_name(src_ds: &Dataset, dst_ds: &mut Dataset, path_remap: Option<RecvPathRemap>) -> Result<(), anyhow::Error> {
+    let Some(path_remap) = path_remap else {
+        dst_ds.append_relative(src_ds);
+        return Ok(());
+    };
+    if !src_ds.relative().is_empty() {
+        bail!(r#"The relative path of "{src_ds}" ("//") can't be combined with --use-d/--use-e, which name the destination after the source already."#);
+    }
+    dst_ds.append_path(path_remap.appended(src_ds));
+    Ok(())
+}
+
+/// The zfs-recv into `dst_ds`. With `opts.recv_path_remap`, zfs-recv is given the filesystem which
+/// [destination_name] appended to, as it appends the same itself.
+fn recv_cmd(dst_machine: &Machine, src_ds: &Dataset, dst_ds: &Dataset, opts: &ReplicateDatasetOpts) -> Command {
+    let flags = RecvFlags {
+        rollback: opts.use_rollback_flag_on_recv,
+        no_mount: opts.no_mount || opts.safe_recv,
+        path_remap: opts.recv_path_remap,
+    };
+    let properties = recv_properties(opts);
+    let Some(path_remap) = opts.recv_path_remap else {
+        return dst_machine.recv(dst_ds, &flags, &properties);
+    };
+    let appended = path_remap.appended(src_ds);
+    let target = match appended {
+        "" => dst_ds.fullname(),
+        _ => &dst_ds.fullname()[..dst_ds.fullname().len() - appended.len() - 1],
+    };
+    let target = target.parse::<Dataset>().expect("Application bug: the destination was named by appending to a valid name.");
+    dst_machine.recv(&target, &flags, &properties)
+}
+
+/// What --safe-recv sets on the received dataset, so that it neither mounts itself on top of the
+/// destination's own filesystems (say, at boot), nor gets changed there.
+pub const SAFE_RECV_PROPERTIES: [(&str, &str); 2] = [("canmount", "noauto"), ("readonly", "on")];
+
+/// `opts.recv_properties`, followed, with `opts.safe_recv`, by whichever [SAFE_RECV_PROPERTIES]
+/// they don't name already.
+fn recv_properties(opts: &ReplicateDatasetOpts) -> Vec<(String, Option<String>)> {
+    let mut properties = opts.recv_properties.clone();
+    if opts.safe_recv {
+        for (name, value) in SAFE_RECV_PROPERTIES {
+            if !opts.recv_properties.iter().any(|(n, _)| n == name) {
+                properties.push((name.to_string(), Some(value.to_string())));
+            }
+        }
+    }
+    properties
+}
+
+/// Adds a likely explanation to `err`, if it's the failure of a transfer. Which is
+/// [MachineError::OutOfSpace] if zfs-recv said so. Otherwise, if the destination has readonly=on;
+/// which doesn't keep zfs-recv from receiving into it, unless its pool was imported read-only, but
+/// is worth ruling out.
+fn explain_recv_failure(dst_machine: &Machine, dst_ds: &Dataset, err: anyhow::Error) -> anyhow::Error {
+    let Some(pipeline_err) = err.downcast_ref::<PipelineError>() else {
+        return err;
+    };
+    if let Some(MachineError::OutOfSpace) = pipeline_err.recv_error() {
+        return anyhow::Error::new(MachineError::OutOfSpace).context(format!(
+            r#"Pool "{dst_machine}:{pool}" ran out of space receiving into "{dst_ds}". Hint: free some space there, or pass --prune-destination to destroy the snapshots of "{dst_ds}" which are gone from the source."#,
+            pool = dst_ds.pool()
+        ));
+    }
+    match dst_machine.get_property(dst_ds, "readonly") {
+        Ok(Some(readonly)) if readonly == "on" =>
+            anyhow!(r#"{err:#}
+Hint: "{dst_machine}:{dst_ds}" has readonly=on; was its pool imported read-only?"#),
+        _ => err,
+    }
+}
+
+/// Sets up the programs which run next to zfs-send and zfs-recv, on the machine of each: the
+/// compressor/decompressor of `opts.transport_compress`, and the rate-limiting program if it's
+/// meant to run on the remote side.
+fn set_up_stream_filters(
+    src_machine: &mut Machine,
+    dst_machine: &mut Machine,
+    opts: &ReplicateDatasetOpts,
+) -> Result<(), anyhow::Error> {
+    let compression = match opts.transport_compress {
+        Some(_) if opts.send_flags.compressed || opts.send_flags.raw => {
+            log::warn!("Not compressing the stream in transit, since zfs send -c/-w already sends it compressed (or encrypted); see --no-compressed.");
+            None
+        }
+        Some(_) if src_machine.location == Location::Local && dst_machine.location == Location::Local => {
+            log::warn!("Not compressing the stream in transit, since neither the source nor the destination is remote.");
+            None
+        }
+        compression => compression,
+    };
+    // The data is compressed right after zfs-send and decompressed right before zfs-recv, so that
+    // the rate-limiting program (if any) gets to see it compressed, whichever side it runs on.
+    if let Some(compression) = compression {
+        src_machine.stream_filters.push(compression.compress_cmd().to_string());
+    }
+    if opts.ratelimit_side == RatelimitSide::Remote {
+        if let Some(filter) = buffer_cmd(&opts.ratelimit, &opts.buffer) {
+            let remote = if matches!(dst_machine.location, Location::Remote { .. }) {
+                &mut *dst_machine
+            } else if matches!(src_machine.location, Location::Remote { .. }) {
+                &mut *src_machine
+            } else {
+                bail!("--ratelimit-side remote requires either the source or the destination to be remote.");
+            };
+            remote.stream_filters.push(render_cmd(&filter));
+        }
+    }
+    if let Some(compression) = compression {
+        dst_machine.stream_filters.push(compression.decompress_cmd().to_string());
+    }
+    return Ok(());
+}
+
+/// One of the source/destination pairs for [replicate_many_cli].
+pub struct ReplicateJob {
+    pub src_machine: Machine,
+    pub src_ds: Dataset,
+    pub dst_machine: Machine,
+    pub dst_ds: Dataset,
+}
+
+/// Runs [replicate_dataset_cli] for each of `jobs`, with the same `opts`, up to `concurrency` at a
+/// time. Returns the outcome of each, labelled "source -> destination", in the order of `jobs`.
+pub fn replicate_many_cli(
+    jobs: Vec<ReplicateJob>,
+    opts: &ReplicateDatasetOpts,
+    concurrency: usize,
+) -> Vec<(String, Result<ReplicateResult, anyhow::Error>)> {
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let outcomes = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| loop {
+                let Some((idx, mut job)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let label = format!("{}:{} -> {}:{}", job.src_machine, job.src_ds, job.dst_machine, job.dst_ds);
+                let result = replicate_dataset_cli(&mut job.src_machine, &mut job.src_ds, &mut job.dst_machine, &mut job.dst_ds, opts.clone());
+                outcomes.lock().unwrap().push((idx, label, result));
+            });
+        }
+    });
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(idx, _, _)| *idx);
+    outcomes.into_iter().map(|(_, label, result)| (label, result)).collect()
+}
+
+/// Renders the outcomes of [replicate_many_cli] one per line, or as a JSON array if `json`. Errors
+/// are reported as objects with an "error" field in the latter.
+pub fn render_many_summary(outcomes: &[(String, Result<ReplicateResult, anyhow::Error>)], json: bool) -> String {
+    if json {
+        return serde_json::Value::Array(outcomes.iter()
+            .map(|(label, result)| match result {
+                Ok(result) => result.to_json(),
+                Err(err) => json!({"job": label, "error": format!("{err:#}")}),
+            })
+            .collect()
+        ).to_string();
+    }
+    outcomes.iter()
+        .map(|(label, result)| match result {
+            Ok(result) => format!("OK      {label}: {}", result.message),
+            Err(err) => format!("FAILED  {label}: {err:#}"),
+        })
+        .join("\n")
+}
+
+/// Works out what [replicate_dataset_cli] would send, as it would, and reports how much that is,
+/// as estimated by `zfs send -nvP`, without sending anything. A snapshot to be taken first
+/// (`opts.take_snap_now`) can't be accounted for.
+pub fn estimate_replication_cli(
+    src_machine : &mut Machine,
+    src_ds : &mut Dataset,
+    dst_machine : &mut Machine,
+    dst_ds: &mut Dataset,
+    opts: ReplicateDatasetOpts,
 ) -> Result<String, anyhow::Error> {
-    dst_ds.append_relative(src_ds);
+    destination_name(src_ds, dst_ds, opts.recv_path_remap)?;
+    src_machine.get_snaps(src_ds).context(format!(r#"Unable to get snapshots for "{src_machine}:{src_ds}"."#))?;
+    if src_ds.snaps.is_empty() {
+        bail!(r#"Source dataset "{src_machine}:{src_ds}" has no snapshots to send."#);
+    }
+    if let Some(until) = &opts.until {
+        truncate_until(src_machine, src_ds, until)?;
+    }
+    let dst_dataset_existed = get_destination_snaps(dst_machine, dst_ds)?;
+    check_max_snapshots(src_machine, src_ds, opts.max_snapshots)?;
+    check_max_snapshots(dst_machine, dst_ds, opts.max_snapshots)?;
+    let flags = SendFlags { dry_run: true, ..opts.send_flags };
 
-    src_machine.get_snaps(src_ds).context(format!(r#"Unable to get snapshots for "{src_machine}:{src_ds}"."#))?;  // No handling it if this fails.
-    let dst_dataset_existed = match dst_machine.get_snaps(dst_ds) {
-        Ok(_) => true,
-        Err(MachineError::NoDataset) => false,
-        Err(e) => return Err(e).context(format!(r#"Unable to get snapshots for "{dst_machine}:{dst_ds}"."#))
-    };
-    if opts.app_verbose {
-        eprintln!(r#"There are {} snapshot(s) in "{src_machine}:{src_ds}"."#, src_ds.snaps.len());
-        if dst_dataset_existed {
-            eprintln!(r#"There are {} snapshot(s) in "{dst_machine}:{dst_ds}"."#, dst_ds.snaps.len());
+    let mut send_cmds = Vec::new();
+    if !dst_dataset_existed {
+        if !opts.init_nonexistent_destination {
+            bail!(r#"Dataset "{dst_machine}:{dst_ds}" does not exist and full send (--init-empty) not requested."#);
+        }
+        let oldest = src_ds.oldest_snap();
+        if opts.init_latest {
+            send_cmds.push(src_machine.fullsend_s(src_ds, src_ds.newest_snap(), &flags));
         } else {
-            eprintln!(r#"Dataset "{dst_machine}:{dst_ds}" not found; continuing."#);
+            send_cmds.push(src_machine.fullsend_s(src_ds, oldest, &flags));
+            if src_ds.newest_snap() != oldest {
+                send_cmds.push(src_machine.send_from_s_till_newest(src_ds, oldest, opts.simple_incremental, &flags));
+            }
+        }
+    } else {
+        match find_mrcud(src_ds, dst_ds) {
+            UpToDate(mrc) =>
+                return Ok(format!(r#"Nothing to send: datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" are already up-to-date at snapshot "{mrc}"."#)),
+            DestinationHasMore(mrc) =>
+                bail!(r#"Source dataset "{src_machine}:{src_ds}"'s most recent snapshot, "{mrc}", is also found in destination dataset "{dst_machine}:{dst_ds}", but there are additional, newer snapshots at the destination."#),
+            Divergence(mrc) if !opts.allow_divergent_destination =>
+                bail!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" diverge after "{mrc}" and --allow-divergent-destination not given."#),
+            Divergence(mrc) | SourceHasMore(mrc) =>
+                send_cmds.push(src_machine.send_from_s_till_newest(src_ds, mrc, opts.simple_incremental, &flags)),
+            NoneInCommon => {
+                src_machine.get_bookmarks(src_ds).context(format!(r#"Unable to get bookmarks for "{src_machine}:{src_ds}"."#))?;
+                let Some(bookmark) = find_bookmark_base(src_ds, dst_ds) else {
+                    bail!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" have no snapshots in common, and no bookmark in the source matches any snapshot in the destination."#);
+                };
+                send_cmds.push(src_machine.send_from_bookmark_till_newest(src_ds, bookmark, &flags));
+            }
+        }
+    }
+
+    let mut streams = Vec::new();
+    let mut total = 0;
+    for cmd in send_cmds {
+        let output = src_machine.run_send_estimate(cmd).context(format!(r#"Unable to estimate what sending "{src_machine}:{src_ds}" would take."#))?;
+        let (items, size) = parse_send_header(&mut output.as_bytes())
+            .context(format!(r#"Unable to estimate what sending "{src_machine}:{src_ds}" would take."#))?;
+        streams.extend(items);
+        total += size;
+    }
+    let mut report = streams.iter()
+        .map(|(name, size)| format!("{name}\t{}", HumanBytes(*size)))
+        .collect::<Vec<_>>();
+    report.push(format!(r#"Replicating "{src_machine}:{src_ds}" to "{dst_machine}:{dst_ds}" would send {} snapshot(s), totalling ~{} ({total} bytes)."#, streams.len(), HumanBytes(total)));
+    if opts.take_snap_now.is_some() {
+        report.push("Not counting the snapshot to be taken first.".to_string());
+    }
+    Ok(report.join("\n"))
+}
+
+/// How long to wait before the `attempt`th retry: 5s, doubling every time, up to 5 minutes.
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(5 << attempt.saturating_sub(1).min(6)).min(Duration::from_secs(300))
+}
+
+fn replicate_dataset(
+    src_machine : &mut Machine,
+    src_ds : &mut Dataset,
+    dst_machine : &mut Machine,
+    dst_ds: &mut Dataset,
+    mut opts: ReplicateDatasetOpts,
+) -> Result<ReplicateResult, anyhow::Error> {
+    let mut result = ReplicateResult {
+        source: format!("{src_machine}:{src_ds}"),
+        destination: format!("{dst_machine}:{dst_ds}"),
+        base: None,
+        snapshots_sent: 0,
+        bytes_sent: 0,
+        status: ReplicateStatus::Synchronized,
+        message: String::new(),
+    };
+
+    src_machine.get_snaps(src_ds).context(format!(r#"Unable to get snapshots for "{src_machine}:{src_ds}"."#))?;  // No handling it if this fails.
+    ensure_source_snaps(src_machine, src_ds, &mut opts)?;
+    if let Some(until) = &opts.until {
+        truncate_until(src_machine, src_ds, until)?;
+    }
+    let mut dst_dataset_existed = get_destination_snaps(dst_machine, dst_ds)?;
+    check_max_snapshots(src_machine, src_ds, opts.max_snapshots)?;
+    check_max_snapshots(dst_machine, dst_ds, opts.max_snapshots)?;
+    log::info!(r#"There are {} snapshot(s) in "{src_machine}:{src_ds}"."#, src_ds.snaps.len());
+    if dst_dataset_existed {
+        log::info!(r#"There are {} snapshot(s) in "{dst_machine}:{dst_ds}"."#, dst_ds.snaps.len());
+    } else {
+        log::info!(r#"Dataset "{dst_machine}:{dst_ds}" not found; continuing."#);
+    }
+
+    if opts.send_flags.raw {
+        if let Some(problem) = check_encryption_root(src_machine, src_ds, dst_machine, dst_ds, dst_dataset_existed)? {
+            if !opts.force {
+                bail!("{problem}\nHint: use --force to try anyway.");
+            }
+            log::warn!("{problem}");
+        }
+    }
+
+    if dst_dataset_existed {
+        let token = dst_machine.get_resume_token(dst_ds).context(format!(r#"Unable to get the resume token for "{dst_machine}:{dst_ds}"."#))?;
+        if let Some(token) = token {
+            if opts.no_resume {
+                eprintln!(r#"Discarding the partial state of an interrupted receive into "{dst_machine}:{dst_ds}" (requested by --no-resume)."#);
+                if !opts.dry_run {
+                    dst_machine.abort_partial_recv(dst_ds).context(format!(r#"Failed to discard the partial state of "{dst_machine}:{dst_ds}"."#))?;
+                }
+            } else {
+                eprintln!(r#"Resuming an interrupted receive into "{dst_machine}:{dst_ds}"."#);
+                let mut source_send_cmd = src_machine.send_resume(&token);
+                let mut destination_recv_cmd = recv_cmd(dst_machine, src_ds, dst_ds, &opts);
+                if opts.dry_run {
+                    println!("{}", render_pipeline(&source_send_cmd, &destination_recv_cmd, &local_buffer_cmd(&opts)));
+                    result.status = ReplicateStatus::DryRun;
+                    result.snapshots_sent = 1;
+                    result.message = format!(r#"Dry run: would have resumed the interrupted receive into "{dst_ds}"; run again afterwards to plan the rest."#);
+                    return Ok(result);
+                }
+                result.bytes_sent += run_pipeline(&mut source_send_cmd, &mut destination_recv_cmd, local_buffer_cmd(&opts), opts.progress, rate_cap(&opts), wire_may_differ(src_machine, dst_machine, &opts))?;
+                result.snapshots_sent += 1;
+                // Whatever remains to be sent is figured out as usual.
+                dst_machine.get_snaps(dst_ds).context(format!(r#"Unable to get snapshots for "{dst_machine}:{dst_ds}"."#))?;
+            }
         }
     }
 
-    if !dst_dataset_existed && !opts.init_nonexistent_destination {
+    let mut reinitialized = false;
+    if dst_dataset_existed && opts.force_full && needs_full_resend(src_machine, src_ds, dst_ds)? {
+        destroy_for_full_resend(dst_machine, dst_ds, &opts)?;
+        dst_ds.snaps.clear();
+        dst_dataset_existed = false;
+        reinitialized = true;
+    }
+
+    if !dst_dataset_existed && !opts.init_nonexistent_destination && !reinitialized {
         return Err(anyhow!(r#"Dataset "{dst_machine}:{dst_ds}" does not exist and full send (--init-empty) not requested."#));
     }
-    if !dst_dataset_existed && opts.init_nonexistent_destination {
+    if !dst_dataset_existed {
         if dst_ds.is_pool_root() {
             bail!(r#"Dataset "{dst_machine}:{dst_ds}" does not exist and it cannot be created via full send because it is top-level."#);
         }
-        if opts.app_verbose {
-            eprintln!(r#"Ensuring "{dst_machine}:{dst_ds}"'s ancestors exist."#);
+        if opts.create_parents {
+            log::info!(r#"Ensuring "{dst_machine}:{dst_ds}"'s ancestors exist."#);
+            if !opts.dry_run {
+                dst_machine.create_ancestors(dst_ds).context(format!(r#"Failed to create "{dst_machine}:{dst_ds}"'s ancestors!"#))?;
+            }
+        } else {
+            // zfs-recv would fail all the same, but less to the point.
+            let parent = dst_ds.parent().unwrap();
+            if !dst_machine.dataset_exists(parent).context(format!(r#"Unable to check whether "{dst_machine}:{parent}" exists."#))? {
+                bail!(r#"Dataset "{dst_machine}:{dst_ds}" can't be created, since its parent "{parent}" does not exist, and --no-create-parents was given.
+Hint: check the destination for typos, or create "{parent}" first."#);
+            }
         }
-        dst_machine.create_ancestors(dst_ds).context(format!(r#"Failed to create "{dst_machine}:{dst_ds}"'s ancestors!"#))?;
+        // Taken now, and not again below: only the oldest snapshot is sent in full, and the
+        // incremental catch-up that follows sends the rest, up to this one. With --init-latest,
+        // this one is sent in full, and there's nothing left to catch up on.
         if let Some(snap_name) = opts.take_snap_now.take() {
-            eprintln!(r#"Taking snapshot "{src_machine}:{src_ds}@{snap_name}" (requested by --take-snap-now)."#);
-            src_machine.create_snap_with_name(src_ds, &snap_name).context("Failed to take snapshot (requested by --take-snap-now).")?;
+            take_snap_now(src_machine, src_ds, &snap_name, opts.recursive_snap, opts.dry_run)?;
         }
-        let mut source_send_cmd = src_machine.fullsend_s(&src_ds, src_ds.oldest_snap());
-        let mut destination_recv_cmd = dst_machine.recv(&dst_ds, opts.use_rollback_flag_on_recv);
-        let (mut source_send_process,
-            mut destination_recv_process,
-            pv_ratelimit_option
-        ) = pipe_with_ratelimit(&mut source_send_cmd, &mut destination_recv_cmd, &opts.ratelimit)?;
-        do_progressbar_from_zfs_send_stderr(source_send_process.stderr.take().unwrap());
-        let source_send_finished = source_send_process.wait().unwrap();
-        let destination_recv_finished = destination_recv_process.wait().unwrap();
-        if let Some(mut pv_process) = pv_ratelimit_option {
-            pv_process.wait().unwrap();
+        let full = match opts.init_latest {
+            true => src_ds.newest_snap(),
+            false => src_ds.oldest_snap(),
+        };
+        let mut source_send_cmd = src_machine.fullsend_s(src_ds, full, &opts.send_flags);
+        let mut destination_recv_cmd = recv_cmd(dst_machine, src_ds, dst_ds, &opts);
+        if opts.dry_run {
+            println!("{}", render_pipeline(&source_send_cmd, &destination_recv_cmd, &local_buffer_cmd(&opts)));
+            // Pretend the full send happened, so that the incremental catch-up below can be planned too.
+            dst_ds.snaps = vec![full.clone()];
+        } else {
+            result.bytes_sent += run_pipeline(&mut source_send_cmd, &mut destination_recv_cmd, local_buffer_cmd(&opts), opts.progress, rate_cap(&opts), wire_may_differ(src_machine, dst_machine, &opts))?;
+            log::info!(r#"Full-send of "{src_machine}:{src_ds}@{full}" successful."#);
+            dst_machine.get_snaps(dst_ds).expect("Application bug: no snaps in destination after full-send successfully performed.");
         }
-        if !source_send_finished.success() || !destination_recv_finished.success() {
-            return Err(anyhow!("There was a problem with the zfs-send|zfs-recv processes. Exit status: send {source_send_finished}, recv {destination_recv_finished}"));
+        result.snapshots_sent += 1;
+    }
+
+    if let Some(snap_name) = &opts.rollback_to {
+        rollback_destination(src_ds, dst_machine, dst_ds, snap_name, opts.dry_run)?;
+    }
+
+    let mut bookmark_base = None;
+    if let NoneInCommon = find_mrcud(src_ds, dst_ds) {
+        // The source may have pruned the snapshots in common, but kept bookmarks of them.
+        src_machine.get_bookmarks(src_ds).context(format!(r#"Unable to get bookmarks for "{src_machine}:{src_ds}"."#))?;
+        let Some(bookmark) = find_bookmark_base(src_ds, dst_ds) else {
+            return Err(anyhow!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" have no snapshots in common, and no bookmark in the source matches any snapshot in the destination."#));
+        };
+        if opts.send_flags.recursive {
+            bail!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" have no snapshots in common; bookmark "{bookmark}" could be used as the incremental base, but not with --recursive."#);
         }
-        if opts.app_verbose {
-            eprintln!(r#"Full-send of "{src_machine}:{src_ds}@{src_oldest_name}" successful."#, src_oldest_name=&src_ds.oldest_snap().name);
+        if dst_ds.newest_snap().guid != bookmark.guid && !opts.allow_divergent_destination {
+            bail!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" diverge after bookmark "{bookmark}" and --allow-divergent-destination not given."#);
         }
-        dst_machine.get_snaps(dst_ds).expect("Application bug: no snaps in destination after full-send successfully performed.");
+        log::info!(r#"No snapshots in common; sending from bookmark "{src_ds}#{bookmark}" instead."#);
+        bookmark_base = Some(bookmark.clone());
     }
 
-    let mrcud = find_mrcud(&src_ds, &dst_ds);
+    let mrcud = find_mrcud(src_ds, dst_ds);
     // Check for reasons to bail early.
     match mrcud {
-        NoneInCommon =>
-            return Err(anyhow!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" have no snapshots in common."#)),
+        NoneInCommon => (),  // Dealt with above, by falling back to bookmark_base.
 
-        UpToDate(mrc) if !opts.take_snap_now.is_some() =>
-            return Ok(format!(r#"Nothing to do: datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" are already up-to-date at snapshot "{mrc}"."#)),
+        UpToDate(mrc) if opts.take_snap_now.is_none() => {
+            if opts.prune_destination {
+                prune_destination(src_ds, dst_machine, dst_ds, opts.dry_run)?;
+            }
+            if opts.bookmark_on_send {
+                bookmark_newest(src_machine, src_ds, opts.dry_run)?;
+            }
+            if let Some(tag) = &opts.hold_latest {
+                hold_newest(dst_machine, dst_ds, &src_ds.newest_snap().name, None, tag, opts.dry_run)?;
+            }
+            result.base = Some(mrc.clone());
+            if opts.dry_run {
+                result.status = ReplicateStatus::DryRun;
+            } else if result.snapshots_sent == 0 {
+                result.status = ReplicateStatus::UpToDate;
+            }
+            // Unless they only just got to be, by a full send (or a resumed one).
+            result.message = match (result.snapshots_sent, opts.dry_run) {
+                (0, _) => format!(r#"Nothing to do: datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" are already up-to-date at snapshot "{mrc}"."#),
+                (_, true) => format!(r#"Dry run: would have synchronized "{src_ds}" to "{dst_ds}" up to "{mrc}"."#),
+                (_, false) => format!(r#"Successfully synchronized "{src_ds}" to "{dst_ds}"."#),
+            };
+            if let (Some(verify), false) = (opts.verify, opts.dry_run) {
+                result.message += &verify_replica(src_machine, src_ds, dst_machine, dst_ds, verify)?;
+            }
+            return Ok(result);
+        }
+
+        DestinationHasMore(mrc) if opts.until.is_some() =>
+            return Err(anyhow!(r#"Destination dataset "{dst_machine}:{dst_ds}" already has snapshots newer than "{mrc}" (--until); nothing to replicate up to it."#)),
 
         DestinationHasMore(mrc) => {
-            if !opts.take_snap_now.is_some() {
+            let Some(snap_name) = &opts.take_snap_now else {
                 return Err(anyhow!(r#"Source dataset "{src_machine}:{src_ds}"'s most recent snapshot, "{mrc}", is also found in destination dataset "{dst_machine}:{dst_ds}", but there are additional, newer snapshots at the destination.
 Hint: perhaps you meant to send from "{dst_machine}:{dst_ds}" to "{src_machine}:{src_ds}"?"#));
-            } else {
-                if !opts.allow_divergent_destination {
-                    return Err(anyhow!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" would diverge after taking snapshot "{}" and --allow-divergent-destination not given."#, opts.take_snap_now.unwrap()))
-                }
+            };
+            if !opts.allow_divergent_destination {
+                return Err(anyhow!(r#"Datasets "{src_machine}:{src_ds}" and "{dst_machine}:{dst_ds}" would diverge after taking snapshot "{snap_name}" and --allow-divergent-destination not given."#))
             }
         }
 
@@ -103,84 +829,1037 @@ Hint: perhaps you meant to send from "{dst_machine}:{dst_ds}" to "{src_machine}:
     }
 
     let most_recent_common_snap = match mrcud {
-        Divergence(s) | SourceHasMore(s) | UpToDate(s) | DestinationHasMore(s) => s,
-        _ => unreachable!()
-    }.clone();
+        Divergence(s) | SourceHasMore(s) | UpToDate(s) | DestinationHasMore(s) => s.clone(),
+        NoneInCommon => bookmark_base.clone().unwrap(),
+    };
+
+    log::info!(r#"Figured out "{}" as the most recent common snapshot."#, most_recent_common_snap.name);
+
+    if opts.use_rollback_flag_on_recv && !opts.dry_run {
+        let rolled_back = snaps_rolled_back(dst_ds, &most_recent_common_snap);
+        if !rolled_back.is_empty() {
+            eprintln!(r#"Receiving with -F (requested by --rollback) will destroy these snapshots of "{dst_machine}:{dst_ds}", newer than "{}":"#, most_recent_common_snap.name);
+            for snap in &rolled_back {
+                eprintln!("    {dst_ds}@{snap}");
+            }
+            if !confirm("Go ahead?") {
+                bail!(r#"Not rolling back "{dst_machine}:{dst_ds}"; nothing was sent."#);
+            }
+        }
+    }
+
+    if let Some(snap_name) = &opts.take_snap_now {
+        take_snap_now(src_machine, src_ds, snap_name, opts.recursive_snap, opts.dry_run)?;
+    }
+
+    match opts.simple_incremental {
+        true => log::info!(r#"Now sending delta between "{}" to "{}"."#, most_recent_common_snap.name, src_ds.newest_snap()),
+        false => log::info!(r#"Now sending deltas of all intervening snapshots between "{}" to "{}"."#, most_recent_common_snap.name, src_ds.newest_snap())
+    }
+
+    let mut source_send_cmd = match &bookmark_base {
+        Some(bookmark) => src_machine.send_from_bookmark_till_newest(src_ds, bookmark, &opts.send_flags),
+        None => src_machine.send_from_s_till_newest(src_ds, &most_recent_common_snap, opts.simple_incremental, &opts.send_flags),
+    };
+    let mut destination_recv_cmd = recv_cmd(dst_machine, src_ds, dst_ds, &opts);
+
+    if opts.dry_run {
+        println!("{}", render_pipeline(&source_send_cmd, &destination_recv_cmd, &local_buffer_cmd(&opts)));
+    } else {
+        result.bytes_sent += run_pipeline(&mut source_send_cmd, &mut destination_recv_cmd, local_buffer_cmd(&opts), opts.progress, rate_cap(&opts), wire_may_differ(src_machine, dst_machine, &opts))?;
+    }
+    result.snapshots_sent += match opts.simple_incremental {
+        true => 1,
+        false => count_snaps_after(src_ds, &most_recent_common_snap),
+    };
+    result.base = Some(most_recent_common_snap.clone());
+
+    if opts.prune_destination {
+        if !opts.dry_run {
+            dst_machine.get_snaps(dst_ds).context(format!(r#"Unable to get snapshots for "{dst_machine}:{dst_ds}"."#))?;
+        }
+        prune_destination(src_ds, dst_machine, dst_ds, opts.dry_run)?;
+    }
+    if opts.bookmark_on_send {
+        bookmark_newest(src_machine, src_ds, opts.dry_run)?;
+    }
+    if let Some(tag) = &opts.hold_latest {
+        hold_newest(dst_machine, dst_ds, &src_ds.newest_snap().name, Some(&most_recent_common_snap.name), tag, opts.dry_run)?;
+    }
+
+    if opts.dry_run {
+        result.status = ReplicateStatus::DryRun;
+        result.message = format!(r#"Dry run: would have synchronized "{src_ds}" to "{dst_ds}" from "{}" to "{}"."#, most_recent_common_snap.name, src_ds.newest_snap());
+        return Ok(result);
+    }
+    result.message = format!(r#"Successfully synchronized "{src_ds}" to "{dst_ds}"."#);
+    if let Some(verify) = opts.verify {
+        result.message += &verify_replica(src_machine, src_ds, dst_machine, dst_ds, verify)?;
+    }
+    Ok(result)
+}
+
+/// Fails if `ds` has more than `max` snapshots, as a guard against datasets which piled up far too
+/// many (e.g. an autosnapshot job gone wrong), for which everything would take forever.
+pub fn check_max_snapshots(machine: &Machine, ds: &Dataset, max: Option<usize>) -> Result<(), anyhow::Error> {
+    match max {
+        Some(max) if ds.snaps.len() > max => bail!(r#"Dataset "{machine}:{ds}" has {} snapshots, more than --max-snapshots ({max}) allows.
+Hint: raise --max-snapshots if that many are expected, or prune them first (see apply-retention)."#, ds.snaps.len()),
+        _ => Ok(()),
+    }
+}
+
+/// Leaves out the snapshots of `src_ds` newer than `until`, which is then its newest as far as the
+/// rest of the replication goes. Fails if `src_ds` has no such snapshot.
+fn truncate_until(src_machine: &Machine, src_ds: &mut Dataset, until: &str) -> Result<(), anyhow::Error> {
+    let Some(idx) = src_ds.snaps.iter().position(|s| s.name == until) else {
+        bail!(r#"Source dataset "{src_machine}:{src_ds}" has no snapshot "{until}" to replicate up to (--until)."#);
+    };
+    let left_out = src_ds.snaps.len() - idx - 1;
+    if left_out > 0 {
+        log::info!(r#"Leaving out the {left_out} snapshot(s) of "{src_machine}:{src_ds}" newer than "{until}" (requested by --until)."#);
+    }
+    src_ds.snaps.truncate(idx + 1);
+    Ok(())
+}
+
+/// Makes sure that `src_ds` has some snapshot to send: should it have none, takes the one of
+/// `opts.take_snap_now` right away, if any, and fails otherwise.
+fn ensure_source_snaps(src_machine: &Machine, src_ds: &mut Dataset, opts: &mut ReplicateDatasetOpts) -> Result<(), anyhow::Error> {
+    if !src_ds.snaps.is_empty() {
+        return Ok(());
+    }
+    let Some(snap_name) = opts.take_snap_now.take() else {
+        bail!(r#"Source dataset "{src_machine}:{src_ds}" has no snapshots to replicate; take one first or pass --take-snap-now."#);
+    };
+    take_snap_now(src_machine, src_ds, &snap_name, opts.recursive_snap, opts.dry_run)
+}
+
+/// Checks, as thoroughly as `verify` says, that `dst_ds` now matches `src_ds`. Fails if it doesn't;
+/// otherwise, returns a sentence on what was checked, to go after the report.
+fn verify_replica(
+    src_machine: &Machine,
+    src_ds: &Dataset,
+    dst_machine: &Machine,
+    dst_ds: &mut Dataset,
+    verify: Verify,
+) -> Result<String, anyhow::Error> {
+    dst_machine.get_snaps(dst_ds).context(format!(r#"Unable to get snapshots for "{dst_machine}:{dst_ds}"."#))?;
+    let (src_newest, dst_newest) = (src_ds.newest_snap(), dst_ds.newest_snap());
+    if src_newest.guid != dst_newest.guid {
+        bail!(r#"Verification failed: the newest snapshot of "{src_machine}:{src_ds}" is "{src_newest}" (guid {}), but that of "{dst_machine}:{dst_ds}" is "{dst_newest}" (guid {})."#, src_newest.guid, dst_newest.guid);
+    }
+    if verify == Verify::Guid {
+        return Ok(format!(r#" Verified: both have "{src_newest}" as their newest snapshot, with the same guid."#));
+    }
+    let src_size = src_machine.estimate_send_size(src_ds, src_newest)
+        .context(format!(r#"Unable to estimate the stream size of "{src_machine}:{src_ds}@{src_newest}"."#))?;
+    let dst_size = dst_machine.estimate_send_size(dst_ds, dst_newest)
+        .context(format!(r#"Unable to estimate the stream size of "{dst_machine}:{dst_ds}@{dst_newest}"."#))?;
+    if src_size != dst_size {
+        bail!(r#"Verification failed: "{src_newest}" has the same guid on both sides, but its estimated stream size is {src_size} bytes in "{src_machine}:{src_ds}" and {dst_size} bytes in "{dst_machine}:{dst_ds}"."#);
+    }
+    Ok(format!(r#" Verified: both have "{src_newest}" as their newest snapshot, with the same guid and estimated stream size ({})."#, HumanBytes(src_size)))
+}
+
+/// The snapshots of `dst_ds` newer than `base` (a snapshot, or a bookmark of one, it has in common
+/// with the source), which `zfs recv -F` destroys on the way to receiving an incremental from `base`.
+fn snaps_rolled_back<'a>(dst_ds: &'a Dataset, base: &Snap) -> Vec<&'a Snap> {
+    dst_ds.snaps.iter()
+        .skip_while(|s| s.guid != base.guid)
+        .skip(1)
+        .collect()
+}
+
+/// Rolls `dst_ds` back to its snapshot `snap_name`, as requested by --rollback-to, if it has any
+/// snapshots newer than the most recent one in common with `src_ds`; once the user has been shown
+/// which, and [confirm]ed it. In dry-run mode, only pretends to. `snap_name` must be one it has in
+/// common with `src_ds`, or there would be no incremental to send onto it afterwards.
+/// Unlike `zfs recv -F` (--rollback), this happens before anything is sent, to a snapshot of the
+/// user's choosing, and only ever once per run.
+fn rollback_destination(src_ds: &Dataset, dst_machine: &Machine, dst_ds: &mut Dataset, snap_name: &str, dry_run: bool) -> Result<(), anyhow::Error> {
+    if !matches!(find_mrcud(src_ds, dst_ds), DestinationHasMore(_) | Divergence(_)) {
+        log::info!(r#"Not rolling back "{dst_machine}:{dst_ds}" (--rollback-to), which has no snapshots newer than the most recent one in common."#);
+        return Ok(());
+    }
+    let Some(target) = dst_ds.snaps.iter().find(|s| s.name == snap_name) else {
+        bail!(r#"Can't roll back to "{dst_machine}:{dst_ds}@{snap_name}" (--rollback-to): no such snapshot."#);
+    };
+    if !src_ds.snaps.iter().any(|s| s.guid == target.guid) {
+        bail!(r#"Can't roll back to "{dst_machine}:{dst_ds}@{snap_name}" (--rollback-to): the source doesn't have it, so there would be nothing to send onto it."#);
+    }
+    let rolled_back = snaps_rolled_back(dst_ds, target).into_iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+    let target = target.clone();
+    let verb = if dry_run { "Would roll" } else { "Rolling" };
+    eprintln!(r#"{verb} back "{dst_machine}:{dst_ds}" to "{target}" (requested by --rollback-to), destroying these snapshots of it:"#);
+    for snap in &rolled_back {
+        eprintln!("    {dst_ds}@{snap}");
+    }
+    if dry_run {
+        // Pretend it did, so that the transfer after it can be planned.
+        dst_ds.snaps.retain(|s| !rolled_back.contains(&s.name));
+        return Ok(());
+    }
+    if !confirm("Go ahead?") {
+        bail!(r#"Not rolling back "{dst_machine}:{dst_ds}"; nothing was sent."#);
+    }
+    dst_machine.rollback_to(dst_ds, &target.name).context(format!(r#"Failed to roll back "{dst_machine}:{dst_ds}" to "{target}"."#))?;
+    dst_machine.get_snaps(dst_ds).context(format!(r#"Unable to get snapshots for "{dst_machine}:{dst_ds}"."#))?;
+    Ok(())
+}
+
+/// Whether an incremental send onto `dst_ds` is out of the question, for lack of any snapshot (or
+/// bookmark) in common with `src_ds` that `dst_ds` doesn't diverge from.
+fn needs_full_resend(src_machine: &mut Machine, src_ds: &mut Dataset, dst_ds: &Dataset) -> Result<bool, anyhow::Error> {
+    match find_mrcud(src_ds, dst_ds) {
+        Divergence(_) => Ok(true),
+        NoneInCommon => {
+            src_machine.get_bookmarks(src_ds).context(format!(r#"Unable to get bookmarks for "{src_machine}:{src_ds}"."#))?;
+            Ok(find_bookmark_base(src_ds, dst_ds).is_none_or(|bookmark| dst_ds.newest_snap().guid != bookmark.guid))
+        }
+        _ => Ok(false),
+    }
+}
 
-    if opts.app_verbose {  // TODO: use log::info!() or something instead of checking conditionals.
-        eprintln!(r#"Figured out "{}" as the most recent common snapshot."#, most_recent_common_snap.name);
+/// Destroys `dst_ds` for [ReplicateDatasetOpts::force_full], once the user has been shown exactly
+/// what goes with it and [confirm]ed it.
+fn destroy_for_full_resend(dst_machine: &Machine, dst_ds: &Dataset, opts: &ReplicateDatasetOpts) -> Result<(), anyhow::Error> {
+    if dst_ds.is_pool_root() {
+        bail!(r#"Refusing to destroy "{dst_machine}:{dst_ds}" for --force-full, since it is top-level."#);
+    }
+    let listing = dst_machine.destroy_dataset(dst_ds, true)
+        .context(format!(r#"Unable to list what destroying "{dst_machine}:{dst_ds}" would take along."#))?;
+    eprintln!(r#"Nothing can be sent incrementally onto "{dst_machine}:{dst_ds}"; destroying it to send the source in full (requested by --force-full):"#);
+    eprint!("{listing}");
+    if opts.dry_run {
+        println!("zfs destroy -r -v {dst_ds}");
+        return Ok(());
+    }
+    if !confirm(&format!(r#"Destroy "{dst_machine}:{dst_ds}", along with everything listed above?"#)) {
+        bail!(r#"Not destroying "{dst_machine}:{dst_ds}"; nothing was sent."#);
+    }
+    let destroyed = dst_machine.destroy_dataset(dst_ds, false)
+        .context(format!(r#"Failed to destroy "{dst_machine}:{dst_ds}"."#))?;
+    for line in destroyed.lines() {
+        log::warn!("{line}");
     }
+    Ok(())
+}
 
-    if let Some(snap_name) = opts.take_snap_now {
-        eprintln!(r#"Taking snapshot "{src_machine}:{src_ds}@{snap_name}" (requested by --take-snap-now)."#);
-        src_machine.create_snap_with_name(src_ds, &snap_name).context("Failed to take snapshot (requested by --take-snap-now).")?;
+/// Before a raw send: if `src_ds` inherits its encryption from some parent, rather than being its
+/// own encryption root, the destination must provide one as well; otherwise, the receive fails
+/// with "encryption root not found", possibly after hours of transfer. Returns what's wrong, if so.
+fn check_encryption_root(
+    src_machine: &Machine,
+    src_ds: &Dataset,
+    dst_machine: &Machine,
+    dst_ds: &Dataset,
+    dst_dataset_existed: bool,
+) -> Result<Option<String>, anyhow::Error> {
+    let src_root = src_machine.get_encryption_root(src_ds.fullname())
+        .context(format!(r#"Unable to get the encryption root of "{src_machine}:{src_ds}"."#))?;
+    let Some(src_root) = src_root else {
+        return Ok(None);
+    };
+    if src_root == src_ds.fullname() {
+        return Ok(None);
     }
+    // A new destination inherits its encryption from its parent, as long as that one exists.
+    let dst_name = match (dst_dataset_existed, dst_ds.parent()) {
+        (true, _) => dst_ds.fullname(),
+        (false, Some(parent)) => parent,
+        (false, None) => return Ok(None),
+    };
+    match dst_machine.get_encryption_root(dst_name) {
+        Ok(Some(_)) => Ok(None),
+        Ok(None) | Err(MachineError::NoDataset) => Ok(Some(format!(
+            r#"Raw send of "{src_machine}:{src_ds}", whose encryption root is "{src_root}", but "{dst_machine}:{dst_name}" isn't encrypted; the receive would fail for lack of an encryption root."#
+        ))),
+        Err(e) => Err(e).context(format!(r#"Unable to get the encryption root of "{dst_machine}:{dst_name}"."#)),
+    }
+}
 
-    if opts.app_verbose {
-        match opts.simple_incremental {
-            true => eprintln!(r#"Now sending delta between "{}" to "{}"."#, most_recent_common_snap.name, src_ds.newest_snap()),
-            false => eprintln!(r#"Now sending deltas of all intervening snapshots between "{}" to "{}"."#, most_recent_common_snap.name, src_ds.newest_snap())
+/// How many snapshots of `src_ds` are newer than `base`, which may also be a bookmark of a
+/// snapshot `src_ds` no longer has.
+pub fn count_snaps_after(src_ds: &Dataset, base: &Snap) -> usize {
+    match src_ds.snaps.iter().position(|s| s.guid == base.guid) {
+        Some(idx) => src_ds.snaps.len() - idx - 1,
+        None => src_ds.snaps.iter().filter(|s| s.creation > base.creation).count(),
+    }
+}
+
+/// The command for the middle stage of the zfs-send | [pv or mbuffer |] zfs-recv pipeline, if any.
+fn buffer_cmd(ratelimit: &Option<String>, buffer: &Option<BufferTool>) -> Option<Command> {
+    match (buffer, ratelimit) {
+        (Some(BufferTool::Mbuffer { size }), _) => {
+            let mut cmd = Command::new("mbuffer");
+            cmd.args(["-q", "-m", size, "-s", "128k"]);
+            if let Some(lim) = ratelimit {
+                cmd.args(["-r", lim]);
+            }
+            Some(cmd)
+        }
+        (_, Some(lim)) => {
+            let mut cmd = Command::new("pv");
+            cmd.args(["-q", "-L", lim]);
+            Some(cmd)
         }
+        (_, None) => None,
     }
+}
 
-    let mut source_send_cmd = src_machine.send_from_s_till_newest(&src_ds, &most_recent_common_snap, opts.simple_incremental);
-    let mut destination_recv_cmd = dst_machine.recv(&dst_ds, opts.use_rollback_flag_on_recv);
+/// Like [buffer_cmd], unless it's meant to run on the remote side.
+fn local_buffer_cmd(opts: &ReplicateDatasetOpts) -> Option<Command> {
+    match opts.ratelimit_side {
+        RatelimitSide::Local => buffer_cmd(&opts.ratelimit, &opts.buffer),
+        RatelimitSide::Remote => None,
+    }
+}
 
+/// Spawns the zfs-send | [pv or mbuffer |] zfs-recv pipeline, reports its progress as requested,
+/// and waits for all of its processes to exit. Returns the number of bytes sent.
+fn run_pipeline(
+    source_send_cmd: &mut Command,
+    destination_recv_cmd: &mut Command,
+    buffer_cmd: Option<Command>,
+    progress: ProgressFormat,
+    rate_cap: Option<u64>,
+    count_wire: bool,
+) -> Result<u64, anyhow::Error> {
+    // Only the progress bars show the bytes on the wire, so only they are worth the relay; and only
+    // if there can be any difference to show.
+    let wire_bytes = (progress == ProgressFormat::Bars && count_wire).then(|| Arc::new(AtomicU64::new(0)));
     let (mut source_send_process,
         mut destination_recv_process,
-        pv_ratelimit_option
-    ) = pipe_with_ratelimit(&mut source_send_cmd, &mut destination_recv_cmd, &opts.ratelimit)?;
+        buffer_process_option,
+        relay
+    ) = pipe_with_ratelimit(source_send_cmd, destination_recv_cmd, buffer_cmd, wire_bytes.clone())?;
+    // zfs-send leads the process group of the pipeline; see pipe_with_ratelimit.
+    let pgid = source_send_process.id();
+    interrupt::register_pipeline(pgid);
 
-    // At this point the transfer process is underway and we're not involved in moving data.
-    // We do have to draw a progress bar. To do so take the standard error stream from the
+    // At this point the transfer process is underway, and we're not involved in moving data, bar
+    // the relay (if any). We do have to draw a progress bar. To do so take the standard error stream from the
     // sending process, where we find a header with the estimated amount of data to send as well
     // as periodic updates of progress.
-    do_progressbar_from_zfs_send_stderr(source_send_process.stderr.take().unwrap());
+    let send_stderr = source_send_process.stderr.take().unwrap();
+    let recv_stderr = destination_recv_process.stderr.take().unwrap();
+    let recv_stderr = thread::spawn(move || drain_recv_stderr(recv_stderr));
+    let bytes_sent = match progress {
+        ProgressFormat::Bars => do_progressbar_from_zfs_send_stderr(send_stderr, rate_cap, wire_bytes),
+        ProgressFormat::Quiet => drain_zfs_send_stderr(send_stderr),
+        ProgressFormat::Json => emit_progress_json_from_zfs_send_stderr(send_stderr),
+    };
 
     let source_send_finished = source_send_process.wait().unwrap();
     let destination_recv_finished = destination_recv_process.wait().unwrap();
-    if let Some(mut pv_process) = pv_ratelimit_option {
-        pv_process.wait().unwrap();
+    if let Some(mut buffer_process) = buffer_process_option {
+        buffer_process.wait().unwrap();
+    }
+    if let Some(relay) = relay {
+        relay.join().unwrap();
     }
+    interrupt::unregister_pipeline(pgid);
+    let recv_stderr = recv_stderr.join().unwrap();
 
     if !source_send_finished.success() || !destination_recv_finished.success() {
-        return Err(anyhow!("There was a problem with the zfs-send|zfs-recv processes. Exit status: send {source_send_finished}, recv {destination_recv_finished}"));
+        return Err(PipelineError { send: source_send_finished, recv: destination_recv_finished, recv_stderr }.into());
     }
+    Ok(bytes_sent)
+}
 
-    Ok(format!(r#"Successfully synchronized "{src_ds}" to "{dst_ds}"."#))
+/// The rate limit of `opts.ratelimit`, in bytes per second, for the progress bar to show; None if
+/// there's none. A rate of 0 doesn't limit anything.
+fn rate_cap(opts: &ReplicateDatasetOpts) -> Option<u64> {
+    opts.ratelimit.as_deref().and_then(|rate| parse_rate(rate).ok()).filter(|&rate| rate > 0)
 }
 
-/// Returns the zfs-send process, the zfs-recv process, and (if requested) the pv process, in this order.
+/// Whether the bytes on the wire may differ in number from those zfs-send reports, making them worth
+/// counting (see [relay_counting]): the stream is compressed by zfs-send itself (-c, or -w), or
+/// goes through ssh(1), and with it maybe --transport-compress.
+fn wire_may_differ(src_machine: &Machine, dst_machine: &Machine, opts: &ReplicateDatasetOpts) -> bool {
+    opts.send_flags.compressed || opts.send_flags.raw
+        || src_machine.location != Location::Local || dst_machine.location != Location::Local
+}
+
+/// How many of the last lines of zfs-recv's stderr go into a [PipelineError].
+const RECV_STDERR_TAIL: usize = 5;
+
+/// Reads the stderr of zfs-recv through to the end, and returns its last [RECV_STDERR_TAIL] lines;
+/// which is where it says why it failed, e.g. "destination has been modified" or "out of space".
+/// Every line is logged at debug level too, so that --verbose shows them all, as they come.
+fn drain_recv_stderr<R: Read>(stream: R) -> Vec<String> {
+    let mut tail = VecDeque::with_capacity(RECV_STDERR_TAIL);
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { break };
+        log::debug!("zfs recv: {line}");
+        if tail.len() == RECV_STDERR_TAIL {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+    tail.into()
+}
+
+/// The processes of a transfer, as spawned by [pipe_with_ratelimit]: zfs-send, zfs-recv, (if any)
+/// pv or mbuffer, and (if any) the relay thread, in this order.
+type Pipeline = (Child, Child, Option<Child>, Option<JoinHandle<()>>);
+
+/// Returns the zfs-send process, the zfs-recv process, (if any) the pv or mbuffer process, and (if
+/// any) the relay thread, in this order.
+/// They all run in a new process group, led by zfs-send, so that they don't get the SIGINT of a
+/// Ctrl-C on the terminal, but are stopped by [interrupt] instead, all of them.
+/// When both ends are local and there's nothing in between (no `buffer_cmd`, nor stream filters),
+/// zfs-send and zfs-recv are spawned directly, rather than each through a `sh -c` of its own; see
+/// [local_cmd_without_shell].
+/// Given `wire_bytes`, the stream goes through a thread of ours on its way into zfs-recv instead,
+/// which counts the bytes in it into `wire_bytes`; see [relay_counting].
 fn pipe_with_ratelimit(
     source_send_cmd: &mut Command,
     destination_recv_cmd: &mut Command,
-    ratelimit: &Option<String>
-) -> Result<(Child, Child, Option<Child>), anyhow::Error>
+    buffer_cmd: Option<Command>,
+    wire_bytes: Option<Arc<AtomicU64>>,
+) -> Result<Pipeline, anyhow::Error>
 {
     let mut source_send_process;
-    let destination_recv_process;
-    let mut pv_ratelimit_option = None;
+    let mut destination_recv_process;
+    let mut buffer_process_option = None;
+    let (mut direct_send_cmd, mut direct_recv_cmd);
+    let (source_send_cmd, destination_recv_cmd) = match (&buffer_cmd, local_cmd_without_shell(source_send_cmd), local_cmd_without_shell(destination_recv_cmd)) {
+        (None, Some(send_cmd), Some(recv_cmd)) => {
+            log::debug!("Both ends are local: running zfs send and zfs recv without a shell.");
+            direct_send_cmd = send_cmd;
+            direct_send_cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+            direct_recv_cmd = recv_cmd;
+            direct_recv_cmd.stdout(Stdio::null()).stderr(Stdio::piped());
+            (&mut direct_send_cmd, &mut direct_recv_cmd)
+        }
+        _ => (source_send_cmd, destination_recv_cmd),
+    };
     // Pipe the sending process into the receiving process, and spawn them both.
     // It's a bit of a shame that there's no natural way (using std::process) to set up the pipes
     // before spawning any of the child processes, but oh well.
-    match ratelimit{
-        None => {
-            source_send_process = source_send_cmd.spawn().context("Failed to spawn source-side send process.")?;
-            destination_recv_cmd.stdin(source_send_process.stdout.take().unwrap());
-            destination_recv_process = destination_recv_cmd.spawn().context("Failed to spawn destination-side recv process.")?;
-        }
-        Some(lim) => {
-            let mut pv_ratelimit_cmd = std::process::Command::new("pv");
-            pv_ratelimit_cmd.args(["-q", "-L", lim.as_str()])
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped());
-            source_send_process = source_send_cmd.spawn().context("Failed to spawn source-side send process.")?;
-            pv_ratelimit_cmd.stdin(source_send_process.stdout.take().unwrap());
-            let mut pv_ratelimit_process = pv_ratelimit_cmd.spawn().context("Failed to sneed.")?;
-            destination_recv_cmd.stdin(pv_ratelimit_process.stdout.take().unwrap());
-            pv_ratelimit_option = Some(pv_ratelimit_process);
-            destination_recv_process = destination_recv_cmd.spawn().context("Failed to spawn destination-side recv process.")?;
-        }
-    }
-    Ok((source_send_process, destination_recv_process, pv_ratelimit_option))
-}
\ No newline at end of file
+    source_send_cmd.process_group(0);
+    source_send_process = source_send_cmd.spawn().context("Failed to spawn source-side send process.")?;
+    destination_recv_cmd.process_group(source_send_process.id() as i32);
+    let upstream = match buffer_cmd {
+        None => source_send_process.stdout.take().unwrap(),
+        Some(mut buffer_cmd) => {
+            buffer_cmd.stdout(Stdio::piped());
+            buffer_cmd.process_group(source_send_process.id() as i32);
+            buffer_cmd.stdin(source_send_process.stdout.take().unwrap());
+            let mut buffer_process = buffer_cmd.spawn().context(format!("Failed to spawn {:?}.", buffer_cmd.get_program()))?;
+            let upstream = buffer_process.stdout.take().unwrap();
+            buffer_process_option = Some(buffer_process);
+            upstream
+        }
+    };
+    let Some(wire_bytes) = wire_bytes else {
+        destination_recv_cmd.stdin(upstream);
+        destination_recv_process = destination_recv_cmd.spawn().context("Failed to spawn destination-side recv process.")?;
+        return Ok((source_send_process, destination_recv_process, buffer_process_option, None));
+    };
+    destination_recv_cmd.stdin(Stdio::piped());
+    destination_recv_process = destination_recv_cmd.spawn().context("Failed to spawn destination-side recv process.")?;
+    let downstream = destination_recv_process.stdin.take().unwrap();
+    let relay = thread::spawn(move || relay_counting(upstream, downstream, &wire_bytes));
+    Ok((source_send_process, destination_recv_process, buffer_process_option, Some(relay)))
+}
+
+/// Copies `from` into `to` until either end is done with, adding up the bytes copied into
+/// `counter` as it goes. Which, between zfs-send and zfs-recv, are those which go over the wire:
+/// compressed by `zfs send -c` and --transport-compress, unlike those zfs-send reports.
+/// Either end is dropped when done, so that the other one sees EOF, or EPIPE, and stops as well.
+fn relay_counting<R: Read, W: Write>(mut from: R, mut to: W, counter: &AtomicU64) {
+    let mut buf = vec![0; 128 * 1024];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                log::debug!("Relay: reading the stream: {e}");
+                break;
+            }
+        };
+        if let Err(e) = to.write_all(&buf[..n]) {
+            log::debug!("Relay: writing the stream: {e}");
+            break;
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Takes snapshot `snap_name` of `src_ds`, as requested by --take-snap-now, and refreshes its
+/// snapshots ([Machine::create_snap_with_name] does), so that the new one is its newest, which is
+/// what gets sent; even if the datasets were up-to-date before.
+fn take_snap_now(src_machine: &Machine, src_ds: &mut Dataset, snap_name: &str, recursive: bool, dry_run: bool) -> Result<(), anyhow::Error> {
+    let recursively = if recursive { ", recursively" } else { "" };
+    if dry_run {
+        eprintln!(r#"Would take snapshot "{src_machine}:{src_ds}@{snap_name}"{recursively} (requested by --take-snap-now)."#);
+        // Stand-in for the snapshot that would have been taken, so that the planned commands refer to it.
+        src_ds.snaps.push(Snap { name: snap_name.to_owned(), ..Snap::default() });
+        return Ok(());
+    }
+    eprintln!(r#"Taking snapshot "{src_machine}:{src_ds}@{snap_name}"{recursively} (requested by --take-snap-now)."#);
+    src_machine.create_snap_with_name(src_ds, snap_name, recursive).context("Failed to take snapshot (requested by --take-snap-now).")?;
+    Ok(())
+}
+
+/// Bookmarks the newest snapshot of `src_ds`, as requested by --bookmark-on-send. On re-runs the
+/// bookmark may well exist already, which is fine.
+fn bookmark_newest(src_machine: &Machine, src_ds: &Dataset, dry_run: bool) -> Result<(), anyhow::Error> {
+    let newest = &src_ds.newest_snap().name;
+    if dry_run {
+        eprintln!(r#"Would create bookmark "{src_machine}:{src_ds}#{newest}" (requested by --bookmark-on-send)."#);
+        return Ok(());
+    }
+    match src_machine.create_bookmark(src_ds, newest) {
+        Ok(()) | Err(MachineError::NameAlreadyInUse) => Ok(()),
+        Err(e) => Err(e).context(format!(r#"Failed to create bookmark "{src_machine}:{src_ds}#{newest}"."#)),
+    }
+}
+
+/// Holds `{dst_ds}@{newest}` with `tag`, then releases that tag from `{dst_ds}@{previous}`, as
+/// requested by --hold-latest; holding first, so that there's never no snapshot pinned. Either
+/// may well be done already, e.g. on re-runs, which is fine.
+fn hold_newest(dst_machine: &Machine, dst_ds: &Dataset, newest: &str, previous: Option<&str>, tag: &str, dry_run: bool) -> Result<(), anyhow::Error> {
+    let previous = previous.filter(|previous| *previous != newest);
+    if dry_run {
+        eprintln!(r#"Would hold "{dst_machine}:{dst_ds}@{newest}" with tag "{tag}" (requested by --hold-latest)."#);
+        if let Some(previous) = previous {
+            eprintln!(r#"Would release hold "{tag}" from "{dst_machine}:{dst_ds}@{previous}"."#);
+        }
+        return Ok(());
+    }
+    match dst_machine.hold_snapshot(dst_ds, newest, tag) {
+        Ok(()) | Err(MachineError::NameAlreadyInUse) => (),
+        Err(e) => return Err(e).context(format!(r#"Failed to hold "{dst_machine}:{dst_ds}@{newest}" with tag "{tag}"."#)),
+    }
+    if let Some(previous) = previous {
+        match dst_machine.release_hold(dst_ds, previous, tag) {
+            // The previous base may have been sent from a bookmark, or held by hand, or not at all.
+            Ok(()) | Err(MachineError::NoSuchHold) | Err(MachineError::NoDataset) => (),
+            Err(e) => return Err(e).context(format!(r#"Failed to release hold "{tag}" from "{dst_machine}:{dst_ds}@{previous}"."#)),
+        }
+    }
+    Ok(())
+}
+
+/// Destroys the snapshots of `dst_ds` which `src_ds` doesn't have (anymore), as requested by
+/// --prune-destination. In dry-run mode, prints the `zfs destroy` command instead.
+fn prune_destination(src_ds: &Dataset, dst_machine: &Machine, dst_ds: &Dataset, dry_run: bool) -> Result<(), anyhow::Error> {
+    let Some(to_destroy) = snaps_to_prune(src_ds, dst_ds).context(format!(r#"Refusing to prune "{dst_machine}:{dst_ds}"."#))? else {
+        eprintln!(r#"Nothing to prune: every snapshot of "{dst_machine}:{dst_ds}" also exists in "{src_ds}"."#);
+        return Ok(());
+    };
+    if dry_run {
+        println!("zfs destroy -v {dst_ds}@\\\n{to_destroy}");
+        return Ok(());
+    }
+    eprintln!(r#"Pruning snapshots of "{dst_machine}:{dst_ds}" which no longer exist in "{src_ds}" (requested by --prune-destination)."#);
+    let destroyed = dst_machine.destroy_snaps(dst_ds, &to_destroy, false).context(format!(r#"Failed to prune snapshots of "{dst_machine}:{dst_ds}"."#))?;
+    eprint!("{destroyed}");
+    Ok(())
+}
+
+/// Renders, in the form taken by [Machine::destroy_snaps], the snapshots of `dst_ds` not found in
+/// `src_ds`; or None, if there are none. Held snapshots are left alone.
+/// Fails unless `dst_ds` is up-to-date with, or behind, `src_ds`; otherwise the snapshots only the
+/// destination has may well be the only copy of some data.
+fn snaps_to_prune(src_ds: &Dataset, dst_ds: &Dataset) -> Result<Option<String>, anyhow::Error> {
+    match find_mrcud(src_ds, dst_ds) {
+        UpToDate(_) | SourceHasMore(_) => (),
+        NoneInCommon => bail!("The datasets have no snapshots in common."),
+        Divergence(mrc) | DestinationHasMore(mrc) => bail!(r#"The destination has snapshots newer than "{mrc}", the most recent snapshot in common."#),
+    }
+    let (comm, _) = src_ds.comm(dst_ds);
+    let destination_only = comm.iter()
+        .filter(|(side, _)| matches!(side, Comm::RIGHT))
+        .map(|(_, snap)| snap.guid)
+        .collect::<HashSet<_>>();
+    let tagged = dst_ds.tag_snaps_for_deletion(|s| !destination_only.contains(&s.guid) || s.holds != 0);
+    if tagged.iter().all(|(keep, _)| *keep) {
+        return Ok(None);
+    }
+    Ok(Some(render_tagged_snaps_for_deletion(tagged)))
+}
+
+/// Renders the pipeline `pipe_with_ratelimit` would set up, as a shell-like command line.
+fn render_pipeline(source_send_cmd: &Command, destination_recv_cmd: &Command, buffer_cmd: &Option<Command>) -> String {
+    let mut stages = vec![render_cmd(source_send_cmd)];
+    if let Some(buffer_cmd) = buffer_cmd {
+        stages.push(render_cmd(buffer_cmd));
+    }
+    stages.push(render_cmd(destination_recv_cmd));
+    stages.join(" | ")
+}
+
+fn render_cmd(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.contains(char::is_whitespace) { format!("'{arg}'") } else { arg.into_owned() }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[test]
+fn test_render_pipeline() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let (zelda, zelda_webdata) = crate::dataset::parse_spec("zelda:zelda/webdata").unwrap();
+    let send = Machine::from(crate::machine::Location::Local).fullsend_s(&tank_webdata, tank_webdata.oldest_snap(), &SendFlags::default());
+    let recv = zelda.recv(&zelda_webdata, &RecvFlags::default(), &[]);
+    assert_eq!(
+        render_pipeline(&send, &recv, &buffer_cmd(&Some("50M".to_string()), &None)),
+        "sh -c 'zfs send -vP -cpLe tank/webdata@2021-08-30' | pv -q -L 50M | ssh zelda -- 'zfs recv -s zelda/webdata'"
+    );
+    let mbuffer = Some(BufferTool::Mbuffer { size: "256M".to_string() });
+    assert_eq!(
+        render_pipeline(&send, &recv, &buffer_cmd(&Some("50M".to_string()), &mbuffer)),
+        "sh -c 'zfs send -vP -cpLe tank/webdata@2021-08-30' | mbuffer -q -m 256M -s 128k -r 50M | ssh zelda -- 'zfs recv -s zelda/webdata'"
+    );
+    assert!(buffer_cmd(&None, &Some(BufferTool::Pv)).is_none());
+}
+
+#[test]
+fn test_snaps_to_prune() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let zelda_webdata_divergence = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-divergence.list")
+    );
+    // tank/webdata starts at 2021-08-30; everything zelda/webdata has from before that goes.
+    assert_eq!(snaps_to_prune(&tank_webdata, &zelda_webdata).unwrap().unwrap(), "2020-12-06%2021-08-29");
+    assert!(snaps_to_prune(&tank_webdata, &zelda_webdata_divergence).is_err());
+    assert_eq!(snaps_to_prune(&tank_webdata, &tank_webdata).unwrap(), None);
+}
+
+#[test]
+fn test_retry_delay() {
+    assert_eq!(retry_delay(1), Duration::from_secs(5));
+    assert_eq!(retry_delay(2), Duration::from_secs(10));
+    assert_eq!(retry_delay(4), Duration::from_secs(40));
+    assert_eq!(retry_delay(7), Duration::from_secs(300));
+    assert_eq!(retry_delay(100), Duration::from_secs(300));
+}
+
+#[test]
+fn test_count_snaps_after() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let bookmarks = crate::machine::parse_zfs_bookmarks(include_str!("dataset/tests/tank_webdata-bookmarks.list")).unwrap();
+    assert_eq!(count_snaps_after(&tank_webdata, tank_webdata.newest_snap()), 0);
+    assert_eq!(count_snaps_after(&tank_webdata, tank_webdata.oldest_snap()), 81);
+    // #2021-08-29 is a bookmark of a snapshot tank/webdata no longer has; #2021-09-02 is not.
+    assert_eq!(count_snaps_after(&tank_webdata, &bookmarks[1]), 82);
+    assert_eq!(count_snaps_after(&tank_webdata, &bookmarks[2]), 80);
+}
+
+#[test]
+fn test_set_up_stream_filters() {
+    let opts = ReplicateDatasetOpts {
+        ratelimit: Some("50M".to_string()),
+        ratelimit_side: RatelimitSide::Remote,
+        transport_compress: Some(TransportCompression::Zstd),
+        send_flags: SendFlags { compressed: false, ..SendFlags::default() },
+        ..ReplicateDatasetOpts::default()
+    };
+    let mut local = Machine::from(Location::Local);
+    let (mut zelda, zelda_webdata) = crate::dataset::parse_spec("zelda:zelda/webdata").unwrap();
+    set_up_stream_filters(&mut local, &mut zelda, &opts).unwrap();
+    assert_eq!(local.stream_filters, vec!["zstd -q -c"]);
+    // Rate-limited while still compressed.
+    assert_eq!(zelda.stream_filters, vec!["pv -q -L 50M", "zstdcat -q"]);
+    assert_eq!(
+        zelda.recv(&zelda_webdata, &RecvFlags::default(), &[]).get_args().last().unwrap(),
+        "pv -q -L 50M | zstdcat -q | zfs recv -s zelda/webdata"
+    );
+    // Pulling instead: the source runs both.
+    let (mut local, mut zelda) = (Machine::from(Location::Local), crate::dataset::parse_spec("zelda:zelda/webdata").unwrap().0);
+    set_up_stream_filters(&mut zelda, &mut local, &opts).unwrap();
+    assert_eq!(zelda.stream_filters, vec!["zstd -q -c", "pv -q -L 50M"]);
+    assert_eq!(local.stream_filters, vec!["zstdcat -q"]);
+    // zfs send -c output doesn't compress any further.
+    let opts = ReplicateDatasetOpts { send_flags: SendFlags::default(), ..opts };
+    let (mut local, mut zelda) = (Machine::from(Location::Local), crate::dataset::parse_spec("zelda:zelda/webdata").unwrap().0);
+    set_up_stream_filters(&mut local, &mut zelda, &opts).unwrap();
+    assert!(local.stream_filters.is_empty());
+    assert_eq!(zelda.stream_filters, vec!["pv -q -L 50M"]);
+}
+
+#[test]
+fn test_recv_properties() {
+    let mut opts = ReplicateDatasetOpts::default();
+    assert!(recv_properties(&opts).is_empty());
+    opts.safe_recv = true;
+    assert_eq!(recv_properties(&opts), [("canmount".to_string(), Some("noauto".to_string())), ("readonly".to_string(), Some("on".to_string()))]);
+    // What's asked for explicitly prevails.
+    opts.recv_properties = vec![("readonly".to_string(), None)];
+    assert_eq!(recv_properties(&opts), [("readonly".to_string(), None), ("canmount".to_string(), Some("noauto".to_string()))]);
+    let src_ds = "tank/webdata".parse::<Dataset>().unwrap();
+    let cmd = recv_cmd(&Machine::from(Location::Local), &src_ds, &"backup/webdata".parse().unwrap(), &opts);
+    assert_eq!(cmd.get_args().last().unwrap(), "zfs recv -s -u -x readonly -o canmount='noauto' backup/webdata");
+}
+
+#[test]
+fn test_needs_full_resend() {
+    use crate::dataset::build_fake_dataset;
+    let mut local = Machine::from(Location::Local);
+    let mut tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let zelda_webdata_divergence = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-divergence.list")
+    );
+    assert!(!needs_full_resend(&mut local, &mut tank_webdata, &zelda_webdata).unwrap());
+    assert!(needs_full_resend(&mut local, &mut tank_webdata, &zelda_webdata_divergence).unwrap());
+}
+
+#[test]
+fn test_snaps_rolled_back() {
+    use crate::dataset::build_fake_dataset;
+    let tank_webdata = build_fake_dataset(
+        "tank/webdata",
+        include_str!("dataset/tests/tank_webdata.list")
+    );
+    let zelda_webdata_divergence = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-divergence.list")
+    );
+    let Divergence(mrc) = find_mrcud(&tank_webdata, &zelda_webdata_divergence) else {
+        panic!("zelda_webdata-divergence.list is meant to diverge from tank_webdata.list");
+    };
+    let rolled_back = snaps_rolled_back(&zelda_webdata_divergence, mrc);
+    assert!(!rolled_back.is_empty());
+    assert!(rolled_back.iter().all(|s| s.creation > mrc.creation));
+    assert!(snaps_rolled_back(&tank_webdata, tank_webdata.newest_snap()).is_empty());
+}
+
+#[test]
+fn test_render_many_summary() {
+    let ok = ReplicateResult {
+        source: "tank/webdata".to_string(),
+        destination: "zelda:zelda/webdata".to_string(),
+        base: None,
+        snapshots_sent: 2,
+        bytes_sent: 1024,
+        status: ReplicateStatus::Synchronized,
+        message: r#"Successfully synchronized "tank/webdata" to "zelda/webdata"."#.to_string(),
+    };
+    let outcomes = vec![
+        ("localhost:tank/webdata -> zelda:zelda/webdata".to_string(), Ok(ok)),
+        ("localhost:tank/mail -> zelda:zelda/mail".to_string(), Err(anyhow!("No such dataset.")).context("Unable to get snapshots")),
+    ];
+    assert_eq!(render_many_summary(&outcomes, false), r#"OK      localhost:tank/webdata -> zelda:zelda/webdata: Successfully synchronized "tank/webdata" to "zelda/webdata".
+FAILED  localhost:tank/mail -> zelda:zelda/mail: Unable to get snapshots: No such dataset."#);
+    let json: serde_json::Value = serde_json::from_str(&render_many_summary(&outcomes, true)).unwrap();
+    assert_eq!(json[0]["status"], "synchronized");
+    assert_eq!(json[1]["job"], "localhost:tank/mail -> zelda:zelda/mail");
+    assert_eq!(json[1]["error"], "Unable to get snapshots: No such dataset.");
+}
+
+#[test]
+fn test_recv_path_remap() {
+    let (_, src_ds) = crate::dataset::parse_spec("tank/lxc/web").unwrap();
+    let (zelda, _) = crate::dataset::parse_spec("zelda:zelda/backup").unwrap();
+    let zelda_backup = || "zelda/backup".parse::<Dataset>().unwrap();
+    let mut opts = ReplicateDatasetOpts { no_mount: true, recv_path_remap: Some(RecvPathRemap::AllButPool), ..ReplicateDatasetOpts::default() };
+    let mut dst_ds = zelda_backup();
+    destination_name(&src_ds, &mut dst_ds, opts.recv_path_remap).unwrap();
+    assert_eq!(dst_ds.fullname(), "zelda/backup/lxc/web");
+    assert_eq!(recv_cmd(&zelda, &src_ds, &dst_ds, &opts).get_args().last().unwrap(), "zfs recv -s -u -d zelda/backup");
+    opts.recv_path_remap = Some(RecvPathRemap::LastElement);
+    let mut dst_ds = zelda_backup();
+    destination_name(&src_ds, &mut dst_ds, opts.recv_path_remap).unwrap();
+    assert_eq!(dst_ds.fullname(), "zelda/backup/web");
+    assert_eq!(recv_cmd(&zelda, &src_ds, &dst_ds, &opts).get_args().last().unwrap(), "zfs recv -s -u -e zelda/backup");
+    // The relative path would be appended on top of what zfs-recv appends.
+    let (_, relative_src_ds) = crate::dataset::parse_spec("tank//lxc/web").unwrap();
+    assert!(destination_name(&relative_src_ds, &mut zelda_backup(), opts.recv_path_remap).is_err());
+}
+
+#[test]
+fn test_ensure_source_snaps() {
+    let local = Machine::from(Location::Local);
+    let mut empty = crate::dataset::build_fake_dataset("tank/empty", "");
+    assert!(empty.snaps.is_empty());
+    let mut opts = ReplicateDatasetOpts { dry_run: true, ..ReplicateDatasetOpts::default() };
+    let err = ensure_source_snaps(&local, &mut empty, &mut opts).unwrap_err();
+    assert!(err.to_string().contains("has no snapshots to replicate"));
+    // The snapshot of --take-snap-now is taken beforehand instead, and only once.
+    opts.take_snap_now = Some("first".to_string());
+    ensure_source_snaps(&local, &mut empty, &mut opts).unwrap();
+    assert_eq!(empty.newest_snap().name, "first");
+    assert_eq!(opts.take_snap_now, None);
+    let mut tank_webdata = crate::dataset::build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    opts.take_snap_now = Some("last".to_string());
+    ensure_source_snaps(&local, &mut tank_webdata, &mut opts).unwrap();
+    assert_eq!(opts.take_snap_now.as_deref(), Some("last"));
+}
+
+#[test]
+fn test_take_snap_now_up_to_date() {
+    use crate::dataset::build_fake_dataset;
+    use crate::machine::FakeZfs;
+    let local = Machine::from(Location::Local);
+    let listing = include_str!("dataset/tests/tank_webdata.list");
+    let mut tank_webdata = build_fake_dataset("tank/webdata", listing);
+    let zelda_webdata = build_fake_dataset("zelda/webdata", &listing.replace("tank/", "zelda/"));
+    let UpToDate(mrc) = find_mrcud(&tank_webdata, &zelda_webdata) else { panic!("Expected the datasets to be up-to-date.") };
+    let mrc = mrc.clone();
+    take_snap_now(&local, &mut tank_webdata, "now", false, true).unwrap();
+    assert_eq!(tank_webdata.newest_snap().name, "now");
+    assert!(matches!(find_mrcud(&tank_webdata, &zelda_webdata), SourceHasMore(s) if *s == mrc));
+    let cmd = local.send_from_s_till_newest(&tank_webdata, &mrc, false, &SendFlags::default());
+    assert!(cmd.get_args().last().unwrap().to_str().unwrap().ends_with("@2021-11-21 tank/webdata@now"));
+    assert_eq!(count_snaps_after(&tank_webdata, &mrc), 1);
+
+    // For real, against a stand-in for zfs(8), which lists tank/webdata@now once it has been taken.
+    let zfs = FakeZfs::new("take-snap-now", r#"
+    "snapshot tank/webdata@now") touch $DIR/taken ;;
+    "list "*" tank/webdata")
+        cat $FIXTURES/tank_webdata.list
+        [ -e $DIR/taken ] && printf 'tank/webdata@now\t1637546406\t1234567890123456789\t0\t0\t52009129165\t0\t30909281\n' ;;
+"#);
+    let machine = zfs.machine();
+    let mut tank_webdata = build_fake_dataset("tank/webdata", listing);
+    take_snap_now(&machine, &mut tank_webdata, "now", false, false).unwrap();
+    // Refreshed from the listing, rather than made up.
+    let now = tank_webdata.newest_snap();
+    assert_eq!((now.name.as_str(), now.guid, now.creation.timestamp()), ("now", 1234567890123456789, 1637546406));
+    assert_eq!(tank_webdata.snaps.len(), 83);
+    assert!(matches!(find_mrcud(&tank_webdata, &zelda_webdata), SourceHasMore(s) if *s == mrc));
+    assert!(take_snap_now(&machine, &mut "tank/nope".parse().unwrap(), "now", false, false).is_err());
+}
+
+#[test]
+fn test_replicate_init() {
+    use crate::machine::FakeZfs;
+    // A stand-in for zfs(8), which knows tank/webdata, and nothing else.
+    let zfs = FakeZfs::new("init", r#"
+    "list "*" tank/webdata") cat $FIXTURES/tank_webdata.list ;;
+"#);
+
+    for (take_snap_now, init_latest) in [(None, false), (Some("now"), false), (None, true), (Some("now"), true)] {
+        let (mut src_machine, mut dst_machine) = (zfs.machine(), zfs.machine());
+        let mut src_ds = "tank/webdata".parse::<Dataset>().unwrap();
+        let mut dst_ds = "backup/webdata".parse::<Dataset>().unwrap();
+        let opts = ReplicateDatasetOpts {
+            init_nonexistent_destination: true,
+            take_snap_now: take_snap_now.map(str::to_string),
+            init_latest,
+            dry_run: true,
+            ..ReplicateDatasetOpts::default()
+        };
+        let result = replicate_dataset(&mut src_machine, &mut src_ds, &mut dst_machine, &mut dst_ds, opts).unwrap();
+        assert_eq!(result.status, ReplicateStatus::DryRun);
+        let newest = take_snap_now.unwrap_or("2021-11-21");
+        if init_latest {
+            // The newest snapshot in full, and nothing else.
+            assert_eq!(result.base.unwrap().name, newest);
+            assert_eq!(result.snapshots_sent, 1);
+            assert_eq!(dst_ds.snaps.len(), 1);
+            assert!(result.message.ends_with(&format!(r#"up to "{newest}"."#)));
+            continue;
+        }
+        // The oldest snapshot in full, then every other one, in the same run.
+        assert_eq!(result.base.unwrap().name, "2021-08-30");
+        assert_eq!(result.snapshots_sent, 82 + take_snap_now.iter().count());
+        // The snapshot to take is taken only once, before the full send, and caught up to.
+        assert_eq!(src_ds.snaps.iter().filter(|s| Some(s.name.as_str()) == take_snap_now).count(), take_snap_now.iter().count());
+        assert_eq!(dst_ds.snaps.len(), 1);
+        assert!(result.message.ends_with(&format!(r#"from "2021-08-30" to "{newest}"."#)));
+    }
+}
+
+#[test]
+fn test_truncate_until() {
+    use crate::dataset::build_fake_dataset;
+    let local = Machine::from(Location::Local);
+    let mut tank_webdata = build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let zelda_webdata = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata.list"));
+    let len = tank_webdata.snaps.len();
+    assert!(truncate_until(&local, &mut tank_webdata, "no-such-snapshot").is_err());
+    assert_eq!(tank_webdata.snaps.len(), len);
+    truncate_until(&local, &mut tank_webdata, "2021-11-18").unwrap();
+    assert_eq!(tank_webdata.newest_snap().name, "2021-11-18");
+    assert_eq!(tank_webdata.snaps.len(), len - 3);
+    assert!(matches!(find_mrcud(&tank_webdata, &zelda_webdata), SourceHasMore(_)));
+    // The destination already has more than what --until asks for.
+    truncate_until(&local, &mut tank_webdata, "2021-09-05").unwrap();
+    assert!(matches!(find_mrcud(&tank_webdata, &zelda_webdata), DestinationHasMore(_)));
+}
+
+#[test]
+fn test_pipeline_error() {
+    use std::os::unix::process::ExitStatusExt;
+    let output = (1..=7).map(|n| format!("line {n}\n")).collect::<String>()
+        + "cannot receive incremental stream: destination tank/webdata has been modified\n";
+    let recv_stderr = drain_recv_stderr(output.as_bytes());
+    assert_eq!(recv_stderr.len(), RECV_STDERR_TAIL);
+    assert_eq!(recv_stderr[0], "line 4");
+    let err = PipelineError { send: ExitStatus::from_raw(0), recv: ExitStatus::from_raw(1 << 8), recv_stderr };
+    let err = err.to_string();
+    assert!(err.starts_with("There was a problem with the zfs-send|zfs-recv processes. Exit status: send exit status: 0, recv exit status: 1\n  zfs recv: line 4\n"));
+    assert!(err.ends_with("\n  zfs recv: cannot receive incremental stream: destination tank/webdata has been modified"));
+    let err = PipelineError { send: ExitStatus::from_raw(0), recv: ExitStatus::from_raw(1 << 8), recv_stderr: vec![] };
+    assert!(err.to_string().ends_with("recv exit status: 1"));
+}
+
+#[test]
+fn test_rollback_to() {
+    use crate::machine::FakeZfs;
+    // A stand-in for zfs(8), which knows tank/webdata, and zelda/webdata diverging from it.
+    let zfs = FakeZfs::new("rollback-to", r#"
+    "list "*" tank/webdata") cat $FIXTURES/tank_webdata.list ;;
+    "list "*" zelda/webdata") cat $FIXTURES/zelda_webdata-divergence.list ;;
+    "get "*) echo - ;;
+"#);
+    let replicate = |rollback_to: Option<&str>| {
+        let (mut src_machine, mut dst_machine) = (zfs.machine(), zfs.machine());
+        let mut src_ds = "tank/webdata".parse::<Dataset>().unwrap();
+        let mut dst_ds = "zelda/webdata".parse::<Dataset>().unwrap();
+        let opts = ReplicateDatasetOpts { rollback_to: rollback_to.map(str::to_string), dry_run: true, ..ReplicateDatasetOpts::default() };
+        replicate_dataset(&mut src_machine, &mut src_ds, &mut dst_machine, &mut dst_ds, opts).map(|result| (result, dst_ds))
+    };
+
+    assert!(replicate(None).is_err());
+    let (result, dst_ds) = replicate(Some("2021-11-14")).unwrap();
+    assert_eq!(result.base.unwrap().name, "2021-11-14");
+    assert_eq!(result.snapshots_sent, 7);
+    assert_eq!(dst_ds.newest_snap().name, "2021-11-14");
+    // An older snapshot in common will do too, destroying more.
+    let (result, _) = replicate(Some("2021-11-12")).unwrap();
+    assert_eq!(result.snapshots_sent, 9);
+    // Not one the source doesn't have.
+    let err = replicate(Some("2021-11-14DIVERGE")).unwrap_err();
+    assert!(err.to_string().contains("the source doesn't have it"));
+    assert!(replicate(Some("nope")).unwrap_err().to_string().contains("no such snapshot"));
+}
+
+#[test]
+fn test_run_replicate_hooks() {
+    let out = std::env::temp_dir().join(format!("zfs-rs-test-replicate-hooks-{}", std::process::id()));
+    let record = format!(r#"echo "$ZFS_RS_STATUS $ZFS_RS_SOURCE $ZFS_RS_DEST $ZFS_RS_BYTES $ZFS_RS_BASE_SNAP" > {}"#, out.display());
+    let synchronized = || Ok(ReplicateResult {
+        source: "tank/webdata".to_string(),
+        destination: "backup:backup/webdata".to_string(),
+        base: Some(Snap { name: "2021-11-20".to_string(), ..Snap::default() }),
+        snapshots_sent: 1,
+        bytes_sent: 1024,
+        status: ReplicateStatus::Synchronized,
+        message: String::new(),
+    });
+    let run = |outcome, opts: &ReplicateDatasetOpts| run_replicate_hooks("localhost:tank/webdata", "backup:backup/webdata", outcome, opts);
+
+    let opts = ReplicateDatasetOpts { on_success: Some(record.clone()), on_failure: Some(record.clone()), ..ReplicateDatasetOpts::default() };
+    run(synchronized(), &opts).unwrap();
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "synchronized localhost:tank/webdata backup:backup/webdata 1024 2021-11-20\n");
+    assert!(run(Err(anyhow!("Out of luck.")), &opts).is_err());
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "failed localhost:tank/webdata backup:backup/webdata 0 \n");
+    std::fs::remove_file(&out).unwrap();
+    // Not on dry runs.
+    run(synchronized(), &ReplicateDatasetOpts { dry_run: true, ..opts }).unwrap();
+    assert!(!out.exists());
+
+    // A failing hook changes nothing, unless strict.
+    let opts = ReplicateDatasetOpts { on_success: Some("false".to_string()), ..ReplicateDatasetOpts::default() };
+    assert!(run(synchronized(), &opts).is_ok());
+    assert!(run(synchronized(), &ReplicateDatasetOpts { strict_hooks: true, ..opts }).is_err());
+}
+
+#[test]
+fn test_replicate_many_same_host() {
+    // Two jobs to the same host, each with a master connection of its own, which finishing one
+    // job closes; so that it doesn't cut off the other, still running over its own.
+    let job = |src: &str, dst: &str| {
+        let (src_machine, src_ds) = crate::dataset::parse_spec(src).unwrap();
+        let (mut dst_machine, dst_ds) = crate::dataset::parse_spec(dst).unwrap();
+        dst_machine.enable_ssh_multiplexing();
+        ReplicateJob { src_machine, src_ds, dst_machine, dst_ds }
+    };
+    let jobs = vec![job("tank/webdata", "zelda.invalid:zelda/webdata"), job("tank/phone", "zelda.invalid:zelda/phone")];
+    let control_path = |job: &ReplicateJob| job.dst_machine.recv(&job.dst_ds, &RecvFlags::default(), &[]).get_args()
+        .find_map(|arg| arg.to_str().unwrap().strip_prefix("ControlPath=").map(str::to_string))
+        .unwrap();
+    assert_ne!(control_path(&jobs[0]), control_path(&jobs[1]));
+    // Both run at the same time, and fail on their own account (there is no such host).
+    let opts = ReplicateDatasetOpts { dry_run: true, ..ReplicateDatasetOpts::default() };
+    let outcomes = replicate_many_cli(jobs, &opts, 2);
+    assert_eq!(outcomes.len(), 2);
+    assert!(outcomes.iter().all(|(_, result)| result.is_err()));
+}
+
+#[test]
+fn test_relay_counting() {
+    let stream = (0..300_000u32).map(|n| n as u8).collect::<Vec<_>>();
+    let counter = AtomicU64::new(0);
+    let mut out = Vec::new();
+    relay_counting(stream.as_slice(), &mut out, &counter);
+    assert_eq!(out, stream);
+    assert_eq!(counter.load(Ordering::Relaxed), 300_000);
+    // zfs-recv gone: the relay stops, rather than spinning.
+    let counter = AtomicU64::new(0);
+    relay_counting(stream.as_slice(), &mut [0u8; 1000][..], &counter);
+    assert_eq!(counter.load(Ordering::Relaxed), 0);
+
+    // Nothing to count locally, unless zfs-send compresses the stream.
+    let local = Machine::from(Location::Local);
+    let remote = Machine::from(Location::Remote { user: None, host: "zelda".to_string(), port: None });
+    let uncompressed = ReplicateDatasetOpts { send_flags: SendFlags { compressed: false, ..SendFlags::default() }, ..ReplicateDatasetOpts::default() };
+    assert!(!wire_may_differ(&local, &local, &uncompressed));
+    assert!(wire_may_differ(&local, &remote, &uncompressed));
+    assert!(wire_may_differ(&local, &local, &ReplicateDatasetOpts { send_flags: SendFlags { compressed: true, ..SendFlags::default() }, ..ReplicateDatasetOpts::default() }));
+}
+
+#[test]
+fn test_recv_out_of_space() {
+    use std::os::unix::process::ExitStatusExt;
+    let recv_stderr = drain_recv_stderr("receiving incremental stream of tank/webdata@2021-11-21 into backup/webdata@2021-11-21\n\
+        cannot receive incremental stream: out of space\n".as_bytes());
+    let err = PipelineError { send: ExitStatus::from_raw(1 << 8), recv: ExitStatus::from_raw(1 << 8), recv_stderr };
+    assert!(matches!(err.recv_error(), Some(MachineError::OutOfSpace)));
+    // Explained without asking the destination anything.
+    let err = explain_recv_failure(&Machine::from(Location::Local), &"backup/webdata".parse().unwrap(), err.into());
+    assert!(matches!(err.downcast_ref::<MachineError>(), Some(MachineError::OutOfSpace)));
+    assert!(err.to_string().starts_with(r#"Pool "localhost:backup" ran out of space receiving into "backup/webdata"."#));
+    assert!(err.to_string().contains("--prune-destination"));
+
+    let err = PipelineError { send: ExitStatus::from_raw(0), recv: ExitStatus::from_raw(0), recv_stderr: vec![] };
+    assert!(err.recv_error().is_none());
+}
+
+#[test]
+fn test_check_max_snapshots() {
+    let local = Machine::from(Location::Local);
+    let tank_webdata = crate::dataset::build_fake_dataset("tank/webdata", include_str!("dataset/tests/tank_webdata.list"));
+    let len = tank_webdata.snaps.len();
+    assert!(check_max_snapshots(&local, &tank_webdata, None).is_ok());
+    assert!(check_max_snapshots(&local, &tank_webdata, Some(len)).is_ok());
+    let err = check_max_snapshots(&local, &tank_webdata, Some(len - 1)).unwrap_err();
+    assert!(err.to_string().starts_with(&format!(r#"Dataset "localhost:tank/webdata" has {len} snapshots, more than --max-snapshots ({}) allows."#, len - 1)));
+}
@@ -1,19 +1,606 @@
+use std::collections::HashSet;
 use std::fmt::Debug;
+use anyhow::{bail, Context};
+use chrono::{Datelike, DateTime, Duration};
+use chrono::offset::Utc;
+use indicatif::HumanBytes;
+use itertools::Itertools;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 
-use crate::machine::{Machine};
-use crate::dataset::{Dataset, };
+use crate::confirm::confirm;
+use crate::machine::{Machine, shell_quote_if_needed};
+use crate::dataset::{Dataset, Snap, render_tagged_snaps_for_deletion};
 
-#[derive(Copy, Clone, Debug)]
+/// A retention policy in the config file (see [crate::config]) goes by the same names, in TOML;
+/// `keep_since` as taken by [parse_keep_since].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct RetentionOpts {
+    /// Always keep the snapshots whose name doesn't match `normal_name`, e.g. ones taken by hand.
     pub keep_unusual: bool,
+    /// What the names of the snapshots taken on schedule look like; by default, "YYYY-MM-DD".
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub normal_name: Regex,
     pub run_directly: bool,
+    /// Keep this many of the most recent snapshots, no matter when they were taken.
+    pub keep_last: u32,
+    /// Keep the newest snapshot of each of the last `keep_daily` days.
+    pub keep_daily: u32,
+    /// Keep the newest snapshot of each of the last `keep_weekly` (ISO, Monday-starting) weeks.
+    pub keep_weekly: u32,
+    /// Keep the newest snapshot of each of the last `keep_monthly` calendar months.
+    pub keep_monthly: u32,
+    /// Keep the newest snapshot of each of the last `keep_yearly` calendar years.
+    pub keep_yearly: u32,
+    /// Keep every snapshot taken within this long before `now`.
+    #[serde(deserialize_with = "deserialize_keep_since")]
+    pub keep_since: Option<Duration>,
+    /// Keep the newest snapshots of each stream named by a pattern; see [KeepRule]. Snapshots that
+    /// match any of them aren't unusual, whatever `normal_name` says.
+    #[serde(deserialize_with = "deserialize_keep_rules")]
+    pub keep: Vec<KeepRule>,
+    /// Snapshots whose name matches are always kept, and don't count towards any of the above.
+    #[serde(deserialize_with = "deserialize_optional_regex")]
+    pub exclude: Option<Regex>,
+    /// Always keep this many of the newest snapshots, whatever the rest of the policy says; even
+    /// excluded ones count.
+    pub min_keep: u32,
+    /// Go ahead even if the policy would destroy every last snapshot.
+    pub allow_delete_all: bool,
+    /// The time as of which to apply the policy; the current time if None.
+    #[serde(skip)]
+    pub now: Option<DateTime<Utc>>,
+    /// Don't keep held snapshots just for being held. Those which the policy doesn't keep either get
+    /// destroyed with `zfs destroy -d`, i.e. as soon as their holds are released.
+    pub ignore_holds: bool,
+    /// Release the hold by this tag (e.g. the one of `zfs-rs replicate --hold-latest`) from the
+    /// snapshots which the policy doesn't keep, so that they can be destroyed; holds by any other
+    /// tag are left alone, and still keep their snapshots.
+    pub release_holds: Option<String>,
+}
+
+/// What `zfs-rs apply-retention` takes [RetentionOpts::normal_name] to be by default.
+pub const DEFAULT_NORMAL_NAME_PATTERN: &str = r"^\d{4}-\d{2}-\d{2}$";
+
+impl Default for RetentionOpts {
+    /// Keeps nothing, except for `min_keep` (1) and unusual snapshots; every `keep_*` is 0.
+    fn default() -> Self {
+        RetentionOpts {
+            keep_unusual: true,
+            normal_name: Regex::new(DEFAULT_NORMAL_NAME_PATTERN).unwrap(),
+            run_directly: false,
+            keep_last: 0,
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_yearly: 0,
+            keep_since: None,
+            keep: Vec::new(),
+            exclude: None,
+            min_keep: 1,
+            allow_delete_all: false,
+            now: None,
+            ignore_holds: false,
+            release_holds: None,
+        }
+    }
+}
+
+impl RetentionOpts {
+    /// Whether any of the `keep_*` settings would keep anything at all.
+    pub fn keeps_anything(&self) -> bool {
+        let buckets = [self.keep_last, self.keep_daily, self.keep_weekly, self.keep_monthly, self.keep_yearly];
+        return buckets.iter().any(|n| *n > 0) || self.keep_since.is_some() || self.keep.iter().any(|rule| rule.count > 0);
+    }
+}
+
+/// Keep the `count` newest snapshots whose name matches `pattern`, e.g. "hourly-*:24"; for
+/// datasets where several schedules (hourly-*, daily-*, manual-*...) take snapshots side by side.
+/// A snapshot that matches more than one rule counts towards the first one only.
+#[derive(Clone, Debug)]
+pub struct KeepRule {
+    pub pattern: Regex,
+    pub count: u32,
+}
+
+/// Parses a [KeepRule] of the form "GLOB:N". The glob may use `*` and `?`, and has to match the
+/// whole snapshot name. It may contain ':' itself; the count is what follows the last one.
+pub fn parse_keep_rule(s: &str) -> Result<KeepRule, String> {
+    let err = || format!("{s} isn't of the form GLOB:N, e.g. \"hourly-*:24\".");
+    let (glob, count) = s.rsplit_once(':').ok_or_else(err)?;
+    if glob.is_empty() {
+        return Err(err());
+    }
+    let count = count.parse().map_err(|_| err())?;
+    let pattern = glob.split('*')
+        .map(|part| part.split('?').map(regex::escape).join("."))
+        .join(".*");
+    let pattern = Regex::new(&format!("^{pattern}$")).map_err(|_| err())?;
+    Ok(KeepRule { pattern, count })
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    deserialize_regex(deserializer).map(Some)
+}
+
+fn deserialize_keep_rules<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<KeepRule>, D::Error> {
+    let rules = Vec::<String>::deserialize(deserializer)?;
+    rules.iter().map(|rule| parse_keep_rule(rule).map_err(serde::de::Error::custom)).collect()
+}
+
+fn deserialize_keep_since<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let span = String::deserialize(deserializer)?;
+    parse_keep_since(&span).map(Some).map_err(serde::de::Error::custom)
 }
 
-#[allow(warnings)]
 pub fn apply_retention(
     machine : &mut Machine,
     ds : &mut Dataset,
     opts: RetentionOpts
 ) -> Result<String, anyhow::Error> {
-    unimplemented!()
+    machine.get_snaps(ds).context(format!(r#"Unable to get snapshots for "{machine}:{ds}"."#))?;
+    if ds.snaps.is_empty() {
+        return Ok(format!(r#"Nothing to do: "{machine}:{ds}" has no snapshots."#));
+    }
+
+    let released = match &opts.release_holds {
+        Some(tag) => {
+            let held = ds.snaps.iter().filter(|s| s.holds != 0).map(|s| s.name.as_str()).collect::<Vec<_>>();
+            let holds = machine.get_holds(ds, &held).context(format!(r#"Unable to get the holds on snapshots of "{machine}:{ds}"."#))?;
+            discount_holds(ds, &holds, tag)
+        }
+        None => HashSet::new(),
+    };
+    let tagged = tag_for_retention(ds, &opts, opts.now.unwrap_or_else(Utc::now));
+
+    if tagged.iter().all(|(keep, _)| *keep) {
+        return Ok(format!(r#"Nothing to do: no snapshots of "{machine}:{ds}" fall outside the retention policy."#));
+    }
+    if tagged.iter().all(|(keep, _)| !keep) && !opts.allow_delete_all {
+        bail!(r#"Refusing to destroy every single snapshot of "{machine}:{ds}", as the retention policy says.
+Hint: double-check the --keep-* options, or use --allow-delete-all if this is really what you want."#);
+    }
+    eprintln!("{}", reclaim_estimate(&tagged));
+    // Only from snapshots to be destroyed; those kept, keep their hold too.
+    let to_release = tagged.iter()
+        .filter(|(keep, s)| !keep && released.contains(&s.guid))
+        .map(|(_, s)| s.name.clone())
+        .collect::<Vec<_>>();
+    // Left out by render_tagged_snaps_for_deletion; only tagged for deletion with opts.ignore_holds.
+    let held = tagged.iter()
+        .filter(|(keep, s)| !keep && s.holds != 0)
+        .map(|(_, s)| shell_quote_if_needed(&s.name))
+        .join(",");
+    let to_destroy = render_tagged_snaps_for_deletion(tagged);
+
+    if !opts.run_directly {
+        let releases = to_release.iter().map(|name| format!(
+            "zfs release {} {ds}@{}", shell_quote_if_needed(opts.release_holds.as_deref().unwrap()), shell_quote_if_needed(name)
+        ));
+        let commands = [
+            (!to_destroy.is_empty()).then(|| format!("zfs destroy -v {ds}@\\\n{to_destroy}")),
+            (!held.is_empty()).then(|| format!("zfs destroy -d -v {ds}@{held}")),
+        ];
+        return Ok(releases.chain(commands.into_iter().flatten()).join("\n"));
+    }
+    if !confirm(&format!(r#"Destroy these snapshots of "{machine}:{ds}"?"#)) {
+        bail!(r#"Not destroying any snapshots of "{machine}:{ds}"."#);
+    }
+    if let Some(tag) = &opts.release_holds {
+        for name in &to_release {
+            machine.release_hold(ds, name, tag).context(format!(r#"Failed to release hold "{tag}" from "{machine}:{ds}@{name}"."#))?;
+        }
+    }
+    let mut destroyed = String::new();
+    if !to_destroy.is_empty() {
+        destroyed += &machine.destroy_snaps(ds, &to_destroy, false).context(format!(r#"Failed to destroy snapshots of "{machine}:{ds}"."#))?;
+    }
+    if !held.is_empty() {
+        destroyed += &machine.destroy_snaps(ds, &held, true).context(format!(r#"Failed to mark held snapshots of "{machine}:{ds}" for deferred destruction."#))?;
+    }
+    Ok(destroyed)
+}
+
+/// Takes the holds by `tag`, out of `holds` (as listed by [Machine::get_holds]), off the hold
+/// counts of the snapshots of `ds`, as if already released; those holding nothing else become
+/// destroyable. Returns the guids of the snapshots which `tag` holds.
+fn discount_holds(ds: &mut Dataset, holds: &[(String, String)], tag: &str) -> HashSet<u64> {
+    let mut released = HashSet::new();
+    for s in ds.snaps.iter_mut() {
+        if s.holds != 0 && holds.iter().any(|(name, t)| *name == s.name && t == tag) {
+            s.holds -= 1;
+            released.insert(s.guid);
+        }
+    }
+    released
+}
+
+/// Tags the snapshots of `ds` as per [Dataset::tag_snaps_for_deletion]: true for those to keep.
+fn tag_for_retention<'a>(ds: &'a Dataset, opts: &RetentionOpts, now: DateTime<Utc>) -> Vec<(bool, &'a Snap)> {
+    let is_excluded = |s: &Snap| opts.exclude.as_ref().is_some_and(|re| re.is_match(&s.name));
+    let considered = ds.snaps.iter().filter(|s| !is_excluded(s)).cloned().collect::<Vec<_>>();
+    let keep = gfs_keep_set(&considered, opts, now);
+    let keep_by_rule = keep_rules_set(&considered, &opts.keep);
+    let newest = ds.snaps.iter().rev()
+        .take(opts.min_keep as usize)
+        .map(|s| s.guid)
+        .collect::<HashSet<_>>();
+    ds.tag_snaps_for_deletion(|s| {
+        // A "true" veredict is interpreted as TO KEEP
+        let bucket_decision = keep.contains(&s.guid) || keep_by_rule.contains(&s.guid);
+        let recent_decision = opts.keep_since.is_some_and(|since| s.creation > now - since);
+        let is_normal = opts.normal_name.is_match(&s.name) || opts.keep.iter().any(|rule| rule.pattern.is_match(&s.name));
+        let name_decision = opts.keep_unusual && !is_normal;
+        // zfs-destroy refuses to destroy held snapshots, and would abort the whole batch.
+        let holds_decision = s.holds != 0 && !opts.ignore_holds;
+        bucket_decision || recent_decision || name_decision || holds_decision || is_excluded(s) || newest.contains(&s.guid)
+    })
+}
+
+/// Parses a span of time for [RetentionOpts::keep_since]: a number of days, weeks, months or years,
+/// e.g. "14d", "2w", "6m", "1y". Months count as 30 days, and years as 365.
+pub fn parse_keep_since(s: &str) -> Result<Duration, String> {
+    let err = || format!("{s} isn't a span of time like 14d, 2w, 6m or 1y.");
+    let (number, unit_days) = match s.char_indices().last() {
+        Some((idx, 'd')) => (&s[..idx], 1),
+        Some((idx, 'w')) => (&s[..idx], 7),
+        Some((idx, 'm')) => (&s[..idx], 30),
+        Some((idx, 'y')) => (&s[..idx], 365),
+        _ => return Err(err()),
+    };
+    let number: i64 = number.parse().map_err(|_| err())?;
+    if number < 0 {
+        return Err(err());
+    }
+    number.checked_mul(unit_days).and_then(Duration::try_days).ok_or_else(err)
+}
+
+/// Summarizes what destroying the snapshots tagged for deletion (i.e. not TO KEEP) would achieve.
+/// The sum of their `used` is only a lower bound of the space freed: blocks shared by two or more of
+/// the destroyed snapshots (and by no others) count towards the `used` of none of them.
+fn reclaim_estimate(tagged: &[(bool, &Snap)]) -> String {
+    let (count, bytes) = tagged.iter()
+        .filter(|(keep, _)| !keep)
+        .fold((0, 0), |(count, bytes), (_, s)| (count + 1, bytes + s.used));
+    format!("This will destroy {count} snapshot(s), reclaiming ~{} (approximate; space shared only among them comes on top).", HumanBytes(bytes))
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Period {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+/// How many whole periods (days, weeks...) `t` lies in the past with respect to `now`.
+/// Zero means "in the same period as `now`"; negative values mean `t` is in the future.
+fn periods_ago(t: DateTime<Utc>, now: DateTime<Utc>, period: Period) -> i64 {
+    match period {
+        Period::Day => (now.date_naive() - t.date_naive()).num_days(),
+        Period::Week => {
+            let monday_of = |d: DateTime<Utc>| d.date_naive() - Duration::days(d.weekday().num_days_from_monday() as i64);
+            (monday_of(now) - monday_of(t)).num_days() / 7
+        }
+        Period::Month => (now.year() as i64 * 12 + now.month0() as i64) - (t.year() as i64 * 12 + t.month0() as i64),
+        Period::Year => now.year() as i64 - t.year() as i64,
+    }
+}
+
+/// Grandfather-father-son bucketing.
+/// Walk `snaps` (which must be sorted oldest-first) from newest to oldest, assigning each one to the
+/// most recent bucket it satisfies. Returns the guids of the snapshots which were assigned a bucket.
+/// A snapshot may be assigned to more than one bucket; e.g. the newest snapshot of this month is
+/// usually also the newest snapshot of today.
+fn gfs_keep_set(snaps: &[Snap], opts: &RetentionOpts, now: DateTime<Utc>) -> HashSet<u64> {
+    let mut keep = HashSet::new();
+    for s in snaps.iter().rev().take(opts.keep_last as usize) {
+        keep.insert(s.guid);
+    }
+    for (period, count) in [
+        (Period::Day, opts.keep_daily),
+        (Period::Week, opts.keep_weekly),
+        (Period::Month, opts.keep_monthly),
+        (Period::Year, opts.keep_yearly),
+    ] {
+        let mut filled = HashSet::new();
+        for s in snaps.iter().rev() {
+            let ago = periods_ago(s.creation, now, period);
+            if ago < 0 || ago >= count as i64 {
+                continue;
+            }
+            // Newest-first walk: the first snapshot seen for a given period is the one that stays.
+            if filled.insert(ago) {
+                keep.insert(s.guid);
+            }
+        }
+    }
+    keep
+}
+
+/// The guids of the `count` newest snapshots of each [KeepRule] in `rules`. `snaps` must be sorted
+/// oldest-first.
+fn keep_rules_set(snaps: &[Snap], rules: &[KeepRule]) -> HashSet<u64> {
+    let mut kept_per_rule = vec![0; rules.len()];
+    let mut keep = HashSet::new();
+    for s in snaps.iter().rev() {
+        let Some(idx) = rules.iter().position(|rule| rule.pattern.is_match(&s.name)) else {
+            continue;
+        };
+        if kept_per_rule[idx] < rules[idx].count {
+            kept_per_rule[idx] += 1;
+            keep.insert(s.guid);
+        }
+    }
+    keep
+}
+
+/// The names of the snapshots of `ds` that [tag_for_retention] keeps, oldest first.
+#[cfg(test)]
+fn kept_names(ds: &Dataset, opts: &RetentionOpts, now: DateTime<Utc>) -> Vec<String> {
+    tag_for_retention(ds, opts, now).into_iter()
+        .filter(|(keep, _)| *keep)
+        .map(|(_, s)| s.name.clone())
+        .collect()
+}
+
+#[test]
+fn test_gfs_keep_set() {
+    use crate::dataset::build_fake_dataset;
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let opts = RetentionOpts {
+        keep_last: 3,
+        keep_daily: 7,
+        keep_weekly: 4,
+        keep_monthly: 6,
+        keep_yearly: 2,
+        min_keep: 0,
+        ..RetentionOpts::default()
+    };
+    let now = "2021-11-15T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let keep = gfs_keep_set(&zelda_webdata.snaps, &opts, now);
+    let kept = zelda_webdata.snaps.iter()
+        .filter(|s| keep.contains(&s.guid))
+        .map(|s| s.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(kept, vec![
+        "2020-12-27",  // yearly
+        "2021-06-29",  // monthly
+        "2021-07-31",  // monthly
+        "2021-08-30",  // monthly
+        "2021-09-30",  // monthly
+        "2021-10-31",  // weekly, monthly
+        "2021-11-07",  // weekly
+        "2021-11-09",  // daily
+        "2021-11-10",  // daily
+        "2021-11-11",  // daily
+        "2021-11-12",  // daily, last
+        "2021-11-13",  // daily, last
+        "2021-11-14",  // daily, weekly, monthly, yearly, last
+    ]);
+
+    let mut nothing = opts.clone();
+    (nothing.keep_last, nothing.keep_daily, nothing.keep_weekly, nothing.keep_monthly, nothing.keep_yearly) = (0, 0, 0, 0, 0);
+    assert!(gfs_keep_set(&zelda_webdata.snaps, &nothing, now).is_empty());
+}
+
+#[test]
+fn test_tag_for_retention_normal_name() {
+    use crate::dataset::build_fake_dataset;
+    let tank_backups = build_fake_dataset(
+        "tank/backups",
+        include_str!("dataset/tests/tank_backups-daily-names.list")
+    );
+    let mut opts = RetentionOpts {
+        normal_name: Regex::new(r"^daily-\d{8}$").unwrap(),
+        keep_last: 3,
+        min_keep: 0,
+        ..RetentionOpts::default()
+    };
+    let now = "2021-11-12T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    assert_eq!(kept_names(&tank_backups, &opts, now), vec!["before-upgrade", "2021-10-29", "daily-20211104-manual", "daily-20211110", "daily-20211111", "daily-20211112"]);
+    opts.keep_unusual = false;
+    assert_eq!(kept_names(&tank_backups, &opts, now), vec!["daily-20211110", "daily-20211111", "daily-20211112"]);
+    // With the default pattern, every daily-* snapshot is unusual; only 2021-10-29 is up for deletion.
+    opts.keep_unusual = true;
+    opts.normal_name = Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
+    assert_eq!(kept_names(&tank_backups, &opts, now).len(), tank_backups.snaps.len() - 1);
+    assert!(!kept_names(&tank_backups, &opts, now).contains(&"2021-10-29".to_string()));
+}
+
+#[test]
+fn test_tag_for_retention_autosnap_names() {
+    use crate::dataset::{build_fake_dataset, render_tagged_snaps_for_deletion};
+    let tank_backups = build_fake_dataset(
+        "tank/backups",
+        include_str!("dataset/tests/tank_backups-autosnap.list")
+    );
+    let opts = RetentionOpts {
+        normal_name: Regex::new(r"^autosnap_\d{4}-\d{2}-\d{2}_\d{2}:\d{2}:\d{2}_daily$").unwrap(),
+        keep_last: 2,
+        ..RetentionOpts::default()
+    };
+    let now = "2024-01-07T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    assert_eq!(kept_names(&tank_backups, &opts, now), vec!["pre-upgrade.6.1", "before kernel 6.6", "autosnap_2024-01-06_00:00:01_daily", "autosnap_2024-01-07_00:00:01_daily"]);
+    let tagged = tag_for_retention(&tank_backups, &opts, now);
+    assert_eq!(render_tagged_snaps_for_deletion(tagged), "\
+autosnap_2024-01-01_00:00:01_daily%autosnap_2024-01-03_00:00:01_daily,\\
+autosnap_2024-01-04_00:00:01_daily%autosnap_2024-01-05_00:00:01_daily");
+    // Spaces are allowed too, but need quoting for zfs-destroy to get the name in one piece.
+    let tagged = tank_backups.tag_snaps_for_deletion(|s| s.name != "before kernel 6.6");
+    assert_eq!(render_tagged_snaps_for_deletion(tagged), "'before kernel 6.6'");
+}
+
+#[test]
+fn test_parse_keep_rule() {
+    let rule = parse_keep_rule("hourly-*:24").unwrap();
+    assert_eq!(rule.count, 24);
+    assert!(rule.pattern.is_match("hourly-20240320-1200"));
+    assert!(!rule.pattern.is_match("old-hourly-20240320-1200"));
+    let rule = parse_keep_rule("autosnap_*_??:00:01_daily:7").unwrap();
+    assert_eq!(rule.count, 7);
+    assert!(rule.pattern.is_match("autosnap_2024-01-01_00:00:01_daily"));
+    assert!(!rule.pattern.is_match("autosnap_2024-01-01_00.00.01_daily"));
+    assert!(parse_keep_rule("daily.*:1").unwrap().pattern.is_match("daily.x"));
+    assert!(!parse_keep_rule("daily.*:1").unwrap().pattern.is_match("daily-x"));
+    assert!(parse_keep_rule("hourly-*").is_err());
+    assert!(parse_keep_rule("hourly-*:-1").is_err());
+    assert!(parse_keep_rule(":24").is_err());
+}
+
+#[test]
+fn test_tag_for_retention_keep_rules() {
+    use crate::dataset::build_fake_dataset;
+    let tank_home = build_fake_dataset(
+        "tank/home",
+        include_str!("dataset/tests/tank_home-streams.list")
+    );
+    let mut opts = RetentionOpts {
+        keep: vec![parse_keep_rule("hourly-*:24").unwrap(), parse_keep_rule("daily-*:14").unwrap()],
+        ..RetentionOpts::default()
+    };
+    let now = "2024-03-20T12:30:00Z".parse::<DateTime<Utc>>().unwrap();
+    let kept_now = kept_names(&tank_home, &opts, now);
+    assert_eq!(kept_now.iter().filter(|name| name.starts_with("hourly-")).count(), 24);
+    assert_eq!(kept_now.iter().filter(|name| name.starts_with("daily-")).count(), 14);
+    assert!(kept_now.contains(&"hourly-20240319-1300".to_string()));
+    assert!(!kept_now.contains(&"hourly-20240319-1200".to_string()));
+    assert!(kept_now.contains(&"daily-20240307".to_string()));
+    assert!(!kept_now.contains(&"daily-20240306".to_string()));
+    // Neither stream matches the default normal_name, but they aren't unusual; the manual ones are.
+    assert!(kept_now.contains(&"manual-before-migration".to_string()));
+    assert_eq!(kept_now.len(), 24 + 14 + 2);
+    // The first rule a snapshot matches is the only one it counts towards.
+    opts.keep.insert(0, parse_keep_rule("*-20240320*:1").unwrap());
+    let kept_now = kept_names(&tank_home, &opts, now);
+    assert!(kept_now.contains(&"hourly-20240320-1200".to_string()));
+    assert!(!kept_now.contains(&"daily-20240320".to_string()));
+    assert!(!kept_now.contains(&"hourly-20240320-0000".to_string()));
+    // Which leaves the hourly-* rule with fewer than 24 to keep: all of the rest.
+    assert!(kept_now.contains(&"hourly-20240319-0700".to_string()));
+}
+
+#[test]
+fn test_tag_for_retention_holds() {
+    use crate::dataset::build_fake_dataset;
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata-holds-and-weird-name.list")
+    );
+    // A policy keeping nothing at all, but for held snapshots.
+    let mut opts = RetentionOpts {
+        keep_unusual: false,
+        min_keep: 0,
+        ..RetentionOpts::default()
+    };
+    let now = "2021-11-15T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    assert_eq!(kept_names(&zelda_webdata, &opts, now), vec!["2021-11-12"]);
+    opts.ignore_holds = true;
+    assert!(kept_names(&zelda_webdata, &opts, now).is_empty());
+}
+
+#[test]
+fn test_discount_holds() {
+    use crate::dataset::build_fake_dataset;
+    let held = |holds: u32| {
+        let mut ds = build_fake_dataset("zelda/webdata", include_str!("dataset/tests/zelda_webdata-holds-and-weird-name.list"));
+        ds.snaps.iter_mut().find(|s| s.name == "2021-11-12").unwrap().holds = holds;
+        ds
+    };
+    let opts = RetentionOpts { keep_unusual: false, min_keep: 0, allow_delete_all: true, ..RetentionOpts::default() };
+    let now = "2021-11-15T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let holds = vec![("2021-11-12".to_string(), "zfs-rs".to_string()), ("2021-11-12".to_string(), "keep".to_string())];
+
+    // Held by our tag alone: released, then destroyed along with the rest.
+    let mut ds = held(1);
+    let released = discount_holds(&mut ds, &holds[..1], "zfs-rs");
+    assert_eq!(released.len(), 1);
+    let tagged = tag_for_retention(&ds, &opts, now);
+    assert!(tagged.iter().all(|(keep, _)| !keep));
+    // One range, from the oldest to the newest, uninterrupted.
+    assert!(!render_tagged_snaps_for_deletion(tagged).contains(','));
+
+    // Held by someone else too: still kept, and left out of the batch.
+    let mut ds = held(2);
+    discount_holds(&mut ds, &holds, "zfs-rs");
+    let tagged = tag_for_retention(&ds, &opts, now);
+    assert!(tagged.iter().any(|(keep, s)| *keep && s.name == "2021-11-12"));
+    assert!(render_tagged_snaps_for_deletion(tagged).ends_with("2021-11-11BOGUS-NAME,\\\n2021-11-13%2021-11-14"));
+
+    // Some other tag's holds are never released.
+    let mut ds = held(1);
+    assert!(discount_holds(&mut ds, &holds[1..], "zfs-rs").is_empty());
+}
+
+#[test]
+fn test_tag_for_retention_min_keep() {
+    use crate::dataset::build_fake_dataset;
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    // An empty policy: left to itself, it would destroy everything.
+    let mut opts = RetentionOpts {
+        keep_unusual: false,
+        ..RetentionOpts::default()
+    };
+    let now = "2021-11-15T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    assert_eq!(kept_names(&zelda_webdata, &opts, now), vec!["2021-11-14"]);
+    opts.min_keep = 3;
+    assert_eq!(kept_names(&zelda_webdata, &opts, now), vec!["2021-11-12", "2021-11-13", "2021-11-14"]);
+    opts.min_keep = zelda_webdata.snaps.len() as u32;
+    assert!(tag_for_retention(&zelda_webdata, &opts, now).iter().all(|(keep, _)| *keep));
+    opts.min_keep = 0;
+    assert!(kept_names(&zelda_webdata, &opts, now).is_empty());
+}
+
+#[test]
+fn test_parse_keep_since() {
+    assert_eq!(parse_keep_since("14d"), Ok(Duration::days(14)));
+    assert_eq!(parse_keep_since("2w"), Ok(Duration::days(14)));
+    assert_eq!(parse_keep_since("6m"), Ok(Duration::days(180)));
+    assert_eq!(parse_keep_since("1y"), Ok(Duration::days(365)));
+    assert_eq!(parse_keep_since("0d"), Ok(Duration::zero()));
+    for s in ["", "d", "14", "14h", "-1d", "1.5w", "14 d", "99999999999999y"] {
+        assert!(parse_keep_since(s).is_err(), "{s}");
+    }
+}
+
+#[test]
+fn test_tag_for_retention_keep_since() {
+    use crate::dataset::build_fake_dataset;
+    let zelda_webdata = build_fake_dataset(
+        "zelda/webdata",
+        include_str!("dataset/tests/zelda_webdata.list")
+    );
+    let mut opts = RetentionOpts {
+        keep_unusual: false,
+        keep_since: Some(parse_keep_since("1w").unwrap()),
+        min_keep: 0,
+        ..RetentionOpts::default()
+    };
+    let now = "2021-11-15T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    // Taken at 02:00 every day; 2021-11-08's is just over a week old.
+    assert_eq!(kept_names(&zelda_webdata, &opts, now), vec!["2021-11-09", "2021-11-10", "2021-11-11", "2021-11-12", "2021-11-13", "2021-11-14"]);
+    // Along with the bucket rules: the union of both.
+    opts.keep_monthly = 3;
+    assert_eq!(kept_names(&zelda_webdata, &opts, now), vec!["2021-09-30", "2021-10-31", "2021-11-09", "2021-11-10", "2021-11-11", "2021-11-12", "2021-11-13", "2021-11-14"]);
+}
+
+#[test]
+fn test_reclaim_estimate() {
+    let snap = |used| Snap { used, ..Snap::default() };
+    let (a, b, c) = (snap(1 << 30), snap(512 << 20), snap(4096));
+    let tagged = vec![(false, &a), (true, &b), (false, &c)];
+    assert_eq!(reclaim_estimate(&tagged), "This will destroy 2 snapshot(s), reclaiming ~1.00 GiB (approximate; space shared only among them comes on top).");
+    assert_eq!(reclaim_estimate(&[(true, &a)]), "This will destroy 0 snapshot(s), reclaiming ~0 B (approximate; space shared only among them comes on top).");
 }